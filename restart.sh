@@ -4,4 +4,6 @@ CONFIG_FILE=config/$(hostname)-config.json
 
 pkill rust_hyper
 
-nohup ./target/release/rust_hyper $CONFIG_FILE 2>&1 | svlogd logs &
+mkdir -p logs
+
+nohup ./target/release/rust_hyper serve $CONFIG_FILE > logs/nohup.out 2>&1 &
@@ -0,0 +1,224 @@
+use chrono::prelude::{DateTime, Local};
+
+use log::warn;
+
+use serde_json::{Map, Value};
+
+use std::fs::OpenOptions;
+use std::io::{BufWriter, Write};
+use std::net::IpAddr;
+use std::sync::{mpsc, Mutex};
+
+pub const JSON_FIELDS: [&str; 12] = [
+    "time",
+    "request_id",
+    "remote_addr",
+    "method",
+    "uri",
+    "version",
+    "status",
+    "response_bytes",
+    "duration_seconds",
+    "route",
+    "user_agent",
+    "referer",
+];
+
+// Escapes quotes, backslashes and control characters the way Apache does,
+// so client supplied values can't break out of their quoted field.
+fn escape_field(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if c.is_control() => escaped.push_str(&format!("\\x{:02x}", u32::from(c))),
+            c => escaped.push(c),
+        }
+    }
+
+    escaped
+}
+
+// One completed request, as recorded in the access log.
+pub struct AccessLogEntry {
+    pub time: DateTime<Local>,
+    pub request_id: String,
+    pub client_ip: IpAddr,
+    pub method: String,
+    pub uri: String,
+    pub version: String,
+    pub status: u16,
+    pub response_bytes: Option<u64>,
+    pub duration_seconds: f64,
+    pub route: String,
+    pub user_agent: Option<String>,
+    pub referer: Option<String>,
+}
+
+impl AccessLogEntry {
+    fn json_value(&self, field: &str) -> Value {
+        match field {
+            "time" => Value::from(crate::utils::local_time_to_string(self.time)),
+            "request_id" => Value::from(self.request_id.clone()),
+            "remote_addr" => Value::from(self.client_ip.to_string()),
+            "method" => Value::from(self.method.clone()),
+            "uri" => Value::from(self.uri.clone()),
+            "version" => Value::from(self.version.clone()),
+            "status" => Value::from(self.status),
+            "response_bytes" => self.response_bytes.map_or(Value::Null, Value::from),
+            "duration_seconds" => Value::from(self.duration_seconds),
+            "route" => Value::from(self.route.clone()),
+            "user_agent" => self.user_agent.clone().map_or(Value::Null, Value::from),
+            "referer" => self.referer.clone().map_or(Value::Null, Value::from),
+            _ => Value::Null,
+        }
+    }
+
+    fn format_json(&self, fields: &[String]) -> String {
+        let mut map = Map::new();
+
+        for field in fields {
+            map.insert(field.clone(), self.json_value(field));
+        }
+
+        Value::Object(map).to_string()
+    }
+
+    fn format_common(&self) -> String {
+        format!(
+            "{} - - [{}] \"{} {} {}\" {} {}",
+            self.client_ip,
+            self.time.format("%d/%b/%Y:%H:%M:%S %z"),
+            escape_field(&self.method),
+            escape_field(&self.uri),
+            escape_field(&self.version),
+            self.status,
+            self.response_bytes
+                .map_or_else(|| "-".to_string(), |b| b.to_string())
+        )
+    }
+
+    fn format_combined(&self) -> String {
+        format!(
+            "{} \"{}\" \"{}\"",
+            self.format_common(),
+            self.referer
+                .as_ref()
+                .map_or_else(|| "-".to_string(), |r| escape_field(r)),
+            self.user_agent
+                .as_ref()
+                .map_or_else(|| "-".to_string(), |u| escape_field(u))
+        )
+    }
+}
+
+fn run_access_log_output_thread(
+    mut writer: BufWriter<::std::fs::File>,
+    receiver: mpsc::Receiver<String>,
+) {
+    // write everything queued, then flush once
+    while let Ok(first_line) = receiver.recv() {
+        let mut result = writer.write_all(first_line.as_bytes());
+
+        while result.is_ok() {
+            match receiver.try_recv() {
+                Ok(next_line) => result = writer.write_all(next_line.as_bytes()),
+                Err(_) => break,
+            }
+        }
+
+        if let Err(e) = result.and_then(|_| writer.flush()) {
+            warn!("access log write error: {}", e);
+        }
+    }
+}
+
+pub struct AccessLogger {
+    format: crate::config::AccessLogFormat,
+    fields: Vec<String>,
+    sender: Mutex<mpsc::Sender<String>>,
+}
+
+impl AccessLogger {
+    pub fn new(
+        access_log_info: &crate::config::AccessLogInfo,
+    ) -> Result<Self, Box<::std::error::Error>> {
+        // fields are checked by config validation
+        let fields = if access_log_info.fields().is_empty() {
+            JSON_FIELDS.iter().map(|f| f.to_string()).collect()
+        } else {
+            access_log_info.fields().clone()
+        };
+
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(access_log_info.path())?;
+
+        let (sender, receiver) = mpsc::channel();
+
+        ::std::thread::Builder::new()
+            .name("access_log_output".to_string())
+            .spawn(move || {
+                run_access_log_output_thread(BufWriter::new(file), receiver);
+            })?;
+
+        Ok(AccessLogger {
+            format: access_log_info.format(),
+            fields,
+            sender: Mutex::new(sender),
+        })
+    }
+
+    pub fn log(&self, entry: &AccessLogEntry) {
+        let mut line = match self.format {
+            crate::config::AccessLogFormat::Json => entry.format_json(&self.fields),
+            crate::config::AccessLogFormat::Common => entry.format_common(),
+            crate::config::AccessLogFormat::Combined => entry.format_combined(),
+        };
+        line.push('\n');
+
+        if let Err(e) = self.sender.lock().unwrap().send(line) {
+            warn!("access log send error: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(uri: &str, user_agent: &str) -> AccessLogEntry {
+        AccessLogEntry {
+            time: Local::now(),
+            request_id: "1".to_string(),
+            client_ip: "127.0.0.1".parse().unwrap(),
+            method: "GET".to_string(),
+            uri: uri.to_string(),
+            version: "HTTP/1.1".to_string(),
+            status: 200,
+            response_bytes: None,
+            duration_seconds: 0.0,
+            route: "/".to_string(),
+            user_agent: Some(user_agent.to_string()),
+            referer: None,
+        }
+    }
+
+    #[test]
+    fn escape_field_escapes_quotes_backslashes_and_controls() {
+        assert_eq!(escape_field("plain/path?a=b"), "plain/path?a=b");
+        assert_eq!(escape_field("a\"b"), "a\\\"b");
+        assert_eq!(escape_field("a\\b"), "a\\\\b");
+        assert_eq!(escape_field("a\nb\tc"), "a\\x0ab\\x09c");
+    }
+
+    #[test]
+    fn format_combined_keeps_fields_quoted() {
+        let line = entry("/", "agent\" 500 \"forged").format_combined();
+
+        assert!(line.ends_with("\"-\" \"agent\\\" 500 \\\"forged\""));
+    }
+}
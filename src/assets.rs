@@ -0,0 +1,41 @@
+// Static assets compiled into the binary.  Each is served at its http_path
+// unless a static_paths entry in the configuration uses the same path.
+pub struct Asset {
+    http_path: &'static str,
+    content_type: &'static str,
+    data: &'static [u8],
+}
+
+impl Asset {
+    pub fn http_path(&self) -> &'static str {
+        self.http_path
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        self.content_type
+    }
+
+    pub fn data(&self) -> &'static [u8] {
+        self.data
+    }
+}
+
+pub const ASSET_CACHE_CONTROL: &str = "max-age=60";
+
+pub static ASSETS: [Asset; 3] = [
+    Asset {
+        http_path: "/command.js",
+        content_type: "application/javascript",
+        data: include_bytes!("../static/command.js"),
+    },
+    Asset {
+        http_path: "/proxy.js",
+        content_type: "application/javascript",
+        data: include_bytes!("../static/proxy.js"),
+    },
+    Asset {
+        http_path: "/style.css",
+        content_type: "text/css",
+        data: include_bytes!("../static/style.css"),
+    },
+];
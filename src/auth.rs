@@ -0,0 +1,633 @@
+use futures::{future, Future};
+
+use hyper::header::{HeaderValue, ACCEPT, AUTHORIZATION, WWW_AUTHENTICATE};
+use hyper::{Body, Method, Request, Response, StatusCode};
+
+use log::{debug, warn};
+
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// bcrypt is deliberately slow, so verified basic credentials are remembered
+// for a while instead of being checked again on every request.
+const VERIFIED_CREDENTIALS_TTL: Duration = Duration::from_secs(300);
+const MAX_VERIFIED_CREDENTIALS: usize = 1024;
+
+// bcrypt("unused") at the default cost of 12.
+const DUMMY_PASSWORD_HASH: &str = "$2b$12$ya84hinpf0Rb0BJ7U8vImero8RDfbaYNafmCPzyhKBqQQZPxBhEhq";
+
+pub const LOGIN_PATH: &str = "/login";
+pub const LOGOUT_PATH: &str = "/logout";
+
+// Requests authenticated by session cookie must send the session's csrf
+// token in this header unless the method is safe.  Never accepted from the
+// query string, which ends up in access logs and Referer headers.  The
+// logout form posts it as a form field instead, checked by the handler.
+pub const CSRF_TOKEN_HEADER: &str = "x-csrf-token";
+pub const CSRF_TOKEN_PARAMETER: &str = "csrf_token";
+
+#[derive(Debug, Clone)]
+pub struct Principal {
+    name: String,
+    roles: Vec<String>,
+}
+
+impl Principal {
+    pub fn name(&self) -> &String {
+        &self.name
+    }
+
+    fn has_any_role(&self, roles: &[String]) -> bool {
+        roles.iter().any(|role| self.roles.contains(role))
+    }
+}
+
+enum Credentials {
+    Basic { username: String, password: String },
+    Bearer(String),
+}
+
+fn parse_credentials(header_value: &str) -> Option<Credentials> {
+    let mut parts = header_value.trim().splitn(2, ' ');
+    let scheme = parts.next()?;
+    let value = parts.next()?.trim();
+
+    if scheme.eq_ignore_ascii_case("basic") {
+        let decoded = String::from_utf8(::base64::decode(value).ok()?).ok()?;
+        let mut parts = decoded.splitn(2, ':');
+        Some(Credentials::Basic {
+            username: parts.next()?.to_string(),
+            password: parts.next()?.to_string(),
+        })
+    } else if scheme.eq_ignore_ascii_case("bearer") {
+        Some(Credentials::Bearer(value.to_string()))
+    } else {
+        None
+    }
+}
+
+fn is_safe_method(method: &Method) -> bool {
+    *method == Method::GET || *method == Method::HEAD || *method == Method::OPTIONS
+}
+
+// Browsers navigating to a page are sent to the login form; anything else,
+// such as the api calls made by the html pages, gets a json error.
+fn wants_html(req: &Request<Body>) -> bool {
+    (*req.method() == Method::GET || *req.method() == Method::HEAD)
+        && req
+            .headers()
+            .get(ACCEPT)
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(|accept| accept.contains("text/html"))
+}
+
+pub fn encode_query_value(value: &str) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => {
+                encoded.push(byte as char)
+            }
+            _ => write!(encoded, "%{:02X}", byte).unwrap(),
+        }
+    }
+    encoded
+}
+
+fn json_error_response(status_code: StatusCode, error: &str) -> Response<Body> {
+    crate::server::build_response_string(
+        status_code,
+        Cow::from(format!("{{\"error\":\"{}\"}}", error)),
+        crate::server::application_json_content_type_header_value(),
+    )
+}
+
+pub enum Authorization {
+    Allowed(Option<Principal>),
+    Denied(Response<Body>),
+}
+
+struct InnerAuthenticator {
+    www_authenticate: HeaderValue,
+    users: HashMap<String, crate::config::UserInfo>,
+    api_tokens: Vec<crate::config::ApiTokenInfo>,
+    policies: crate::server::PrefixRules<crate::config::RoutePolicyInfo>,
+    default_access: crate::config::RouteAccess,
+    // reachable without credentials whatever the policies say
+    public_paths: HashSet<String>,
+    session_manager: Option<Arc<crate::session::SessionManager>>,
+    // keyed by the full authorization header value
+    verified_credentials: Mutex<HashMap<String, (String, Instant)>>,
+}
+
+impl InnerAuthenticator {
+    fn policy_for(&self, path: &str) -> (crate::config::RouteAccess, Vec<String>) {
+        if self.public_paths.contains(path) {
+            return (crate::config::RouteAccess::Public, Vec::new());
+        }
+
+        match self.policies.lookup(path) {
+            Some(policy) => (policy.access(), policy.roles().clone()),
+            None => (self.default_access, Vec::new()),
+        }
+    }
+
+    fn user_principal(&self, username: &str) -> Option<Principal> {
+        self.users.get(username).map(|user_info| Principal {
+            name: user_info.username().clone(),
+            roles: user_info.roles().clone(),
+        })
+    }
+
+    fn verify_password(
+        self: &Arc<Self>,
+        username: String,
+        password: String,
+    ) -> Box<Future<Item = Option<Principal>, Error = crate::server::HandlerError> + Send> {
+        // Unknown users are checked against a dummy hash so the response
+        // time doesn't reveal which usernames exist.
+        let (known_user, password_hash) = match self.users.get(&username) {
+            Some(user_info) => (true, user_info.password_hash().clone()),
+            None => (false, DUMMY_PASSWORD_HASH.to_string()),
+        };
+
+        let self_clone = Arc::clone(self);
+
+        Box::new(
+            crate::utils::blocking_io(move || {
+                let verified = ::bcrypt::verify(&password, &password_hash).unwrap_or_else(|e| {
+                    warn!("bcrypt error verifying password: {}", e);
+                    false
+                });
+                Ok((known_user && verified, username))
+            })
+            .map(move |(verified, username)| {
+                if verified {
+                    debug!("verified password for user '{}'", username);
+                    self_clone.user_principal(&username)
+                } else if known_user {
+                    warn!("authentication failed for user '{}'", username);
+                    None
+                } else {
+                    debug!("authentication failed for unknown user");
+                    None
+                }
+            })
+            .map_err(crate::server::HandlerError::from),
+        )
+    }
+
+    fn cached_username(&self, header_value: &str) -> Option<String> {
+        let verified_credentials = self.verified_credentials.lock().unwrap();
+
+        match verified_credentials.get(header_value) {
+            Some((username, verified_at)) if verified_at.elapsed() < VERIFIED_CREDENTIALS_TTL => {
+                Some(username.clone())
+            }
+            _ => None,
+        }
+    }
+
+    fn cache_verified(&self, header_value: String, username: String) {
+        let mut verified_credentials = self.verified_credentials.lock().unwrap();
+
+        if verified_credentials.len() >= MAX_VERIFIED_CREDENTIALS {
+            verified_credentials
+                .retain(|_, (_, verified_at)| verified_at.elapsed() < VERIFIED_CREDENTIALS_TTL);
+            if verified_credentials.len() >= MAX_VERIFIED_CREDENTIALS {
+                verified_credentials.clear();
+            }
+        }
+
+        verified_credentials.insert(header_value, (username, Instant::now()));
+    }
+
+    fn authenticate(
+        self: &Arc<Self>,
+        header_value: String,
+    ) -> Box<Future<Item = Option<Principal>, Error = crate::server::HandlerError> + Send> {
+        let credentials = match parse_credentials(&header_value) {
+            Some(credentials) => credentials,
+            None => return Box::new(future::ok(None)),
+        };
+
+        match credentials {
+            Credentials::Bearer(token) => Box::new(future::ok(
+                self.api_tokens
+                    .iter()
+                    .find(|token_info| {
+                        crate::utils::constant_time_eq(
+                            token_info.token().as_bytes(),
+                            token.as_bytes(),
+                        )
+                    })
+                    .map(|token_info| Principal {
+                        name: token_info.name().clone(),
+                        roles: token_info.roles().clone(),
+                    }),
+            )),
+            Credentials::Basic { username, password } => {
+                if let Some(username) = self.cached_username(&header_value) {
+                    return Box::new(future::ok(self.user_principal(&username)));
+                }
+
+                let self_clone = Arc::clone(self);
+
+                Box::new(
+                    self.verify_password(username, password)
+                        .map(move |principal| {
+                            if let Some(principal) = &principal {
+                                self_clone.cache_verified(header_value, principal.name().clone());
+                            }
+                            principal
+                        }),
+                )
+            }
+        }
+    }
+
+    // A valid session cookie authenticates the request; unsafe methods must
+    // also carry the session's csrf token.
+    fn session_principal(
+        &self,
+        req: &Request<Body>,
+        path: &str,
+    ) -> Result<Option<Principal>, Box<Response<Body>>> {
+        let session = match self
+            .session_manager
+            .as_ref()
+            .and_then(|session_manager| session_manager.session_from_headers(req.headers()))
+        {
+            Some(session) => session,
+            None => return Ok(None),
+        };
+
+        let principal = match self.user_principal(session.username()) {
+            Some(principal) => principal,
+            None => return Ok(None),
+        };
+
+        if !is_safe_method(req.method()) && path != LOGOUT_PATH {
+            let request_csrf_token = req
+                .headers()
+                .get(CSRF_TOKEN_HEADER)
+                .and_then(|v| v.to_str().ok())
+                .map(|v| v.to_string());
+
+            let valid = request_csrf_token.is_some_and(|request_csrf_token| {
+                crate::utils::constant_time_eq(
+                    request_csrf_token.as_bytes(),
+                    session.csrf_token().as_bytes(),
+                )
+            });
+
+            if !valid {
+                warn!("missing or invalid csrf token from '{}'", principal.name());
+                return Err(Box::new(json_error_response(
+                    StatusCode::FORBIDDEN,
+                    "invalid csrf token",
+                )));
+            }
+        }
+
+        Ok(Some(principal))
+    }
+
+    fn unauthorized(&self, req: &Request<Body>) -> Authorization {
+        if self.session_manager.is_some() && wants_html(req) {
+            let next = match req.uri().path_and_query() {
+                Some(path_and_query) => path_and_query.as_str(),
+                None => "/",
+            };
+            return Authorization::Denied(crate::server::build_response_redirect(
+                StatusCode::SEE_OTHER,
+                &format!("{}?next={}", LOGIN_PATH, encode_query_value(next)),
+            ));
+        }
+
+        let mut response = json_error_response(StatusCode::UNAUTHORIZED, "authentication required");
+
+        // a basic auth challenge would make browsers prompt on api calls
+        // from pages using the login form
+        if self.session_manager.is_none() {
+            response
+                .headers_mut()
+                .insert(WWW_AUTHENTICATE, self.www_authenticate.clone());
+        }
+
+        Authorization::Denied(response)
+    }
+}
+
+// Checks requests against the configured users, api tokens and per-route
+// policies.  Users authenticate with basic auth or, when sessions are
+// configured, the login form; api tokens are sent as bearer tokens.
+#[derive(Clone)]
+pub struct Authenticator {
+    inner: Arc<InnerAuthenticator>,
+}
+
+impl Authenticator {
+    // public_paths are the built-in assets, which the login page needs
+    // before anyone has logged in.
+    pub fn new(
+        authentication_info: &crate::config::AuthenticationInfo,
+        public_paths: Vec<String>,
+    ) -> Result<Self, Box<::std::error::Error>> {
+        let www_authenticate = HeaderValue::from_str(&format!(
+            "Basic realm=\"{}\", charset=\"UTF-8\"",
+            authentication_info.realm()
+        ))?;
+
+        let mut users = HashMap::new();
+        for user_info in authentication_info.users() {
+            if users
+                .insert(user_info.username().clone(), user_info.clone())
+                .is_some()
+            {
+                return Err(format!("duplicate user '{}'", user_info.username()).into());
+            }
+        }
+
+        let session_manager = match authentication_info.session() {
+            Some(session_info) => {
+                Some(Arc::new(crate::session::SessionManager::new(session_info)?))
+            }
+            None => None,
+        };
+
+        let mut public_paths: HashSet<String> = public_paths.into_iter().collect();
+        if session_manager.is_some() {
+            public_paths.insert(LOGIN_PATH.to_string());
+        }
+
+        let policies = crate::server::PrefixRules::new(
+            authentication_info
+                .policies()
+                .iter()
+                .map(|policy| (policy.path_prefix().clone(), policy.clone()))
+                .collect(),
+        );
+
+        Ok(Authenticator {
+            inner: Arc::new(InnerAuthenticator {
+                www_authenticate,
+                users,
+                api_tokens: authentication_info.api_tokens().clone(),
+                policies,
+                default_access: authentication_info.default_access(),
+                public_paths,
+                session_manager,
+                verified_credentials: Mutex::new(HashMap::new()),
+            }),
+        })
+    }
+
+    // Whether requests for path need credentials under the configured
+    // policies.
+    pub fn requires_authentication(&self, path: &str) -> bool {
+        self.inner.policy_for(path).0 != crate::config::RouteAccess::Public
+    }
+
+    // path is the normalized request path, see crate::server::normalize_path.
+    pub fn authorize(
+        &self,
+        req: &Request<Body>,
+        path: &str,
+    ) -> Box<Future<Item = Authorization, Error = crate::server::HandlerError> + Send> {
+        let (access, roles) = self.inner.policy_for(path);
+
+        if access == crate::config::RouteAccess::Public {
+            return Box::new(future::ok(Authorization::Allowed(None)));
+        }
+
+        let unauthorized = self.inner.unauthorized(req);
+
+        let principal_future: Box<
+            Future<Item = Option<Principal>, Error = crate::server::HandlerError> + Send,
+        > = match req
+            .headers()
+            .get(AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+        {
+            Some(header_value) => self.inner.authenticate(header_value.to_string()),
+            None => match self.inner.session_principal(req, path) {
+                Ok(principal) => Box::new(future::ok(principal)),
+                Err(response) => return Box::new(future::ok(Authorization::Denied(*response))),
+            },
+        };
+
+        Box::new(principal_future.map(move |principal| match principal {
+            None => unauthorized,
+            Some(principal) => {
+                if roles.is_empty() || principal.has_any_role(&roles) {
+                    Authorization::Allowed(Some(principal))
+                } else {
+                    warn!(
+                        "'{}' lacks a role required for this route",
+                        principal.name()
+                    );
+                    Authorization::Denied(json_error_response(StatusCode::FORBIDDEN, "forbidden"))
+                }
+            }
+        }))
+    }
+
+    pub fn verify_password(
+        &self,
+        username: String,
+        password: String,
+    ) -> Box<Future<Item = Option<Principal>, Error = crate::server::HandlerError> + Send> {
+        self.inner.verify_password(username, password)
+    }
+
+    pub fn session_manager(&self) -> Option<&Arc<crate::session::SessionManager>> {
+        self.inner.session_manager.as_ref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::config::RouteAccess;
+
+    fn authenticator(authentication_json: &str) -> Authenticator {
+        let authentication_info: crate::config::AuthenticationInfo =
+            ::serde_json::from_str(authentication_json).unwrap();
+        Authenticator::new(&authentication_info, vec!["/style.css".to_string()]).unwrap()
+    }
+
+    fn authenticator_with_session() -> Authenticator {
+        authenticator(r#"{ "session": {} }"#)
+    }
+
+    fn request(path: &str, authorization: Option<&str>) -> Request<Body> {
+        let mut builder = Request::builder();
+        builder.uri(path);
+        if let Some(authorization) = authorization {
+            builder.header(AUTHORIZATION, authorization);
+        }
+        builder.body(Body::empty()).unwrap()
+    }
+
+    fn basic(username: &str, password: &str) -> String {
+        format!(
+            "Basic {}",
+            ::base64::encode(&format!("{}:{}", username, password))
+        )
+    }
+
+    fn is_allowed(authorization: Authorization) -> bool {
+        match authorization {
+            Authorization::Allowed(_) => true,
+            Authorization::Denied(_) => false,
+        }
+    }
+
+    #[test]
+    fn policy_for_prefers_longest_prefix() {
+        let authenticator = authenticator(
+            r#"{
+                "default_access": "public",
+                "policies": [
+                    { "path_prefix": "/api", "access": "authenticated" },
+                    { "path_prefix": "/api/status", "access": "public" },
+                    { "path_prefix": "/admin", "access": "authenticated", "roles": ["admin"] }
+                ]
+            }"#,
+        );
+        let inner = &authenticator.inner;
+
+        assert_eq!(inner.policy_for("/api/x").0, RouteAccess::Authenticated);
+        assert_eq!(inner.policy_for("/api/status/x").0, RouteAccess::Public);
+        assert_eq!(inner.policy_for("/apis").0, RouteAccess::Public);
+        assert_eq!(
+            inner.policy_for("/admin/log_level").1,
+            vec!["admin".to_string()]
+        );
+    }
+
+    #[test]
+    fn policy_for_falls_back_to_default_access() {
+        let authenticator = authenticator(r#"{}"#);
+        assert!(authenticator.requires_authentication("/anything"));
+        assert!(!authenticator.requires_authentication("/style.css"));
+        assert!(authenticator.requires_authentication(LOGIN_PATH));
+
+        let authenticator = authenticator_with_session();
+        assert!(!authenticator.requires_authentication(LOGIN_PATH));
+        assert!(authenticator.requires_authentication(LOGOUT_PATH));
+    }
+
+    #[test]
+    fn parse_credentials_basic_and_bearer() {
+        match parse_credentials(&basic("user", "pass:word")) {
+            Some(Credentials::Basic { username, password }) => {
+                assert_eq!(username, "user");
+                assert_eq!(password, "pass:word");
+            }
+            _ => panic!("expected basic credentials"),
+        }
+
+        match parse_credentials("bearer  abc123 ") {
+            Some(Credentials::Bearer(token)) => assert_eq!(token, "abc123"),
+            _ => panic!("expected a bearer token"),
+        }
+
+        assert!(parse_credentials("Basic !!!").is_none());
+        assert!(parse_credentials(&format!("Basic {}", ::base64::encode("nocolon"))).is_none());
+        assert!(parse_credentials("Digest abc").is_none());
+        assert!(parse_credentials("Bearer").is_none());
+    }
+
+    #[test]
+    fn authorize_checks_roles() {
+        let authenticator = authenticator(
+            r#"{
+                "api_tokens": [
+                    { "name": "reader", "token": "reader-token", "roles": ["read"] },
+                    { "name": "admin", "token": "admin-token", "roles": ["read", "admin"] }
+                ],
+                "policies": [
+                    { "path_prefix": "/admin", "access": "authenticated", "roles": ["admin"] }
+                ]
+            }"#,
+        );
+
+        let authorize = |path: &str, authorization: Option<&str>| {
+            authenticator
+                .authorize(&request(path, authorization), path)
+                .wait()
+                .unwrap()
+        };
+
+        assert!(is_allowed(authorize("/page", Some("Bearer reader-token"))));
+        assert!(!is_allowed(authorize(
+            "/admin",
+            Some("Bearer reader-token")
+        )));
+        assert!(is_allowed(authorize("/admin", Some("Bearer admin-token"))));
+        assert!(!is_allowed(authorize("/page", Some("Bearer wrong-token"))));
+        assert!(!is_allowed(authorize("/page", None)));
+        assert!(is_allowed(authorize("/style.css", None)));
+
+        match authorize("/admin", Some("Bearer reader-token")) {
+            Authorization::Denied(response) => assert_eq!(response.status(), StatusCode::FORBIDDEN),
+            _ => panic!("expected the request to be forbidden"),
+        }
+    }
+
+    #[test]
+    fn dummy_password_hash_is_valid() {
+        assert!(::bcrypt::verify("unused", DUMMY_PASSWORD_HASH).unwrap());
+    }
+
+    #[test]
+    fn verify_password_rejects_unknown_users() {
+        let password_hash = ::bcrypt::hash("secret", 4).unwrap();
+        let authenticator = authenticator(&format!(
+            r#"{{ "users": [ {{ "username": "user", "password_hash": "{}" }} ] }}"#,
+            password_hash
+        ));
+
+        let mut runtime = ::tokio::runtime::Runtime::new().unwrap();
+        let mut verify = |username: &str, password: &str| {
+            runtime
+                .block_on(authenticator.verify_password(username.to_string(), password.to_string()))
+                .unwrap()
+                .map(|principal| principal.name().clone())
+        };
+
+        assert_eq!(verify("user", "secret"), Some("user".to_string()));
+        assert_eq!(verify("user", "wrong"), None);
+        // the dummy hash's own password must not let an unknown user in
+        assert_eq!(verify("nobody", "unused"), None);
+    }
+
+    #[test]
+    fn verified_credentials_are_keyed_by_header_value() {
+        let authenticator = authenticator(r#"{}"#);
+        let inner = &authenticator.inner;
+
+        let header_value = basic("user", "secret");
+        inner.cache_verified(header_value.clone(), "user".to_string());
+
+        assert_eq!(
+            inner.cached_username(&header_value),
+            Some("user".to_string())
+        );
+        assert_eq!(inner.cached_username(&basic("user", "wrong")), None);
+
+        let expired = Instant::now() - VERIFIED_CREDENTIALS_TTL - Duration::from_secs(1);
+        inner
+            .verified_credentials
+            .lock()
+            .unwrap()
+            .insert(header_value.clone(), ("user".to_string(), expired));
+        assert_eq!(inner.cached_username(&header_value), None);
+    }
+}
@@ -0,0 +1,234 @@
+use log::warn;
+
+use regex::Regex;
+
+use crate::config::CommandInfo;
+
+fn is_valid_metric_name(name: &str) -> bool {
+    !name.is_empty()
+        && !name.starts_with(|c: char| c.is_ascii_digit())
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == ':')
+}
+
+fn is_valid_label_name(name: &str) -> bool {
+    is_valid_metric_name(name) && !name.contains(':') && !name.starts_with("__")
+}
+
+struct CommandMetricRule {
+    name: String,
+    regex: Regex,
+    labels: Vec<(String, String)>,
+}
+
+impl CommandMetricRule {
+    fn record(&self, output: &str, samples: &mut Vec<crate::metrics::CommandOutputSample>) {
+        for captures in self.regex.captures_iter(output) {
+            let value_str = match captures.name("value").or_else(|| captures.get(1)) {
+                Some(value_match) => value_match.as_str().trim(),
+                None => continue,
+            };
+
+            let value: f64 = match value_str.parse() {
+                Ok(value) => value,
+                Err(_) => {
+                    warn!("metric {} invalid value '{}'", self.name, value_str);
+                    continue;
+                }
+            };
+
+            let labels: Vec<(String, String)> = self
+                .labels
+                .iter()
+                .map(|(label_name, template)| {
+                    let mut label_value = String::new();
+                    captures.expand(template, &mut label_value);
+                    (label_name.clone(), label_value)
+                })
+                .collect();
+
+            samples.push(crate::metrics::CommandOutputSample::new(
+                self.name.clone(),
+                labels,
+                value,
+            ));
+        }
+    }
+}
+
+// Metric extraction rules for one command, compiled once at startup.
+pub struct CommandMetricRules {
+    command: String,
+    rules: Vec<CommandMetricRule>,
+}
+
+impl CommandMetricRules {
+    pub fn new(
+        command_info: &CommandInfo,
+        metrics: &crate::metrics::Metrics,
+    ) -> Result<Self, Box<::std::error::Error>> {
+        let mut rules = Vec::with_capacity(command_info.metrics().len());
+
+        for metric_info in command_info.metrics() {
+            if !is_valid_metric_name(metric_info.name()) {
+                return Err(format!("invalid metric name '{}'", metric_info.name()).into());
+            }
+
+            for label_name in metric_info.labels().keys() {
+                if !is_valid_label_name(label_name) {
+                    return Err(format!(
+                        "invalid label name '{}' for metric '{}'",
+                        label_name,
+                        metric_info.name()
+                    )
+                    .into());
+                }
+            }
+
+            let regex = Regex::new(metric_info.regex())?;
+            if regex.captures_len() < 2 {
+                return Err(format!(
+                    "regex for metric '{}' has no capture group",
+                    metric_info.name()
+                )
+                .into());
+            }
+
+            let help = match metric_info.help() {
+                Some(help) => help.clone(),
+                None => format!("Output of {}", command_info.command()),
+            };
+
+            metrics.register_command_output(
+                command_info.api_path(),
+                metric_info.name(),
+                &help,
+                metric_info.metric_type(),
+            )?;
+
+            rules.push(CommandMetricRule {
+                name: metric_info.name().clone(),
+                regex,
+                labels: metric_info
+                    .labels()
+                    .iter()
+                    .map(|(k, v)| (k.clone(), v.clone()))
+                    .collect(),
+            });
+        }
+
+        Ok(CommandMetricRules {
+            command: command_info.api_path().clone(),
+            rules,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    pub fn record(&self, output: &str, metrics: &crate::metrics::Metrics) {
+        if self.rules.is_empty() {
+            return;
+        }
+
+        let mut samples = Vec::new();
+
+        for rule in &self.rules {
+            rule.record(output, &mut samples);
+        }
+
+        metrics.set_command_outputs(&self.command, samples);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn command_info(metrics_json: &str) -> CommandInfo {
+        ::serde_json::from_str(&format!(
+            r#"{{ "api_path": "/api/sensors", "html_path": "/sensors", "description": "sensors",
+                  "command": "sensors", "args": [], "metrics": {} }}"#,
+            metrics_json
+        ))
+        .unwrap()
+    }
+
+    fn rendered_samples(metrics_json: &str, output: &str) -> Vec<String> {
+        let metrics = crate::metrics::Metrics::new();
+        let rules = CommandMetricRules::new(&command_info(metrics_json), &metrics).unwrap();
+
+        rules.record(output, &metrics);
+
+        metrics
+            .render()
+            .lines()
+            .filter(|line| line.starts_with("sensor_"))
+            .map(|line| line.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn record_uses_named_value_or_first_group() {
+        assert_eq!(
+            rendered_samples(
+                r#"[{ "name": "sensor_temperature", "regex": "(cpu|gpu): (?P<value>[0-9.]+)C" }]"#,
+                "cpu: 45.5C\n"
+            ),
+            vec!["sensor_temperature 45.5"]
+        );
+
+        assert_eq!(
+            rendered_samples(
+                r#"[{ "name": "sensor_fan_rpm", "regex": "fan: ([0-9]+) RPM" }]"#,
+                "fan: 1200 RPM\n"
+            ),
+            vec!["sensor_fan_rpm 1200"]
+        );
+    }
+
+    #[test]
+    fn record_expands_label_templates() {
+        assert_eq!(
+            rendered_samples(
+                r#"[{ "name": "sensor_temperature",
+                      "regex": "(?P<chip>[a-z]+)/(?P<core>[0-9]+): (?P<value>[0-9.]+)",
+                      "labels": { "sensor": "$chip", "core": "core${core}" } }]"#,
+                "cpu/0: 45\ncpu/1: 47\n"
+            ),
+            vec![
+                r#"sensor_temperature{core="core0",sensor="cpu"} 45"#,
+                r#"sensor_temperature{core="core1",sensor="cpu"} 47"#,
+            ]
+        );
+    }
+
+    #[test]
+    fn record_skips_missing_and_non_numeric_values() {
+        let metrics_json = r#"[{ "name": "sensor_value", "regex": "value: (?P<value>\\S+)?" }]"#;
+
+        assert!(rendered_samples(metrics_json, "nothing to see\n").is_empty());
+        assert_eq!(
+            rendered_samples(metrics_json, "value: n/a\nvalue: \nvalue: 3\n"),
+            vec!["sensor_value 3"]
+        );
+    }
+
+    #[test]
+    fn new_rejects_invalid_rules() {
+        let metrics = crate::metrics::Metrics::new();
+        let new =
+            |metrics_json: &str| CommandMetricRules::new(&command_info(metrics_json), &metrics);
+
+        assert!(new(r#"[{ "name": "1sensor", "regex": "([0-9]+)" }]"#).is_err());
+        assert!(new(r#"[{ "name": "sensor_a", "regex": "[0-9]+" }]"#).is_err());
+        assert!(new(r#"[{ "name": "sensor_b", "regex": "([0-9]+" }]"#).is_err());
+        assert!(new(
+            r#"[{ "name": "sensor_c", "regex": "([0-9]+)", "labels": { "__reserved": "x" } }]"#
+        )
+        .is_err());
+        assert!(new(r#"[{ "name": "sensor_d", "regex": "([0-9]+)" }]"#).is_ok());
+    }
+}
@@ -0,0 +1,396 @@
+use flate2::write::GzEncoder;
+
+use futures::{future, Future, Stream};
+
+use hyper::body::Payload;
+use hyper::header::{
+    HeaderMap, HeaderValue, ACCEPT_ENCODING, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_RANGE,
+    CONTENT_TYPE, ETAG, VARY,
+};
+use hyper::{Body, Response, StatusCode};
+
+use std::io;
+use std::io::Write;
+
+const BROTLI_BUFFER_SIZE: usize = 4096;
+const BROTLI_QUALITY: u32 = 9;
+const BROTLI_LG_WINDOW_SIZE: u32 = 22;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Encoding {
+    Brotli,
+    Gzip,
+}
+
+impl Encoding {
+    pub fn name(self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gzip",
+        }
+    }
+
+    pub fn file_extension(self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gz",
+        }
+    }
+
+    pub fn header_value(self) -> HeaderValue {
+        HeaderValue::from_static(self.name())
+    }
+}
+
+// Server preference when the client gives several encodings the same weight.
+const SUPPORTED_ENCODINGS: [Encoding; 2] = [Encoding::Brotli, Encoding::Gzip];
+
+fn parse_quality(params: &str) -> Option<f32> {
+    params
+        .split(';')
+        .map(|p| p.trim())
+        .find(|p| p.starts_with("q="))
+        .and_then(|p| p[2..].trim().parse().ok())
+}
+
+// Returns the supported encodings the client accepts, best first.  Identity is
+// always acceptable so it is never part of the result.
+pub fn acceptable_encodings(req_headers: &HeaderMap) -> Vec<Encoding> {
+    let mut wildcard_quality = None;
+    let mut qualities = Vec::new();
+
+    for header_value in req_headers.get_all(ACCEPT_ENCODING) {
+        let header_str = match header_value.to_str() {
+            Ok(header_str) => header_str,
+            Err(_) => continue,
+        };
+
+        for item in header_str.split(',') {
+            let mut split = item.trim().splitn(2, ';');
+            let coding = split.next().unwrap_or("").trim().to_ascii_lowercase();
+            let quality = split.next().and_then(parse_quality).unwrap_or(1.0);
+
+            if coding == "*" {
+                wildcard_quality = Some(quality);
+            } else {
+                qualities.push((coding, quality));
+            }
+        }
+    }
+
+    let mut encodings: Vec<(Encoding, f32)> = SUPPORTED_ENCODINGS
+        .iter()
+        .filter_map(|&encoding| {
+            let quality = qualities
+                .iter()
+                .find(|(coding, _)| {
+                    coding == encoding.name() || (encoding == Encoding::Gzip && coding == "x-gzip")
+                })
+                .map(|(_, quality)| *quality)
+                .or(wildcard_quality)?;
+
+            if quality > 0.0 {
+                Some((encoding, quality))
+            } else {
+                None
+            }
+        })
+        .collect();
+
+    // stable sort keeps SUPPORTED_ENCODINGS order for equal weights
+    encodings.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(::std::cmp::Ordering::Equal));
+
+    encodings
+        .into_iter()
+        .map(|(encoding, _)| encoding)
+        .collect()
+}
+
+pub fn is_compressible_content_type(content_type: &str) -> bool {
+    let mime_type = content_type
+        .split(';')
+        .next()
+        .unwrap_or("")
+        .trim()
+        .to_ascii_lowercase();
+
+    mime_type.starts_with("text/")
+        || mime_type.ends_with("+json")
+        || mime_type.ends_with("+xml")
+        || matches!(
+            mime_type.as_str(),
+            "application/javascript"
+                | "application/json"
+                | "application/xml"
+                | "application/wasm"
+                | "image/x-icon"
+                | "image/vnd.microsoft.icon"
+        )
+}
+
+pub fn compress(encoding: Encoding, level: Option<u32>, data: &[u8]) -> io::Result<Vec<u8>> {
+    match encoding {
+        Encoding::Gzip => {
+            let compression = match level {
+                Some(level) => ::flate2::Compression::new(level),
+                None => ::flate2::Compression::default(),
+            };
+            let mut encoder = GzEncoder::new(Vec::new(), compression);
+            encoder.write_all(data)?;
+            encoder.finish()
+        }
+        Encoding::Brotli => {
+            let mut writer = ::brotli::CompressorWriter::new(
+                Vec::new(),
+                BROTLI_BUFFER_SIZE,
+                level.unwrap_or(BROTLI_QUALITY),
+                BROTLI_LG_WINDOW_SIZE,
+            );
+            writer.write_all(data)?;
+            // into_inner finishes the brotli stream before handing back the buffer
+            Ok(writer.into_inner())
+        }
+    }
+}
+
+// Whether the response is one the compression layer would encode for a client
+// that accepts it.  Partial content is left alone since Content-Range and the
+// multipart byte offsets describe the uncompressed representation.
+fn is_compressible_response(
+    resp: &Response<Body>,
+    compression_info: &crate::config::CompressionInfo,
+) -> bool {
+    let headers = resp.headers();
+
+    // Responses carrying an ETag manage their own representations, and
+    // streaming bodies (no known length) are passed through untouched.
+    if resp.status() == StatusCode::NO_CONTENT
+        || resp.status() == StatusCode::NOT_MODIFIED
+        || resp.status() == StatusCode::PARTIAL_CONTENT
+        || headers.contains_key(CONTENT_RANGE)
+        || headers.contains_key(CONTENT_ENCODING)
+        || headers.contains_key(ETAG)
+    {
+        return false;
+    }
+
+    match resp.body().content_length() {
+        Some(length) if length >= compression_info.min_size() => {}
+        _ => return false,
+    }
+
+    let content_type = match headers.get(CONTENT_TYPE).and_then(|v| v.to_str().ok()) {
+        Some(content_type) => content_type,
+        None => return false,
+    };
+
+    if compression_info.content_types().is_empty() {
+        is_compressible_content_type(content_type)
+    } else {
+        compression_info
+            .content_types()
+            .iter()
+            .any(|t| content_type.starts_with(t.as_str()))
+    }
+}
+
+fn response_encoding(
+    resp: &Response<Body>,
+    acceptable_encodings: &[Encoding],
+    compression_info: &crate::config::CompressionInfo,
+) -> Option<Encoding> {
+    let encoding = *acceptable_encodings.first()?;
+
+    if is_compressible_response(resp, compression_info) {
+        Some(encoding)
+    } else {
+        None
+    }
+}
+
+fn add_vary_accept_encoding(headers: &mut HeaderMap) {
+    let present = headers.get_all(VARY).iter().any(|value| {
+        value.to_str().is_ok_and(|value| {
+            value
+                .split(',')
+                .any(|v| v.trim().eq_ignore_ascii_case("accept-encoding"))
+        })
+    });
+
+    if !present {
+        headers.append(VARY, HeaderValue::from_static("Accept-Encoding"));
+    }
+}
+
+pub fn compress_response(
+    mut resp: Response<Body>,
+    acceptable_encodings: &[Encoding],
+    compression_info: &crate::config::CompressionInfo,
+) -> crate::server::ResponseFuture {
+    if !is_compressible_response(&resp, compression_info) {
+        return Box::new(future::ok(resp));
+    }
+
+    // The representation depends on Accept-Encoding even when this client
+    // gets identity.
+    add_vary_accept_encoding(resp.headers_mut());
+
+    let encoding = match response_encoding(&resp, acceptable_encodings, compression_info) {
+        Some(encoding) => encoding,
+        None => return Box::new(future::ok(resp)),
+    };
+
+    let level = match encoding {
+        Encoding::Gzip => compression_info.gzip_level(),
+        Encoding::Brotli => compression_info.brotli_quality(),
+    };
+
+    let (mut parts, body) = resp.into_parts();
+
+    Box::new(body.concat2().from_err().and_then(move |chunk| {
+        crate::utils::blocking_io(move || compress(encoding, level, &chunk))
+            .from_err()
+            .map(move |data| {
+                parts
+                    .headers
+                    .insert(CONTENT_ENCODING, encoding.header_value());
+                parts
+                    .headers
+                    .insert(CONTENT_LENGTH, HeaderValue::from(data.len() as u64));
+
+                Response::from_parts(parts, Body::from(data))
+            })
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request_headers(accept_encoding: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            ACCEPT_ENCODING,
+            HeaderValue::from_str(accept_encoding).unwrap(),
+        );
+        headers
+    }
+
+    fn compression_info() -> crate::config::CompressionInfo {
+        ::serde_json::from_str(r#"{ "min_size": 4 }"#).unwrap()
+    }
+
+    fn text_response(status: StatusCode) -> Response<Body> {
+        Response::builder()
+            .status(status)
+            .header(CONTENT_TYPE, "text/plain")
+            .body(Body::from("some text"))
+            .unwrap()
+    }
+
+    #[test]
+    fn acceptable_encodings_orders_by_quality() {
+        assert_eq!(
+            acceptable_encodings(&request_headers("gzip, br")),
+            vec![Encoding::Brotli, Encoding::Gzip]
+        );
+        assert_eq!(
+            acceptable_encodings(&request_headers("gzip;q=1.0, br;q=0.5")),
+            vec![Encoding::Gzip, Encoding::Brotli]
+        );
+        assert_eq!(
+            acceptable_encodings(&request_headers("x-gzip")),
+            vec![Encoding::Gzip]
+        );
+        assert_eq!(
+            acceptable_encodings(&request_headers("GZIP;q=0.5, identity")),
+            vec![Encoding::Gzip]
+        );
+    }
+
+    #[test]
+    fn acceptable_encodings_handles_wildcard_and_zero_quality() {
+        assert_eq!(
+            acceptable_encodings(&request_headers("*")),
+            vec![Encoding::Brotli, Encoding::Gzip]
+        );
+        assert_eq!(
+            acceptable_encodings(&request_headers("*, br;q=0")),
+            vec![Encoding::Gzip]
+        );
+        assert!(acceptable_encodings(&request_headers("*;q=0")).is_empty());
+        assert!(acceptable_encodings(&request_headers("deflate")).is_empty());
+        assert!(acceptable_encodings(&HeaderMap::new()).is_empty());
+    }
+
+    #[test]
+    fn response_encoding_picks_first_acceptable() {
+        let info = compression_info();
+        let resp = text_response(StatusCode::OK);
+
+        assert_eq!(
+            response_encoding(&resp, &[Encoding::Gzip, Encoding::Brotli], &info),
+            Some(Encoding::Gzip)
+        );
+        assert_eq!(response_encoding(&resp, &[], &info), None);
+    }
+
+    #[test]
+    fn response_encoding_skips_uncompressible_responses() {
+        let info = compression_info();
+        let encodings = [Encoding::Gzip];
+
+        assert_eq!(
+            response_encoding(
+                &text_response(StatusCode::PARTIAL_CONTENT),
+                &encodings,
+                &info
+            ),
+            None
+        );
+        assert_eq!(
+            response_encoding(&text_response(StatusCode::NOT_MODIFIED), &encodings, &info),
+            None
+        );
+
+        for (name, value) in &[
+            (CONTENT_RANGE, "bytes */9"),
+            (ETAG, "\"abc\""),
+            (CONTENT_ENCODING, "gzip"),
+            (CONTENT_TYPE, "image/png"),
+        ] {
+            let mut resp = text_response(StatusCode::OK);
+            resp.headers_mut()
+                .insert(name.clone(), HeaderValue::from_static(value));
+            assert_eq!(response_encoding(&resp, &encodings, &info), None);
+        }
+
+        let small = Response::builder()
+            .header(CONTENT_TYPE, "text/plain")
+            .body(Body::from("abc"))
+            .unwrap();
+        assert_eq!(response_encoding(&small, &encodings, &info), None);
+    }
+
+    #[test]
+    fn compress_response_adds_vary_for_identity() {
+        let info = compression_info();
+
+        let resp = compress_response(text_response(StatusCode::OK), &[], &info)
+            .wait()
+            .unwrap();
+        assert_eq!(resp.headers().get_all(VARY).iter().count(), 1);
+        assert!(!resp.headers().contains_key(CONTENT_ENCODING));
+
+        let mut resp = text_response(StatusCode::OK);
+        resp.headers_mut()
+            .insert(VARY, HeaderValue::from_static("Accept-Encoding"));
+        let resp = compress_response(resp, &[], &info).wait().unwrap();
+        assert_eq!(resp.headers().get_all(VARY).iter().count(), 1);
+
+        let resp = compress_response(text_response(StatusCode::PARTIAL_CONTENT), &[], &info)
+            .wait()
+            .unwrap();
+        assert!(!resp.headers().contains_key(VARY));
+    }
+}
@@ -1,9 +1,115 @@
-use log::info;
+mod validation;
 
 use serde_derive::Deserialize;
 
+use std::collections::BTreeMap;
+use std::fmt;
 use std::io::Read;
 
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CommandMetricType {
+    #[default]
+    Gauge,
+    Counter,
+}
+
+// Extracts a metric value from command output.  The value is the capture group
+// named "value" if the regex has one, otherwise the first group.  Label values
+// may reference other named groups as $name.
+#[derive(Debug, Clone, Deserialize)]
+pub struct CommandMetricInfo {
+    name: String,
+    #[serde(default)]
+    help: Option<String>,
+    regex: String,
+    #[serde(default)]
+    labels: BTreeMap<String, String>,
+    #[serde(default)]
+    metric_type: CommandMetricType,
+}
+
+impl CommandMetricInfo {
+    pub fn name(&self) -> &String {
+        &self.name
+    }
+
+    pub fn help(&self) -> &Option<String> {
+        &self.help
+    }
+
+    pub fn regex(&self) -> &String {
+        &self.regex
+    }
+
+    pub fn labels(&self) -> &BTreeMap<String, String> {
+        &self.labels
+    }
+
+    pub fn metric_type(&self) -> CommandMetricType {
+        self.metric_type
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SandboxNamespace {
+    Mount,
+    Pid,
+    Network,
+}
+
+fn default_sandbox_namespaces() -> Vec<SandboxNamespace> {
+    vec![
+        SandboxNamespace::Mount,
+        SandboxNamespace::Pid,
+        SandboxNamespace::Network,
+    ]
+}
+
+// default blocks syscalls for changing mounts, namespaces, kernel modules
+// and tracing other processes; unconfined installs no filter.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SeccompProfile {
+    #[default]
+    Default,
+    Unconfined,
+}
+
+// Runs the command in new namespaces with no_new_privs set.  read_only_paths
+// need the mount namespace.  When the sandbox can't be set up the command
+// runs without it unless required is set; the api response says which.
+#[derive(Debug, Clone, Deserialize)]
+pub struct SandboxInfo {
+    #[serde(default = "default_sandbox_namespaces")]
+    namespaces: Vec<SandboxNamespace>,
+    #[serde(default)]
+    read_only_paths: Vec<String>,
+    #[serde(default)]
+    seccomp_profile: SeccompProfile,
+    #[serde(default)]
+    required: bool,
+}
+
+impl SandboxInfo {
+    pub fn namespaces(&self) -> &Vec<SandboxNamespace> {
+        &self.namespaces
+    }
+
+    pub fn read_only_paths(&self) -> &Vec<String> {
+        &self.read_only_paths
+    }
+
+    pub fn seccomp_profile(&self) -> SeccompProfile {
+        self.seccomp_profile
+    }
+
+    pub fn required(&self) -> bool {
+        self.required
+    }
+}
+
 #[derive(Debug, Clone, Deserialize)]
 pub struct CommandInfo {
     api_path: String,
@@ -11,6 +117,12 @@ pub struct CommandInfo {
     description: String,
     command: String,
     args: Vec<String>,
+    #[serde(default)]
+    metrics: Vec<CommandMetricInfo>,
+    #[serde(default)]
+    metrics_interval_seconds: Option<u64>,
+    #[serde(default)]
+    sandbox: Option<SandboxInfo>,
 }
 
 impl CommandInfo {
@@ -33,6 +145,18 @@ impl CommandInfo {
     pub fn args(&self) -> &Vec<String> {
         &self.args
     }
+
+    pub fn metrics(&self) -> &Vec<CommandMetricInfo> {
+        &self.metrics
+    }
+
+    pub fn metrics_interval_seconds(&self) -> Option<u64> {
+        self.metrics_interval_seconds
+    }
+
+    pub fn sandbox(&self) -> &Option<SandboxInfo> {
+        &self.sandbox
+    }
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -61,12 +185,30 @@ impl ProxyInfo {
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ETagType {
+    #[default]
+    Strong,
+    Weak,
+    Disabled,
+}
+
 #[derive(Debug, Clone, Deserialize)]
 pub struct StaticPathInfo {
     http_path: String,
     fs_path: String,
-    content_type: String,
+    #[serde(default)]
+    content_type: Option<String>,
     cache_control: String,
+    #[serde(default)]
+    etag: ETagType,
+    #[serde(default)]
+    precompressed: bool,
+    #[serde(default)]
+    compress: bool,
+    #[serde(default)]
+    cache_in_memory: bool,
     include_in_main_page: bool,
 }
 
@@ -79,7 +221,7 @@ impl StaticPathInfo {
         &self.fs_path
     }
 
-    pub fn content_type(&self) -> &String {
+    pub fn content_type(&self) -> &Option<String> {
         &self.content_type
     }
 
@@ -87,15 +229,165 @@ impl StaticPathInfo {
         &self.cache_control
     }
 
+    pub fn etag(&self) -> ETagType {
+        self.etag
+    }
+
+    pub fn precompressed(&self) -> bool {
+        self.precompressed
+    }
+
+    pub fn compress(&self) -> bool {
+        self.compress
+    }
+
+    pub fn cache_in_memory(&self) -> bool {
+        self.cache_in_memory
+    }
+
+    pub fn include_in_main_page(&self) -> bool {
+        self.include_in_main_page
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct StaticDirectoryInfo {
+    http_path: String,
+    fs_path: String,
+    cache_control: String,
+    #[serde(default)]
+    etag: ETagType,
+    #[serde(default)]
+    precompressed: bool,
+    #[serde(default)]
+    compress: bool,
+    #[serde(default)]
+    index_file: Option<String>,
+    #[serde(default)]
+    directory_listing: bool,
+    // dotfiles are neither listed nor served unless set
+    #[serde(default)]
+    show_hidden: bool,
+    include_in_main_page: bool,
+}
+
+impl StaticDirectoryInfo {
+    pub fn http_path(&self) -> &String {
+        &self.http_path
+    }
+
+    pub fn fs_path(&self) -> &String {
+        &self.fs_path
+    }
+
+    pub fn cache_control(&self) -> &String {
+        &self.cache_control
+    }
+
+    pub fn etag(&self) -> ETagType {
+        self.etag
+    }
+
+    pub fn precompressed(&self) -> bool {
+        self.precompressed
+    }
+
+    pub fn compress(&self) -> bool {
+        self.compress
+    }
+
+    pub fn index_file(&self) -> &Option<String> {
+        &self.index_file
+    }
+
+    pub fn directory_listing(&self) -> bool {
+        self.directory_listing
+    }
+
+    pub fn show_hidden(&self) -> bool {
+        self.show_hidden
+    }
+
     pub fn include_in_main_page(&self) -> bool {
         self.include_in_main_page
     }
 }
 
+fn default_compression_min_size() -> u64 {
+    1024
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct CompressionInfo {
+    #[serde(default = "default_compression_min_size")]
+    min_size: u64,
+    #[serde(default)]
+    content_types: Vec<String>,
+    #[serde(default)]
+    gzip_level: Option<u32>,
+    #[serde(default)]
+    brotli_quality: Option<u32>,
+}
+
+impl CompressionInfo {
+    pub fn min_size(&self) -> u64 {
+        self.min_size
+    }
+
+    pub fn content_types(&self) -> &Vec<String> {
+        &self.content_types
+    }
+
+    pub fn gzip_level(&self) -> Option<u32> {
+        self.gzip_level
+    }
+
+    pub fn brotli_quality(&self) -> Option<u32> {
+        self.brotli_quality
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AccessLogFormat {
+    #[default]
+    Json,
+    Common,
+    Combined,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct AccessLogInfo {
+    path: String,
+    #[serde(default)]
+    format: AccessLogFormat,
+    // json only; all fields when empty
+    #[serde(default)]
+    fields: Vec<String>,
+}
+
+impl AccessLogInfo {
+    pub fn path(&self) -> &String {
+        &self.path
+    }
+
+    pub fn format(&self) -> AccessLogFormat {
+        self.format
+    }
+
+    pub fn fields(&self) -> &Vec<String> {
+        &self.fields
+    }
+}
+
 #[derive(Debug, Clone, Deserialize)]
 pub struct ServerInfo {
     listen_address: String,
     tcp_nodelay: bool,
+    #[serde(default)]
+    compression: Option<CompressionInfo>,
+    #[serde(default)]
+    access_log: Option<AccessLogInfo>,
 }
 
 impl ServerInfo {
@@ -106,6 +398,701 @@ impl ServerInfo {
     pub fn tcp_nodelay(&self) -> bool {
         self.tcp_nodelay
     }
+
+    pub fn compression(&self) -> &Option<CompressionInfo> {
+        &self.compression
+    }
+
+    pub fn access_log(&self) -> &Option<AccessLogInfo> {
+        &self.access_log
+    }
+}
+
+// Rotation happens when the file would exceed max_size_bytes or when
+// interval_seconds have passed since it was opened, whichever comes first.
+#[derive(Debug, Clone, Deserialize)]
+pub struct LogRotationInfo {
+    #[serde(default)]
+    max_size_bytes: Option<u64>,
+    #[serde(default)]
+    interval_seconds: Option<u64>,
+    #[serde(default = "default_log_rotation_max_files")]
+    max_files: usize,
+}
+
+fn default_log_rotation_max_files() -> usize {
+    10
+}
+
+impl LogRotationInfo {
+    pub fn max_size_bytes(&self) -> Option<u64> {
+        self.max_size_bytes
+    }
+
+    pub fn interval_seconds(&self) -> Option<u64> {
+        self.interval_seconds
+    }
+
+    pub fn max_files(&self) -> usize {
+        self.max_files
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum LogOutputInfo {
+    Stdout,
+    File {
+        path: String,
+        #[serde(default)]
+        rotation: Option<LogRotationInfo>,
+    },
+    Syslog {
+        #[serde(default = "default_syslog_socket_path")]
+        socket_path: String,
+    },
+}
+
+fn default_syslog_socket_path() -> String {
+    "/dev/log".to_string()
+}
+
+// What happens to a log message when the output queue is full: block the
+// logging thread until there is room, or drop the new or oldest message.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LogOverflowPolicy {
+    Block,
+    #[default]
+    DropNewest,
+    DropOldest,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct LoggingInfo {
+    #[serde(default = "default_log_level")]
+    level: String,
+    #[serde(default)]
+    module_levels: BTreeMap<String, String>,
+    #[serde(default = "default_log_outputs")]
+    outputs: Vec<LogOutputInfo>,
+    #[serde(default = "default_log_queue_capacity")]
+    queue_capacity: usize,
+    #[serde(default)]
+    overflow_policy: LogOverflowPolicy,
+    #[serde(default = "default_log_dropped_report_interval_seconds")]
+    dropped_report_interval_seconds: u64,
+}
+
+fn default_log_level() -> String {
+    "info".to_string()
+}
+
+fn default_log_outputs() -> Vec<LogOutputInfo> {
+    vec![LogOutputInfo::Stdout]
+}
+
+fn default_log_queue_capacity() -> usize {
+    10000
+}
+
+fn default_log_dropped_report_interval_seconds() -> u64 {
+    60
+}
+
+impl Default for LoggingInfo {
+    fn default() -> Self {
+        LoggingInfo {
+            level: default_log_level(),
+            module_levels: BTreeMap::new(),
+            outputs: default_log_outputs(),
+            queue_capacity: default_log_queue_capacity(),
+            overflow_policy: LogOverflowPolicy::default(),
+            dropped_report_interval_seconds: default_log_dropped_report_interval_seconds(),
+        }
+    }
+}
+
+impl LoggingInfo {
+    pub fn level(&self) -> &String {
+        &self.level
+    }
+
+    pub fn module_levels(&self) -> &BTreeMap<String, String> {
+        &self.module_levels
+    }
+
+    pub fn outputs(&self) -> &Vec<LogOutputInfo> {
+        &self.outputs
+    }
+
+    pub fn queue_capacity(&self) -> usize {
+        self.queue_capacity
+    }
+
+    pub fn overflow_policy(&self) -> LogOverflowPolicy {
+        self.overflow_policy
+    }
+
+    pub fn dropped_report_interval_seconds(&self) -> u64 {
+        self.dropped_report_interval_seconds
+    }
+}
+
+// A configuration value that must never be displayed; Debug prints a
+// placeholder so it stays out of the /configuration page and logs.
+#[derive(Clone, Deserialize)]
+#[serde(transparent)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn value(&self) -> &String {
+        &self.0
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(crate::redaction::REDACTED)
+    }
+}
+
+// password_hash is a bcrypt hash, e.g. from htpasswd -nbB.
+#[derive(Debug, Clone, Deserialize)]
+pub struct UserInfo {
+    username: String,
+    password_hash: Secret,
+    #[serde(default)]
+    roles: Vec<String>,
+}
+
+impl UserInfo {
+    pub fn username(&self) -> &String {
+        &self.username
+    }
+
+    pub fn password_hash(&self) -> &String {
+        self.password_hash.value()
+    }
+
+    pub fn roles(&self) -> &Vec<String> {
+        &self.roles
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ApiTokenInfo {
+    name: String,
+    token: Secret,
+    #[serde(default)]
+    roles: Vec<String>,
+}
+
+impl ApiTokenInfo {
+    pub fn name(&self) -> &String {
+        &self.name
+    }
+
+    pub fn token(&self) -> &String {
+        self.token.value()
+    }
+
+    pub fn roles(&self) -> &Vec<String> {
+        &self.roles
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RouteAccess {
+    Public,
+    #[default]
+    Authenticated,
+}
+
+// Applies to request paths under path_prefix; the longest matching prefix
+// wins.  When roles is non-empty the user needs at least one of them.
+#[derive(Debug, Clone, Deserialize)]
+pub struct RoutePolicyInfo {
+    path_prefix: String,
+    access: RouteAccess,
+    #[serde(default)]
+    roles: Vec<String>,
+}
+
+impl RoutePolicyInfo {
+    pub fn path_prefix(&self) -> &String {
+        &self.path_prefix
+    }
+
+    pub fn access(&self) -> RouteAccess {
+        self.access
+    }
+
+    pub fn roles(&self) -> &Vec<String> {
+        &self.roles
+    }
+}
+
+// Without a configured secret a random one is generated at startup, so
+// sessions don't survive a restart.
+#[derive(Debug, Clone, Deserialize)]
+pub struct SessionInfo {
+    #[serde(default)]
+    secret: Option<Secret>,
+    #[serde(default = "default_session_cookie_name")]
+    cookie_name: String,
+    #[serde(default = "default_session_max_age_seconds")]
+    max_age_seconds: u64,
+    #[serde(default)]
+    secure_cookie: bool,
+}
+
+fn default_session_cookie_name() -> String {
+    "rust_hyper_session".to_string()
+}
+
+fn default_session_max_age_seconds() -> u64 {
+    24 * 60 * 60
+}
+
+impl SessionInfo {
+    pub fn secret(&self) -> Option<&String> {
+        self.secret.as_ref().map(Secret::value)
+    }
+
+    pub fn cookie_name(&self) -> &String {
+        &self.cookie_name
+    }
+
+    pub fn max_age_seconds(&self) -> u64 {
+        self.max_age_seconds
+    }
+
+    pub fn secure_cookie(&self) -> bool {
+        self.secure_cookie
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct AuthenticationInfo {
+    #[serde(default = "default_authentication_realm")]
+    realm: String,
+    #[serde(default)]
+    users: Vec<UserInfo>,
+    #[serde(default)]
+    api_tokens: Vec<ApiTokenInfo>,
+    #[serde(default)]
+    policies: Vec<RoutePolicyInfo>,
+    #[serde(default)]
+    default_access: RouteAccess,
+    #[serde(default)]
+    session: Option<SessionInfo>,
+}
+
+fn default_authentication_realm() -> String {
+    "rust_hyper".to_string()
+}
+
+impl AuthenticationInfo {
+    pub fn realm(&self) -> &String {
+        &self.realm
+    }
+
+    pub fn users(&self) -> &Vec<UserInfo> {
+        &self.users
+    }
+
+    pub fn api_tokens(&self) -> &Vec<ApiTokenInfo> {
+        &self.api_tokens
+    }
+
+    pub fn policies(&self) -> &Vec<RoutePolicyInfo> {
+        &self.policies
+    }
+
+    pub fn default_access(&self) -> RouteAccess {
+        self.default_access
+    }
+
+    pub fn session(&self) -> &Option<SessionInfo> {
+        &self.session
+    }
+}
+
+// Addresses are CIDR ranges such as 192.168.1.0/24 or fd00::/8; a plain
+// address matches only itself.  Deny rules win over allow rules, and a
+// non-empty allow list denies everything it doesn't match.
+#[derive(Debug, Clone, Deserialize)]
+pub struct RouteIpAccessInfo {
+    path_prefix: String,
+    #[serde(default)]
+    allow: Vec<String>,
+    #[serde(default)]
+    deny: Vec<String>,
+}
+
+impl RouteIpAccessInfo {
+    pub fn path_prefix(&self) -> &String {
+        &self.path_prefix
+    }
+
+    pub fn allow(&self) -> &Vec<String> {
+        &self.allow
+    }
+
+    pub fn deny(&self) -> &Vec<String> {
+        &self.deny
+    }
+}
+
+// Global rules apply to every request; route rules for the longest matching
+// path_prefix must also allow it.  X-Forwarded-For is only used for requests
+// from trusted_proxies.
+#[derive(Debug, Clone, Deserialize)]
+pub struct IpAccessInfo {
+    #[serde(default)]
+    trusted_proxies: Vec<String>,
+    #[serde(default)]
+    allow: Vec<String>,
+    #[serde(default)]
+    deny: Vec<String>,
+    #[serde(default)]
+    routes: Vec<RouteIpAccessInfo>,
+}
+
+impl IpAccessInfo {
+    pub fn trusted_proxies(&self) -> &Vec<String> {
+        &self.trusted_proxies
+    }
+
+    pub fn allow(&self) -> &Vec<String> {
+        &self.allow
+    }
+
+    pub fn deny(&self) -> &Vec<String> {
+        &self.deny
+    }
+
+    pub fn routes(&self) -> &Vec<RouteIpAccessInfo> {
+        &self.routes
+    }
+}
+
+// A token bucket holding up to burst requests, refilled at
+// requests_per_second.
+#[derive(Debug, Clone, Deserialize)]
+pub struct RateLimitInfo {
+    requests_per_second: f64,
+    burst: u32,
+}
+
+impl RateLimitInfo {
+    pub fn requests_per_second(&self) -> f64 {
+        self.requests_per_second
+    }
+
+    pub fn burst(&self) -> u32 {
+        self.burst
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct RouteRateLimitInfo {
+    path_prefix: String,
+    limit: RateLimitInfo,
+}
+
+impl RouteRateLimitInfo {
+    pub fn path_prefix(&self) -> &String {
+        &self.path_prefix
+    }
+
+    pub fn limit(&self) -> &RateLimitInfo {
+        &self.limit
+    }
+}
+
+// Requests are counted per authenticated user, or per client ip for
+// anonymous requests and failed authentications.  The global limit applies
+// to every request; the route limit for the longest matching path_prefix
+// applies as well.
+#[derive(Debug, Clone, Deserialize)]
+pub struct RateLimitingInfo {
+    #[serde(default)]
+    global: Option<RateLimitInfo>,
+    #[serde(default)]
+    routes: Vec<RouteRateLimitInfo>,
+    #[serde(default = "default_rate_limit_prune_interval_seconds")]
+    prune_interval_seconds: u64,
+}
+
+fn default_rate_limit_prune_interval_seconds() -> u64 {
+    60
+}
+
+impl RateLimitingInfo {
+    pub fn global(&self) -> &Option<RateLimitInfo> {
+        &self.global
+    }
+
+    pub fn routes(&self) -> &Vec<RouteRateLimitInfo> {
+        &self.routes
+    }
+
+    pub fn prune_interval_seconds(&self) -> u64 {
+        self.prune_interval_seconds
+    }
+}
+
+// Spans are exported as OTLP/HTTP JSON, so collector_url is the full traces
+// endpoint, e.g. http://localhost:4318/v1/traces.
+#[derive(Debug, Clone, Deserialize)]
+pub struct TracingInfo {
+    collector_url: String,
+    #[serde(default = "default_tracing_service_name")]
+    service_name: String,
+    #[serde(default = "default_tracing_export_interval_seconds")]
+    export_interval_seconds: u64,
+    #[serde(default = "default_tracing_max_queued_spans")]
+    max_queued_spans: usize,
+}
+
+fn default_tracing_service_name() -> String {
+    "rust_hyper".to_string()
+}
+
+fn default_tracing_export_interval_seconds() -> u64 {
+    5
+}
+
+fn default_tracing_max_queued_spans() -> usize {
+    2048
+}
+
+impl TracingInfo {
+    pub fn collector_url(&self) -> &String {
+        &self.collector_url
+    }
+
+    pub fn service_name(&self) -> &String {
+        &self.service_name
+    }
+
+    pub fn export_interval_seconds(&self) -> u64 {
+        self.export_interval_seconds
+    }
+
+    pub fn max_queued_spans(&self) -> usize {
+        self.max_queued_spans
+    }
+}
+
+// Values of keys matching patterns (case insensitive, * matches anything)
+// or listed in deny are hidden on the /configuration and /environment pages;
+// keys listed in allow are always shown.
+#[derive(Debug, Clone, Deserialize)]
+pub struct RedactionInfo {
+    #[serde(default = "default_redaction_patterns")]
+    patterns: Vec<String>,
+    #[serde(default)]
+    allow: Vec<String>,
+    #[serde(default)]
+    deny: Vec<String>,
+}
+
+fn default_redaction_patterns() -> Vec<String> {
+    vec![
+        "*TOKEN*".to_string(),
+        "*SECRET*".to_string(),
+        "*PASSWORD*".to_string(),
+    ]
+}
+
+impl Default for RedactionInfo {
+    fn default() -> Self {
+        RedactionInfo {
+            patterns: default_redaction_patterns(),
+            allow: Vec::new(),
+            deny: Vec::new(),
+        }
+    }
+}
+
+impl RedactionInfo {
+    pub fn patterns(&self) -> &Vec<String> {
+        &self.patterns
+    }
+
+    pub fn allow(&self) -> &Vec<String> {
+        &self.allow
+    }
+
+    pub fn deny(&self) -> &Vec<String> {
+        &self.deny
+    }
+}
+
+// Header values for requests under path_prefix, merged over the global
+// security headers.  An empty value removes the header.
+#[derive(Debug, Clone, Deserialize)]
+pub struct RouteSecurityHeadersInfo {
+    path_prefix: String,
+    headers: BTreeMap<String, String>,
+}
+
+impl RouteSecurityHeadersInfo {
+    pub fn path_prefix(&self) -> &String {
+        &self.path_prefix
+    }
+
+    pub fn headers(&self) -> &BTreeMap<String, String> {
+        &self.headers
+    }
+}
+
+// Added to every response that doesn't already set them; an empty value
+// leaves the header out.  The default policy allows the inline onload
+// handlers in the command and proxy pages.  HSTS is off by default since
+// the server itself only speaks plain http.
+#[derive(Debug, Clone, Deserialize)]
+pub struct SecurityHeadersInfo {
+    #[serde(default = "default_content_security_policy")]
+    content_security_policy: String,
+    #[serde(default = "default_x_content_type_options")]
+    x_content_type_options: String,
+    #[serde(default = "default_referrer_policy")]
+    referrer_policy: String,
+    #[serde(default = "default_x_frame_options")]
+    x_frame_options: String,
+    #[serde(default)]
+    strict_transport_security: String,
+    #[serde(default)]
+    routes: Vec<RouteSecurityHeadersInfo>,
+}
+
+fn default_content_security_policy() -> String {
+    "default-src 'self'; script-src 'self' 'unsafe-inline'; img-src 'self' data:; \
+     object-src 'none'; base-uri 'self'; form-action 'self'; frame-ancestors 'none'"
+        .to_string()
+}
+
+fn default_x_content_type_options() -> String {
+    "nosniff".to_string()
+}
+
+fn default_referrer_policy() -> String {
+    "same-origin".to_string()
+}
+
+fn default_x_frame_options() -> String {
+    "DENY".to_string()
+}
+
+impl Default for SecurityHeadersInfo {
+    fn default() -> Self {
+        SecurityHeadersInfo {
+            content_security_policy: default_content_security_policy(),
+            x_content_type_options: default_x_content_type_options(),
+            referrer_policy: default_referrer_policy(),
+            x_frame_options: default_x_frame_options(),
+            strict_transport_security: String::new(),
+            routes: Vec::new(),
+        }
+    }
+}
+
+impl SecurityHeadersInfo {
+    pub fn content_security_policy(&self) -> &String {
+        &self.content_security_policy
+    }
+
+    pub fn x_content_type_options(&self) -> &String {
+        &self.x_content_type_options
+    }
+
+    pub fn referrer_policy(&self) -> &String {
+        &self.referrer_policy
+    }
+
+    pub fn x_frame_options(&self) -> &String {
+        &self.x_frame_options
+    }
+
+    pub fn strict_transport_security(&self) -> &String {
+        &self.strict_transport_security
+    }
+
+    pub fn routes(&self) -> &Vec<RouteSecurityHeadersInfo> {
+        &self.routes
+    }
+}
+
+// Cross-origin requests to paths under path_prefixes.  An allowed origin is
+// either exact, "*" for any origin, or has one * standing for a subdomain as
+// in https://*.example.com.  "*" can't be combined with allow_credentials.
+#[derive(Debug, Clone, Deserialize)]
+pub struct CorsInfo {
+    #[serde(default = "default_cors_path_prefixes")]
+    path_prefixes: Vec<String>,
+    allowed_origins: Vec<String>,
+    #[serde(default = "default_cors_allowed_methods")]
+    allowed_methods: Vec<String>,
+    #[serde(default = "default_cors_allowed_headers")]
+    allowed_headers: Vec<String>,
+    #[serde(default)]
+    exposed_headers: Vec<String>,
+    #[serde(default)]
+    allow_credentials: bool,
+    #[serde(default)]
+    max_age_seconds: Option<u64>,
+}
+
+fn default_cors_path_prefixes() -> Vec<String> {
+    vec!["/api".to_string()]
+}
+
+fn default_cors_allowed_methods() -> Vec<String> {
+    vec!["GET".to_string(), "HEAD".to_string(), "POST".to_string()]
+}
+
+fn default_cors_allowed_headers() -> Vec<String> {
+    vec![
+        "authorization".to_string(),
+        "content-type".to_string(),
+        "x-csrf-token".to_string(),
+        "x-request-id".to_string(),
+    ]
+}
+
+impl CorsInfo {
+    pub fn path_prefixes(&self) -> &Vec<String> {
+        &self.path_prefixes
+    }
+
+    pub fn allowed_origins(&self) -> &Vec<String> {
+        &self.allowed_origins
+    }
+
+    pub fn allowed_methods(&self) -> &Vec<String> {
+        &self.allowed_methods
+    }
+
+    pub fn allowed_headers(&self) -> &Vec<String> {
+        &self.allowed_headers
+    }
+
+    pub fn exposed_headers(&self) -> &Vec<String> {
+        &self.exposed_headers
+    }
+
+    pub fn allow_credentials(&self) -> bool {
+        self.allow_credentials
+    }
+
+    pub fn max_age_seconds(&self) -> Option<u64> {
+        self.max_age_seconds
+    }
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -122,10 +1109,28 @@ impl MainPageInfo {
 #[derive(Debug, Clone, Deserialize)]
 pub struct Configuration {
     server_info: ServerInfo,
+    #[serde(default)]
+    logging: LoggingInfo,
+    #[serde(default)]
+    tracing: Option<TracingInfo>,
+    #[serde(default)]
+    authentication: Option<AuthenticationInfo>,
+    #[serde(default)]
+    ip_access: Option<IpAccessInfo>,
+    #[serde(default)]
+    rate_limiting: Option<RateLimitingInfo>,
+    #[serde(default)]
+    redaction: RedactionInfo,
+    #[serde(default)]
+    security_headers: SecurityHeadersInfo,
+    #[serde(default)]
+    cors: Option<CorsInfo>,
     main_page_info: MainPageInfo,
     commands: Vec<CommandInfo>,
     proxies: Vec<ProxyInfo>,
     static_paths: Vec<StaticPathInfo>,
+    #[serde(default)]
+    static_directories: Vec<StaticDirectoryInfo>,
 }
 
 impl Configuration {
@@ -133,6 +1138,38 @@ impl Configuration {
         &self.server_info
     }
 
+    pub fn logging(&self) -> &LoggingInfo {
+        &self.logging
+    }
+
+    pub fn tracing(&self) -> &Option<TracingInfo> {
+        &self.tracing
+    }
+
+    pub fn authentication(&self) -> &Option<AuthenticationInfo> {
+        &self.authentication
+    }
+
+    pub fn ip_access(&self) -> &Option<IpAccessInfo> {
+        &self.ip_access
+    }
+
+    pub fn rate_limiting(&self) -> &Option<RateLimitingInfo> {
+        &self.rate_limiting
+    }
+
+    pub fn redaction(&self) -> &RedactionInfo {
+        &self.redaction
+    }
+
+    pub fn security_headers(&self) -> &SecurityHeadersInfo {
+        &self.security_headers
+    }
+
+    pub fn cors(&self) -> &Option<CorsInfo> {
+        &self.cors
+    }
+
     pub fn main_page_info(&self) -> &MainPageInfo {
         &self.main_page_info
     }
@@ -148,11 +1185,18 @@ impl Configuration {
     pub fn static_paths(&self) -> &Vec<StaticPathInfo> {
         &self.static_paths
     }
+
+    pub fn static_directories(&self) -> &Vec<StaticDirectoryInfo> {
+        &self.static_directories
+    }
 }
 
-pub fn read_config(config_file: String) -> Result<Configuration, Box<::std::error::Error>> {
-    info!("reading {}", config_file);
+// check fails on these, while serve only warns about them.
+pub fn missing_targets(configuration: &Configuration) -> Vec<String> {
+    validation::missing_targets(configuration)
+}
 
+pub fn read_config(config_file: String) -> Result<Configuration, Box<::std::error::Error>> {
     let mut file = ::std::fs::File::open(config_file)?;
 
     let mut file_contents = String::new();
@@ -161,5 +1205,7 @@ pub fn read_config(config_file: String) -> Result<Configuration, Box<::std::erro
 
     let configuration: Configuration = ::serde_json::from_str(&file_contents)?;
 
+    validation::validate(&configuration)?;
+
     Ok(configuration)
 }
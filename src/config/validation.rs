@@ -0,0 +1,589 @@
+use hyper::header::HeaderValue;
+use hyper::Uri;
+
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+
+// Routes the server always registers, besides the built-in assets, which
+// configured routes may replace.
+const BUILT_IN_ROUTES: [&str; 6] = [
+    "/",
+    "/admin/log_level",
+    "/configuration",
+    "/environment",
+    "/metrics",
+    "/static_cache",
+];
+
+fn is_executable(path: &Path) -> bool {
+    match fs::metadata(path) {
+        Ok(metadata) => metadata.is_file() && metadata.permissions().mode() & 0o111 != 0,
+        Err(_) => false,
+    }
+}
+
+// Collects every problem so they can all be reported at once.  Each one is
+// prefixed with the JSON path of the offending value.
+struct Validator {
+    problems: Vec<String>,
+    // http path to the JSON path that registered it
+    routes: HashMap<String, String>,
+    prefix_routes: HashMap<String, String>,
+}
+
+impl Validator {
+    fn new() -> Self {
+        Validator {
+            problems: Vec::new(),
+            routes: HashMap::new(),
+            prefix_routes: HashMap::new(),
+        }
+    }
+
+    fn problem(&mut self, json_path: &str, message: String) {
+        self.problems.push(format!("{}: {}", json_path, message));
+    }
+
+    fn check_http_path(&mut self, json_path: &str, http_path: &str) -> bool {
+        if http_path.starts_with('/') {
+            true
+        } else {
+            self.problem(
+                json_path,
+                format!("path '{}' does not start with '/'", http_path),
+            );
+            false
+        }
+    }
+
+    fn add_route(&mut self, json_path: String, http_path: &str) {
+        if !self.check_http_path(&json_path, http_path) {
+            return;
+        }
+
+        if let Some(existing_json_path) = self.routes.get(http_path).cloned() {
+            self.problem(
+                &json_path,
+                format!(
+                    "route '{}' is already used by {}",
+                    http_path, existing_json_path
+                ),
+            );
+        } else {
+            self.routes.insert(http_path.to_string(), json_path);
+        }
+    }
+
+    fn add_prefix_route(&mut self, json_path: String, http_path: &str) {
+        if !self.check_http_path(&json_path, http_path) {
+            return;
+        }
+
+        let prefix = http_path.trim_end_matches('/').to_string();
+        if let Some(existing_json_path) = self.prefix_routes.get(&prefix).cloned() {
+            self.problem(
+                &json_path,
+                format!(
+                    "route prefix '{}' is already used by {}",
+                    http_path, existing_json_path
+                ),
+            );
+        } else {
+            self.prefix_routes.insert(prefix, json_path);
+        }
+    }
+
+    fn check_header_value(&mut self, json_path: &str, value: &str) {
+        if let Err(e) = HeaderValue::from_str(value) {
+            self.problem(
+                json_path,
+                format!("invalid header value {:?}: {}", value, e),
+            );
+        }
+    }
+
+    fn check_fs_path(&mut self, json_path: &str, fs_path: &str, directory: bool) {
+        match fs::metadata(fs_path) {
+            Ok(ref metadata) if directory && !metadata.is_dir() => {
+                self.problem(json_path, format!("'{}' is not a directory", fs_path));
+            }
+            Ok(ref metadata) if !directory && !metadata.is_file() => {
+                self.problem(json_path, format!("'{}' is not a file", fs_path));
+            }
+            Ok(_) => {}
+            Err(e) => self.problem(json_path, format!("'{}': {}", fs_path, e)),
+        }
+    }
+
+    fn check_command(&mut self, json_path: &str, command: &str) {
+        let found = if command.contains('/') {
+            is_executable(Path::new(command))
+        } else {
+            env::var_os("PATH").is_some_and(|path| {
+                env::split_paths(&path).any(|directory| is_executable(&directory.join(command)))
+            })
+        };
+
+        if !found {
+            self.problem(
+                json_path,
+                format!("command '{}' not found or not executable", command),
+            );
+        }
+    }
+
+    fn check_proxy_url(&mut self, json_path: &str, url: &str) {
+        match url.parse::<Uri>() {
+            Ok(ref uri) if uri.scheme_part().map(|scheme| scheme.as_str()) != Some("http") => {
+                self.problem(json_path, format!("url '{}' is not an http url", url));
+            }
+            Ok(ref uri) if uri.authority_part().is_none() => {
+                self.problem(json_path, format!("url '{}' has no host", url));
+            }
+            Ok(_) => {}
+            Err(e) => self.problem(json_path, format!("invalid url '{}': {}", url, e)),
+        }
+    }
+
+    fn validate_routes(&mut self, configuration: &super::Configuration) {
+        for route in BUILT_IN_ROUTES.iter() {
+            self.routes
+                .insert(route.to_string(), "a built-in route".to_string());
+        }
+
+        let session_configured = configuration
+            .authentication()
+            .as_ref()
+            .is_some_and(|authentication_info| authentication_info.session().is_some());
+        if session_configured {
+            for route in &[crate::auth::LOGIN_PATH, crate::auth::LOGOUT_PATH] {
+                self.routes
+                    .insert(route.to_string(), "a built-in route".to_string());
+            }
+        }
+
+        for (i, command_info) in configuration.commands().iter().enumerate() {
+            self.add_route(
+                format!("$.commands[{}].api_path", i),
+                command_info.api_path(),
+            );
+            self.add_route(
+                format!("$.commands[{}].html_path", i),
+                command_info.html_path(),
+            );
+        }
+
+        for (i, proxy_info) in configuration.proxies().iter().enumerate() {
+            self.add_route(format!("$.proxies[{}].api_path", i), proxy_info.api_path());
+            self.add_route(
+                format!("$.proxies[{}].html_path", i),
+                proxy_info.html_path(),
+            );
+        }
+
+        for (i, static_path_info) in configuration.static_paths().iter().enumerate() {
+            self.add_route(
+                format!("$.static_paths[{}].http_path", i),
+                static_path_info.http_path(),
+            );
+        }
+
+        for (i, static_directory_info) in configuration.static_directories().iter().enumerate() {
+            self.add_prefix_route(
+                format!("$.static_directories[{}].http_path", i),
+                static_directory_info.http_path(),
+            );
+        }
+    }
+
+    fn validate_path_prefixes(&mut self, configuration: &super::Configuration) {
+        if let Some(authentication_info) = configuration.authentication() {
+            for (i, policy_info) in authentication_info.policies().iter().enumerate() {
+                self.check_http_path(
+                    &format!("$.authentication.policies[{}].path_prefix", i),
+                    policy_info.path_prefix(),
+                );
+            }
+        }
+
+        if let Some(ip_access_info) = configuration.ip_access() {
+            for (i, route_info) in ip_access_info.routes().iter().enumerate() {
+                self.check_http_path(
+                    &format!("$.ip_access.routes[{}].path_prefix", i),
+                    route_info.path_prefix(),
+                );
+            }
+        }
+
+        if let Some(rate_limiting_info) = configuration.rate_limiting() {
+            for (i, route_info) in rate_limiting_info.routes().iter().enumerate() {
+                self.check_http_path(
+                    &format!("$.rate_limiting.routes[{}].path_prefix", i),
+                    route_info.path_prefix(),
+                );
+            }
+        }
+
+        for (i, route_info) in configuration.security_headers().routes().iter().enumerate() {
+            self.check_http_path(
+                &format!("$.security_headers.routes[{}].path_prefix", i),
+                route_info.path_prefix(),
+            );
+        }
+
+        if let Some(cors_info) = configuration.cors() {
+            for (i, path_prefix) in cors_info.path_prefixes().iter().enumerate() {
+                self.check_http_path(&format!("$.cors.path_prefixes[{}]", i), path_prefix);
+            }
+        }
+    }
+
+    fn validate_header_values(&mut self, configuration: &super::Configuration) {
+        for (i, static_path_info) in configuration.static_paths().iter().enumerate() {
+            self.check_header_value(
+                &format!("$.static_paths[{}].cache_control", i),
+                static_path_info.cache_control(),
+            );
+            if let Some(content_type) = static_path_info.content_type() {
+                self.check_header_value(
+                    &format!("$.static_paths[{}].content_type", i),
+                    content_type,
+                );
+            }
+        }
+
+        for (i, static_directory_info) in configuration.static_directories().iter().enumerate() {
+            self.check_header_value(
+                &format!("$.static_directories[{}].cache_control", i),
+                static_directory_info.cache_control(),
+            );
+        }
+
+        let security_headers_info = configuration.security_headers();
+        for (field, value) in &[
+            (
+                "content_security_policy",
+                security_headers_info.content_security_policy(),
+            ),
+            (
+                "x_content_type_options",
+                security_headers_info.x_content_type_options(),
+            ),
+            ("referrer_policy", security_headers_info.referrer_policy()),
+            ("x_frame_options", security_headers_info.x_frame_options()),
+            (
+                "strict_transport_security",
+                security_headers_info.strict_transport_security(),
+            ),
+        ] {
+            self.check_header_value(&format!("$.security_headers.{}", field), value);
+        }
+
+        for (i, route_info) in security_headers_info.routes().iter().enumerate() {
+            for (name, value) in route_info.headers() {
+                self.check_header_value(
+                    &format!("$.security_headers.routes[{}].headers.{}", i, name),
+                    value,
+                );
+            }
+        }
+    }
+
+    fn validate_access_log_fields(&mut self, configuration: &super::Configuration) {
+        if let Some(access_log_info) = configuration.server_info().access_log() {
+            for (i, field) in access_log_info.fields().iter().enumerate() {
+                if !crate::access_log::JSON_FIELDS.contains(&field.as_str()) {
+                    self.problem(
+                        &format!("$.server_info.access_log.fields[{}]", i),
+                        format!("unknown access log field '{}'", field),
+                    );
+                }
+            }
+        }
+    }
+
+    fn validate_proxy_urls(&mut self, configuration: &super::Configuration) {
+        for (i, proxy_info) in configuration.proxies().iter().enumerate() {
+            self.check_proxy_url(&format!("$.proxies[{}].url", i), proxy_info.url());
+        }
+    }
+
+    fn validate_targets(&mut self, configuration: &super::Configuration) {
+        for (i, command_info) in configuration.commands().iter().enumerate() {
+            self.check_command(
+                &format!("$.commands[{}].command", i),
+                command_info.command(),
+            );
+        }
+
+        for (i, static_path_info) in configuration.static_paths().iter().enumerate() {
+            self.check_fs_path(
+                &format!("$.static_paths[{}].fs_path", i),
+                static_path_info.fs_path(),
+                false,
+            );
+        }
+
+        for (i, static_directory_info) in configuration.static_directories().iter().enumerate() {
+            self.check_fs_path(
+                &format!("$.static_directories[{}].fs_path", i),
+                static_directory_info.fs_path(),
+                true,
+            );
+        }
+    }
+}
+
+pub fn validate(configuration: &super::Configuration) -> Result<(), Box<::std::error::Error>> {
+    let mut validator = Validator::new();
+
+    validator.validate_routes(configuration);
+    validator.validate_path_prefixes(configuration);
+    validator.validate_header_values(configuration);
+    validator.validate_proxy_urls(configuration);
+    validator.validate_access_log_fields(configuration);
+
+    if validator.problems.is_empty() {
+        Ok(())
+    } else {
+        Err(format!(
+            "invalid configuration:\n  {}",
+            validator.problems.join("\n  ")
+        )
+        .into())
+    }
+}
+
+// Commands and fs_paths that don't exist, which may only be a problem on
+// this machine or until they are installed.
+pub fn missing_targets(configuration: &super::Configuration) -> Vec<String> {
+    let mut validator = Validator::new();
+
+    validator.validate_targets(configuration);
+
+    validator.problems
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // overrides replaces top-level keys of a minimal valid configuration
+    fn configuration(overrides: &str) -> super::super::Configuration {
+        let mut configuration: ::serde_json::Value = ::serde_json::from_str(
+            r#"{
+                "server_info": { "listen_address": "127.0.0.1:0", "tcp_nodelay": true },
+                "main_page_info": { "title": "test" },
+                "commands": [],
+                "proxies": [],
+                "static_paths": []
+            }"#,
+        )
+        .unwrap();
+
+        let overrides: ::serde_json::Value = ::serde_json::from_str(overrides).unwrap();
+        for (key, value) in overrides.as_object().unwrap() {
+            configuration[key] = value.clone();
+        }
+
+        ::serde_json::from_value(configuration).unwrap()
+    }
+
+    fn problems(overrides: &str) -> Vec<String> {
+        match validate(&configuration(overrides)) {
+            Ok(()) => Vec::new(),
+            Err(e) => e
+                .to_string()
+                .lines()
+                .skip(1)
+                .map(|line| line.trim().to_string())
+                .collect(),
+        }
+    }
+
+    // an entry for either static_paths or static_directories
+    fn static_path(http_path: &str) -> String {
+        format!(
+            r#"{{ "http_path": "{}", "fs_path": "x", "cache_control": "no-cache",
+                  "include_in_main_page": false }}"#,
+            http_path
+        )
+    }
+
+    #[test]
+    fn valid_configuration_has_no_problems() {
+        assert!(problems("{}").is_empty());
+        assert!(problems(&format!(
+            r#"{{ "static_paths": [{}] }}"#,
+            static_path("/style.css")
+        ))
+        .is_empty());
+    }
+
+    #[test]
+    fn duplicate_built_in_route_is_reported() {
+        assert_eq!(
+            problems(&format!(
+                r#"{{ "static_paths": [{}] }}"#,
+                static_path("/metrics")
+            )),
+            vec![
+                "$.static_paths[0].http_path: route '/metrics' is already used by a built-in route"
+            ]
+        );
+
+        assert!(problems(&format!(
+            r#"{{ "static_paths": [{}] }}"#,
+            static_path("/login")
+        ))
+        .is_empty());
+        assert_eq!(
+            problems(&format!(
+                r#"{{ "authentication": {{ "session": {{}} }}, "static_paths": [{}] }}"#,
+                static_path("/login")
+            )),
+            vec!["$.static_paths[0].http_path: route '/login' is already used by a built-in route"]
+        );
+    }
+
+    #[test]
+    fn prefix_collisions_ignore_trailing_slashes() {
+        assert_eq!(
+            problems(&format!(
+                r#"{{ "static_directories": [{}, {}] }}"#,
+                static_path("/files"),
+                static_path("/files/")
+            )),
+            vec![
+                "$.static_directories[1].http_path: route prefix '/files/' is already used by \
+                 $.static_directories[0].http_path"
+            ]
+        );
+
+        assert!(problems(&format!(
+            r#"{{ "static_directories": [{}, {}] }}"#,
+            static_path("/files"),
+            static_path("/files/nested")
+        ))
+        .is_empty());
+    }
+
+    #[test]
+    fn non_http_proxy_url_is_reported() {
+        let proxy = |url: &str| {
+            format!(
+                r#"{{ "proxies": [{{ "api_path": "/api/proxy", "html_path": "/proxy",
+                      "description": "proxy", "url": "{}" }}] }}"#,
+                url
+            )
+        };
+
+        assert!(problems(&proxy("http://127.0.0.1:8080/status")).is_empty());
+        assert_eq!(
+            problems(&proxy("https://127.0.0.1/status")),
+            vec!["$.proxies[0].url: url 'https://127.0.0.1/status' is not an http url"]
+        );
+        assert_eq!(
+            problems(&proxy("/status")),
+            vec!["$.proxies[0].url: url '/status' is not an http url"]
+        );
+    }
+
+    #[test]
+    fn invalid_header_value_is_reported() {
+        let problems = problems(
+            r#"{ "security_headers": { "routes": [
+                { "path_prefix": "/api", "headers": { "x-test": "bad\u0001value" } }
+            ] } }"#,
+        );
+
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0]
+            .starts_with("$.security_headers.routes[0].headers.x-test: invalid header value"));
+    }
+
+    #[test]
+    fn unknown_access_log_field_is_reported() {
+        assert_eq!(
+            problems(
+                r#"{ "server_info": { "listen_address": "127.0.0.1:0", "tcp_nodelay": true,
+                     "access_log": { "path": "access.log", "fields": ["status", "nonsense"] } } }"#
+            ),
+            vec!["$.server_info.access_log.fields[1]: unknown access log field 'nonsense'"]
+        );
+    }
+
+    #[test]
+    fn all_problems_are_reported_together() {
+        let problems = problems(&format!(
+            r#"{{
+                "static_paths": [{}, {}],
+                "authentication": {{ "policies": [{{ "path_prefix": "api", "access": "public" }}] }},
+                "cors": {{ "allowed_origins": ["https://example.com"], "path_prefixes": ["/api", "x"] }}
+            }}"#,
+            static_path("/environment"),
+            static_path("relative")
+        ));
+
+        assert_eq!(
+            problems,
+            vec![
+                "$.static_paths[0].http_path: route '/environment' is already used by a built-in route",
+                "$.static_paths[1].http_path: path 'relative' does not start with '/'",
+                "$.authentication.policies[0].path_prefix: path 'api' does not start with '/'",
+                "$.cors.path_prefixes[1]: path 'x' does not start with '/'",
+            ]
+        );
+    }
+
+    #[test]
+    fn missing_targets_reports_commands_and_fs_paths() {
+        let dir = env::temp_dir().join(format!("rust_hyper_validation_{}", ::std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("file"), "file").unwrap();
+
+        let command = |command: &str| {
+            format!(
+                r#"{{ "api_path": "/api/c", "html_path": "/c", "description": "c",
+                      "command": "{}", "args": [] }}"#,
+                command
+            )
+        };
+        let configuration = configuration(&format!(
+            r#"{{
+                "commands": [{}, {}],
+                "static_paths": [{{ "http_path": "/f", "fs_path": "{}", "cache_control": "no-cache",
+                                    "include_in_main_page": false }}],
+                "static_directories": [{{ "http_path": "/d", "fs_path": "{}", "cache_control": "no-cache",
+                                          "include_in_main_page": false }}]
+            }}"#,
+            command("/bin/sh"),
+            command("rust_hyper_no_such_command"),
+            dir.display(),
+            dir.join("file").display()
+        ));
+
+        assert_eq!(
+            missing_targets(&configuration),
+            vec![
+                "$.commands[1].command: command 'rust_hyper_no_such_command' not found or not \
+                 executable"
+                    .to_string(),
+                format!(
+                    "$.static_paths[0].fs_path: '{}' is not a file",
+                    dir.display()
+                ),
+                format!(
+                    "$.static_directories[0].fs_path: '{}' is not a directory",
+                    dir.join("file").display()
+                ),
+            ]
+        );
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
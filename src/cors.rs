@@ -0,0 +1,280 @@
+use hyper::header::{
+    HeaderMap, HeaderValue, ACCESS_CONTROL_ALLOW_CREDENTIALS, ACCESS_CONTROL_ALLOW_HEADERS,
+    ACCESS_CONTROL_ALLOW_METHODS, ACCESS_CONTROL_ALLOW_ORIGIN, ACCESS_CONTROL_EXPOSE_HEADERS,
+    ACCESS_CONTROL_MAX_AGE, ACCESS_CONTROL_REQUEST_HEADERS, ACCESS_CONTROL_REQUEST_METHOD, ORIGIN,
+    VARY,
+};
+use hyper::{Body, Method, Request, Response, StatusCode};
+
+use log::debug;
+
+enum OriginPattern {
+    Any,
+    Exact(String),
+    // the text before and after the *
+    Subdomain(String, String),
+}
+
+impl OriginPattern {
+    fn parse(origin: &str) -> Result<Self, Box<::std::error::Error>> {
+        let origin = origin.trim_end_matches('/').to_ascii_lowercase();
+
+        if origin == "*" {
+            return Ok(OriginPattern::Any);
+        }
+
+        let mut split = origin.splitn(2, '*');
+        match (split.next(), split.next()) {
+            (Some(_), Some(suffix)) if suffix.contains('*') => {
+                Err(format!("invalid cors origin '{}'", origin).into())
+            }
+            (Some(prefix), Some(suffix)) => Ok(OriginPattern::Subdomain(
+                prefix.to_string(),
+                suffix.to_string(),
+            )),
+            _ => Ok(OriginPattern::Exact(origin)),
+        }
+    }
+
+    fn matches(&self, origin: &str) -> bool {
+        match self {
+            OriginPattern::Any => true,
+            OriginPattern::Exact(exact) => origin.eq_ignore_ascii_case(exact),
+            OriginPattern::Subdomain(prefix, suffix) => {
+                let origin = origin.to_ascii_lowercase();
+                origin.len() > prefix.len() + suffix.len()
+                    && origin.starts_with(prefix.as_str())
+                    && origin.ends_with(suffix.as_str())
+                    && origin[prefix.len()..origin.len() - suffix.len()]
+                        .chars()
+                        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.')
+            }
+        }
+    }
+}
+
+fn join_header_value(values: &[String]) -> Result<HeaderValue, Box<::std::error::Error>> {
+    Ok(HeaderValue::from_str(&values.join(", "))?)
+}
+
+pub struct Cors {
+    path_prefixes: Vec<String>,
+    allowed_origins: Vec<OriginPattern>,
+    allowed_methods: Vec<Method>,
+    allowed_methods_header_value: HeaderValue,
+    allowed_headers: Vec<String>,
+    allowed_headers_header_value: HeaderValue,
+    exposed_headers_header_value: Option<HeaderValue>,
+    allow_credentials: bool,
+    max_age_header_value: Option<HeaderValue>,
+}
+
+impl Cors {
+    pub fn new(cors_info: &crate::config::CorsInfo) -> Result<Self, Box<::std::error::Error>> {
+        let mut allowed_origins = Vec::with_capacity(cors_info.allowed_origins().len());
+        for origin in cors_info.allowed_origins() {
+            let pattern = OriginPattern::parse(origin)?;
+
+            // any origin could then make requests with the user's cookies
+            if let (OriginPattern::Any, true) = (&pattern, cors_info.allow_credentials()) {
+                return Err(
+                    "cors allowed_origins \"*\" can't be used with allow_credentials".into(),
+                );
+            }
+
+            allowed_origins.push(pattern);
+        }
+
+        let mut allowed_methods = Vec::with_capacity(cors_info.allowed_methods().len());
+        for method in cors_info.allowed_methods() {
+            allowed_methods.push(
+                Method::from_bytes(method.to_ascii_uppercase().as_bytes())
+                    .map_err(|e| format!("invalid cors method '{}': {}", method, e))?,
+            );
+        }
+
+        let allowed_headers: Vec<String> = cors_info
+            .allowed_headers()
+            .iter()
+            .map(|header| header.to_ascii_lowercase())
+            .collect();
+
+        let exposed_headers_header_value = if cors_info.exposed_headers().is_empty() {
+            None
+        } else {
+            Some(join_header_value(cors_info.exposed_headers())?)
+        };
+
+        Ok(Cors {
+            path_prefixes: cors_info.path_prefixes().clone(),
+            allowed_origins,
+            allowed_methods,
+            allowed_methods_header_value: join_header_value(cors_info.allowed_methods())?,
+            allowed_headers_header_value: join_header_value(&allowed_headers)?,
+            allowed_headers,
+            exposed_headers_header_value,
+            allow_credentials: cors_info.allow_credentials(),
+            max_age_header_value: cors_info.max_age_seconds().map(HeaderValue::from),
+        })
+    }
+
+    // Responses for covered paths depend on the request's Origin.
+    pub fn covers(&self, path: &str) -> bool {
+        self.path_prefixes
+            .iter()
+            .any(|path_prefix| crate::server::path_matches_prefix(path, path_prefix))
+    }
+
+    // The request's Origin if it may read the response from path.  Allowed
+    // origins are always echoed back, even for "*", so every response for a
+    // covered path has the same form.
+    pub fn allowed_origin(&self, req: &Request<Body>, path: &str) -> Option<HeaderValue> {
+        if !self.covers(path) {
+            return None;
+        }
+
+        let origin = req.headers().get(ORIGIN)?;
+        let origin_str = origin.to_str().ok()?;
+
+        if self
+            .allowed_origins
+            .iter()
+            .any(|pattern| pattern.matches(origin_str))
+        {
+            Some(origin.clone())
+        } else {
+            None
+        }
+    }
+
+    fn is_allowed_preflight(&self, req: &Request<Body>) -> bool {
+        let method_allowed = req
+            .headers()
+            .get(ACCESS_CONTROL_REQUEST_METHOD)
+            .and_then(|value| Method::from_bytes(value.as_bytes()).ok())
+            .is_some_and(|method| self.allowed_methods.contains(&method));
+
+        let headers_allowed = req
+            .headers()
+            .get_all(ACCESS_CONTROL_REQUEST_HEADERS)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(|header| header.trim().to_ascii_lowercase())
+            .filter(|header| !header.is_empty())
+            .all(|header| self.allowed_headers.contains(&header));
+
+        method_allowed && headers_allowed
+    }
+
+    // Answers OPTIONS preflight requests for covered paths; anything else
+    // returns None and is dispatched as usual.
+    pub fn preflight_response(&self, req: &Request<Body>, path: &str) -> Option<Response<Body>> {
+        if req.method() != Method::OPTIONS
+            || !req.headers().contains_key(ACCESS_CONTROL_REQUEST_METHOD)
+        {
+            return None;
+        }
+
+        let origin = self.allowed_origin(req, path)?;
+
+        if !self.is_allowed_preflight(req) {
+            debug!("rejected cors preflight for {}", path);
+            let mut response = crate::server::build_response_status(StatusCode::FORBIDDEN);
+            self.add_response_headers(None, response.headers_mut());
+            return Some(response);
+        }
+
+        let mut response = crate::server::build_response_status(StatusCode::NO_CONTENT);
+        let headers = response.headers_mut();
+        self.add_response_headers(Some(origin), headers);
+        headers.insert(
+            ACCESS_CONTROL_ALLOW_METHODS,
+            self.allowed_methods_header_value.clone(),
+        );
+        headers.insert(
+            ACCESS_CONTROL_ALLOW_HEADERS,
+            self.allowed_headers_header_value.clone(),
+        );
+        if let Some(max_age_header_value) = &self.max_age_header_value {
+            headers.insert(ACCESS_CONTROL_MAX_AGE, max_age_header_value.clone());
+        }
+
+        Some(response)
+    }
+
+    // For responses on covered paths, origin is the allowed_origin of the
+    // request.  Vary is sent even when the origin isn't allowed, so caches
+    // don't hand that response to an allowed origin or the other way round.
+    pub fn add_response_headers(&self, origin: Option<HeaderValue>, headers: &mut HeaderMap) {
+        headers.append(VARY, HeaderValue::from_static("origin"));
+
+        let origin = match origin {
+            Some(origin) => origin,
+            None => return,
+        };
+
+        headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, origin);
+        if self.allow_credentials {
+            headers.insert(
+                ACCESS_CONTROL_ALLOW_CREDENTIALS,
+                HeaderValue::from_static("true"),
+            );
+        }
+        if let Some(exposed_headers_header_value) = &self.exposed_headers_header_value {
+            headers.insert(
+                ACCESS_CONTROL_EXPOSE_HEADERS,
+                exposed_headers_header_value.clone(),
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cors(cors_json: &str) -> Result<Cors, Box<::std::error::Error>> {
+        let cors_info: crate::config::CorsInfo = ::serde_json::from_str(cors_json).unwrap();
+        Cors::new(&cors_info)
+    }
+
+    fn request(origin: &str) -> Request<Body> {
+        Request::get("/api/test")
+            .header(ORIGIN, origin)
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    #[test]
+    fn any_origin_with_credentials_is_rejected() {
+        assert!(cors(r#"{"allowed_origins": ["*"], "allow_credentials": true}"#).is_err());
+        assert!(cors(r#"{"allowed_origins": ["*"]}"#).is_ok());
+        assert!(cors(
+            r#"{"allowed_origins": ["https://*.example.com"], "allow_credentials": true}"#
+        )
+        .is_ok());
+    }
+
+    #[test]
+    fn vary_is_sent_for_disallowed_origins() {
+        let cors = cors(r#"{"allowed_origins": ["https://*.example.com"]}"#).unwrap();
+
+        assert!(cors.covers("/api/test"));
+        assert!(!cors.covers("/other"));
+
+        let allowed = cors.allowed_origin(&request("https://app.example.com"), "/api/test");
+        assert_eq!(
+            allowed,
+            Some(HeaderValue::from_static("https://app.example.com"))
+        );
+
+        let disallowed = cors.allowed_origin(&request("https://evil.com"), "/api/test");
+        assert_eq!(disallowed, None);
+
+        let mut headers = HeaderMap::new();
+        cors.add_response_headers(disallowed, &mut headers);
+        assert_eq!(headers.get(VARY), Some(&HeaderValue::from_static("origin")));
+        assert!(!headers.contains_key(ACCESS_CONTROL_ALLOW_ORIGIN));
+    }
+}
@@ -1,6 +1,5 @@
 use std::collections::BTreeMap;
 use std::env;
-use std::process::Command;
 
 #[derive(Debug, Clone)]
 pub struct Environment {
@@ -14,16 +13,6 @@ impl Environment {
     }
 }
 
-fn get_git_hash() -> Result<String, Box<::std::error::Error>> {
-    let output = Command::new("git").arg("rev-parse").arg("HEAD").output()?;
-
-    let mut combined_output = String::with_capacity(output.stderr.len() + output.stdout.len());
-    combined_output.push_str(&String::from_utf8_lossy(&output.stderr));
-    combined_output.push_str(&String::from_utf8_lossy(&output.stdout));
-
-    Ok(combined_output.trim().to_string())
-}
-
 fn get_env_vars() -> BTreeMap<String, String> {
     let mut map = BTreeMap::new();
 
@@ -34,9 +23,10 @@ fn get_env_vars() -> BTreeMap<String, String> {
     map
 }
 
-pub fn get_environment() -> Result<Environment, Box<::std::error::Error>> {
-    Ok(Environment {
-        git_hash: get_git_hash()?,
+// The git hash is the one embedded at build time, as shown by --version.
+pub fn get_environment() -> Environment {
+    Environment {
+        git_hash: env!("GIT_HASH").to_string(),
         env_vars: get_env_vars(),
-    })
+    }
 }
@@ -1,7 +1,13 @@
+pub mod asset;
 pub mod command;
 pub mod config;
 pub mod environment;
 pub mod index;
+pub mod log_level;
+pub mod login;
+pub mod metrics;
 pub mod not_found;
 pub mod proxy;
+pub mod static_cache;
+pub mod static_directory;
 pub mod static_file;
@@ -0,0 +1,42 @@
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::handlers::static_file::{CachedFile, FileRequestInfo, StaticFileOptions};
+
+pub struct AssetHandler {
+    asset: &'static crate::assets::Asset,
+    cached_file: Arc<CachedFile>,
+    options: StaticFileOptions,
+}
+
+impl AssetHandler {
+    pub fn new(
+        asset: &'static crate::assets::Asset,
+        compressed_file_cache: &Arc<crate::handlers::static_file::CompressedFileCache>,
+    ) -> Result<Self, Box<::std::error::Error>> {
+        let options = StaticFileOptions::new(
+            Some(asset.content_type()),
+            crate::assets::ASSET_CACHE_CONTROL,
+            crate::config::ETagType::default(),
+            false,
+            Some(Arc::clone(compressed_file_cache)),
+        )?;
+
+        Ok(AssetHandler {
+            asset,
+            cached_file: Arc::new(CachedFile::from_static(asset.data())),
+            options,
+        })
+    }
+}
+
+impl crate::server::RequestHandler for AssetHandler {
+    fn handle(&self, req_context: &crate::server::RequestContext) -> crate::server::ResponseFuture {
+        crate::handlers::static_file::serve_cached_file(
+            FileRequestInfo::new(req_context.req()),
+            Arc::clone(&self.cached_file),
+            Path::new(self.asset.http_path()),
+            &self.options,
+        )
+    }
+}
@@ -1,40 +1,135 @@
-use futures::Future;
+use futures::{future, Future, Stream};
 
 use hyper::StatusCode;
 
+use log::warn;
+
 use serde_derive::Serialize;
 
 use std::borrow::Cow;
-use std::process::Command;
+use std::io;
+use std::process::{Command, Output};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use tokio_process::CommandExt;
 
+type OutputFuture = Box<Future<Item = (Output, Option<String>), Error = io::Error> + Send>;
+
 struct InnerAPIHandler {
     command_info: crate::config::CommandInfo,
     command_line_string: String,
+    metric_rules: crate::command_metrics::CommandMetricRules,
+    sandbox: Option<Arc<crate::sandbox::Sandbox>>,
 }
 
 impl InnerAPIHandler {
-    fn run_command(
-        &self,
-    ) -> Box<Future<Item = String, Error = crate::server::HandlerError> + Send> {
+    fn build_command(&self) -> Command {
         let mut command = Command::new(self.command_info.command());
 
         command.args(self.command_info.args());
 
-        Box::new(
-            command
-                .output_async()
-                .and_then(move |output| {
+        command
+    }
+
+    // The second item describes the sandbox for sandboxed commands.  If it
+    // can't be set up the command runs again without it, unless required.
+    // Other spawn errors, such as a missing command, are returned as is.
+    fn command_output(self: &Arc<Self>) -> OutputFuture {
+        let sandbox = match &self.sandbox {
+            Some(sandbox) => sandbox,
+            None => {
+                return Box::new(
+                    self.build_command()
+                        .output_async()
+                        .map(|output| (output, None)),
+                )
+            }
+        };
+
+        let mut command = self.build_command();
+        sandbox.apply(&mut command);
+
+        let required = sandbox.required();
+        let self_clone = Arc::clone(self);
+
+        Box::new(command.output_async().then(move |result| -> OutputFuture {
+            let setup_error = match result {
+                Ok(output) => return Box::new(future::ok((output, Some("applied".to_string())))),
+                Err(e) => match crate::sandbox::setup_error(&e) {
+                    Some(setup_error) => setup_error,
+                    None => return Box::new(future::err(e)),
+                },
+            };
+
+            if required {
+                return Box::new(future::err(io::Error::new(
+                    setup_error.kind(),
+                    format!("sandbox could not be applied: {}", setup_error),
+                )));
+            }
+
+            warn!(
+                "running {} without sandbox: {}",
+                self_clone.command_info.api_path(),
+                setup_error
+            );
+            let sandbox_status = format!("not applied: {}", setup_error);
+            Box::new(
+                self_clone
+                    .build_command()
+                    .output_async()
+                    .map(move |output| (output, Some(sandbox_status))),
+            )
+        }))
+    }
+
+    fn run_command(
+        self: &Arc<Self>,
+        metrics: &Arc<crate::metrics::Metrics>,
+        span: Option<crate::trace::Span>,
+    ) -> Box<Future<Item = (String, Option<String>), Error = crate::server::HandlerError> + Send>
+    {
+        let start_time = Instant::now();
+        let metrics_clone = Arc::clone(metrics);
+        let self_clone = Arc::clone(self);
+
+        Box::new(self.command_output().then(move |result| {
+            let (exit_code, command_output, sandbox_status) = match result {
+                Ok((output, sandbox_status)) => {
+                    let stdout = String::from_utf8_lossy(&output.stdout);
+
+                    // metrics are only extracted from stdout
+                    self_clone.metric_rules.record(&stdout, &metrics_clone);
+
                     let mut combined_output =
                         String::with_capacity(output.stderr.len() + output.stdout.len());
                     combined_output.push_str(&String::from_utf8_lossy(&output.stderr));
-                    combined_output.push_str(&String::from_utf8_lossy(&output.stdout));
-                    Ok(combined_output)
-                })
-                .or_else(move |err| Ok(format!("command error: {}", err))),
-        )
+                    combined_output.push_str(&stdout);
+                    (output.status.code(), combined_output, sandbox_status)
+                }
+                Err(err) => (None, format!("command error: {}", err), None),
+            };
+
+            metrics_clone.observe_command(
+                self_clone.command_info.api_path(),
+                exit_code,
+                start_time.elapsed(),
+            );
+
+            if let Some(mut span) = span {
+                span.set_attribute("command.line", self_clone.command_line_string.clone());
+                if let Some(exit_code) = exit_code {
+                    span.set_attribute("command.exit_code", i64::from(exit_code));
+                }
+                if exit_code != Some(0) {
+                    span.set_error();
+                }
+                span.end();
+            }
+
+            Ok((command_output, sandbox_status))
+        }))
     }
 }
 
@@ -43,7 +138,10 @@ pub struct APIHandler {
 }
 
 impl APIHandler {
-    pub fn new(command_info: crate::config::CommandInfo) -> Self {
+    pub fn new(
+        command_info: crate::config::CommandInfo,
+        metrics: &crate::metrics::Metrics,
+    ) -> Result<Self, Box<::std::error::Error>> {
         let mut command_line_string = String::new();
 
         command_line_string.push_str(command_info.command());
@@ -53,12 +151,57 @@ impl APIHandler {
             command_line_string.push_str(arg);
         }
 
-        APIHandler {
+        let metric_rules = crate::command_metrics::CommandMetricRules::new(&command_info, metrics)?;
+
+        let sandbox = match command_info.sandbox() {
+            Some(sandbox_info) => Some(Arc::new(crate::sandbox::Sandbox::new(sandbox_info)?)),
+            None => None,
+        };
+
+        Ok(APIHandler {
             inner: Arc::new(InnerAPIHandler {
                 command_info,
                 command_line_string,
+                metric_rules,
+                sandbox,
             }),
+        })
+    }
+
+    // Runs the command once outside of a request, for the run-command
+    // subcommand.  Yields the output and the sandbox status.
+    pub fn run_once(
+        &self,
+        metrics: &Arc<crate::metrics::Metrics>,
+    ) -> Box<Future<Item = (String, Option<String>), Error = crate::server::HandlerError> + Send>
+    {
+        self.inner.run_command(metrics, None)
+    }
+
+    // Runs the command every metrics_interval_seconds so its metrics stay
+    // current without anyone calling the api.
+    pub fn metrics_task(
+        &self,
+        metrics: &Arc<crate::metrics::Metrics>,
+    ) -> Option<crate::server::BackgroundTask> {
+        let interval_seconds = self.inner.command_info.metrics_interval_seconds()?;
+
+        if self.inner.metric_rules.is_empty() || interval_seconds == 0 {
+            return None;
         }
+
+        let inner_clone = Arc::clone(&self.inner);
+        let metrics_clone = Arc::clone(metrics);
+
+        Some(Box::new(future::lazy(move || {
+            ::tokio_timer::Interval::new_interval(Duration::from_secs(interval_seconds))
+                .map_err(|e| warn!("metrics interval error: {}", e))
+                .for_each(move |_| {
+                    inner_clone
+                        .run_command(&metrics_clone, None)
+                        .then(|_| Ok(()))
+                })
+        })))
     }
 }
 
@@ -67,29 +210,42 @@ struct APIResponse {
     now: String,
     command_line: String,
     output: String,
+    // only present for sandboxed commands
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sandbox: Option<String>,
 }
 
 impl crate::server::RequestHandler for APIHandler {
-    fn handle(&self, _: &crate::server::RequestContext) -> crate::server::ResponseFuture {
+    fn handle(&self, req_context: &crate::server::RequestContext) -> crate::server::ResponseFuture {
         let inner_clone = Arc::clone(&self.inner);
 
-        Box::new(self.inner.run_command().and_then(move |command_output| {
-            let api_response = APIResponse {
-                now: crate::utils::local_time_now_to_string(),
-                command_line: inner_clone.command_line_string.clone(),
-                output: command_output,
-            };
-
-            match ::serde_json::to_string(&api_response) {
-                Ok(json_string) => Ok(crate::server::build_response_string(
-                    StatusCode::OK,
-                    Cow::from(json_string),
-                    crate::server::application_json_content_type_header_value(),
-                )),
-                Err(_) => Ok(crate::server::build_response_status(
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                )),
-            }
-        }))
+        let metrics = req_context.app_context().metrics();
+
+        let span = req_context.start_child_span(
+            format!("command {}", self.inner.command_info.api_path()),
+            crate::trace::SpanKind::Internal,
+        );
+
+        Box::new(self.inner.run_command(metrics, span).and_then(
+            move |(command_output, sandbox_status)| {
+                let api_response = APIResponse {
+                    now: crate::utils::local_time_now_to_string(),
+                    command_line: inner_clone.command_line_string.clone(),
+                    output: command_output,
+                    sandbox: sandbox_status,
+                };
+
+                match ::serde_json::to_string(&api_response) {
+                    Ok(json_string) => Ok(crate::server::build_response_string(
+                        StatusCode::OK,
+                        Cow::from(json_string),
+                        crate::server::application_json_content_type_header_value(),
+                    )),
+                    Err(_) => Ok(crate::server::build_response_status(
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                    )),
+                }
+            },
+        ))
     }
 }
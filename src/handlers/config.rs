@@ -9,8 +9,11 @@ pub struct ConfigHandler {
 }
 
 impl ConfigHandler {
-    pub fn new(config: &crate::config::Configuration) -> Self {
-        let config_string = format!("{:#?}", config);
+    pub fn new(
+        config: &crate::config::Configuration,
+        redactor: &crate::redaction::Redactor,
+    ) -> Self {
+        let config_string = redactor.redact_debug(&format!("{:#?}", config));
         ConfigHandler { config_string }
     }
 }
@@ -9,8 +9,11 @@ pub struct EnvironmentHandler {
 }
 
 impl EnvironmentHandler {
-    pub fn new(environment: &crate::environment::Environment) -> Self {
-        let environment_string = format!("{:#?}", environment);
+    pub fn new(
+        environment: &crate::environment::Environment,
+        redactor: &crate::redaction::Redactor,
+    ) -> Self {
+        let environment_string = redactor.redact_debug(&format!("{:#?}", environment));
         EnvironmentHandler { environment_string }
     }
 }
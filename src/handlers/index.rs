@@ -16,6 +16,7 @@ impl IndexHandler {
     pub fn new(
         config: &crate::config::Configuration,
         environment: &crate::environment::Environment,
+        path_to_handler: &crate::server::RouteConfigurationHandlerMap,
     ) -> Result<Self, Box<::std::error::Error>> {
         let static_paths_to_include: Vec<_> = config
             .static_paths()
@@ -23,10 +24,25 @@ impl IndexHandler {
             .filter(|s| s.include_in_main_page())
             .collect();
 
+        let static_directories_to_include: Vec<_> = config
+            .static_directories()
+            .iter()
+            .filter(|s| s.include_in_main_page())
+            .collect();
+
+        // the log level and static cache routes are optional
+        let log_level = path_to_handler.contains_key(crate::handlers::log_level::LOG_LEVEL_PATH);
+        let static_cache = path_to_handler.contains_key("/static_cache");
+
         let mut last_modified_string = String::new();
         last_modified_string.push_str("Last Modified: ");
         last_modified_string.push_str(&crate::utils::local_time_now_to_string());
 
+        let session_login = config
+            .authentication()
+            .as_ref()
+            .is_some_and(|authentication_info| authentication_info.session().is_some());
+
         let mut git_hash_string = String::new();
         git_hash_string.push_str("Git Hash: ");
         git_hash_string.push_str(&environment.git_hash());
@@ -85,6 +101,20 @@ impl IndexHandler {
                   }
                 }
               }
+              @ if !static_directories_to_include.is_empty() {
+                h3 {
+                  : "Static Directories:"
+                }
+                ul {
+                  @ for static_directory_info in &static_directories_to_include {
+                    li {
+                      a(href = static_directory_info.http_path()) {
+                        : static_directory_info.fs_path()
+                      }
+                    }
+                  }
+                }
+              }
               h3 {
                 : "Debugging:"
               }
@@ -99,6 +129,32 @@ impl IndexHandler {
                     : "environment"
                   }
                 }
+                @ if log_level {
+                  li {
+                    a(href = crate::handlers::log_level::LOG_LEVEL_PATH) {
+                      : "log levels"
+                    }
+                  }
+                }
+                li {
+                  a(href = "/metrics") {
+                    : "metrics"
+                  }
+                }
+                @ if static_cache {
+                  li {
+                    a(href = "/static_cache") {
+                      : "static cache"
+                    }
+                  }
+                }
+              }
+              @ if session_login {
+                p {
+                  a(href = crate::auth::LOGOUT_PATH) {
+                    : "Logout"
+                  }
+                }
               }
               hr;
               small {
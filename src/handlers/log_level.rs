@@ -0,0 +1,69 @@
+use futures::future;
+
+use hyper::{Method, StatusCode};
+
+use std::borrow::Cow;
+
+pub const LOG_LEVEL_PATH: &str = "/admin/log_level";
+
+pub struct LogLevelHandler {
+    log_level_control: crate::logging::LogLevelControl,
+}
+
+impl LogLevelHandler {
+    pub fn new(log_level_control: crate::logging::LogLevelControl) -> Self {
+        LogLevelHandler { log_level_control }
+    }
+
+    // POST ?level=debug sets the global level, POST ?module=rust_hyper::server&level=debug
+    // sets a module override.
+    fn update_level(&self, query: Option<&str>) -> Result<(), Box<::std::error::Error>> {
+        let mut module = None;
+        let mut level = None;
+
+        for pair in query.unwrap_or("").split('&') {
+            let mut split = pair.splitn(2, '=');
+            match (split.next(), split.next()) {
+                (Some("module"), Some(value)) => module = Some(value),
+                (Some("level"), Some(value)) => level = Some(value),
+                _ => {}
+            }
+        }
+
+        match level {
+            Some(level) => self.log_level_control.set_level(module, level),
+            None => Err("level parameter required".into()),
+        }
+    }
+}
+
+impl crate::server::RequestHandler for LogLevelHandler {
+    fn handle(&self, req_context: &crate::server::RequestContext) -> crate::server::ResponseFuture {
+        let req = req_context.req();
+
+        let status = match *req.method() {
+            Method::GET | Method::HEAD => Ok(()),
+            Method::POST | Method::PUT => self.update_level(req.uri().query()),
+            _ => {
+                return Box::new(future::ok(crate::server::build_response_status(
+                    StatusCode::METHOD_NOT_ALLOWED,
+                )))
+            }
+        };
+
+        let response = match status {
+            Ok(()) => crate::server::build_response_string(
+                StatusCode::OK,
+                Cow::from(self.log_level_control.describe()),
+                crate::server::text_plain_content_type_header_value(),
+            ),
+            Err(e) => crate::server::build_response_string(
+                StatusCode::BAD_REQUEST,
+                Cow::from(format!("{}\n", e)),
+                crate::server::text_plain_content_type_header_value(),
+            ),
+        };
+
+        Box::new(future::ok(response))
+    }
+}
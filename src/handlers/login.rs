@@ -0,0 +1,374 @@
+use futures::{future, Future, Stream};
+
+use horrorshow::helper::doctype;
+use horrorshow::Template;
+use horrorshow::{append_html, html};
+
+use hyper::header::SET_COOKIE;
+use hyper::{Body, Method, Response, StatusCode};
+
+use log::{info, warn};
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::io;
+use std::sync::Arc;
+
+const MAX_FORM_BYTES: usize = 8 * 1024;
+
+fn read_form(
+    req_context: &crate::server::RequestContext,
+) -> Box<Future<Item = HashMap<String, String>, Error = crate::server::HandlerError> + Send> {
+    let body = match req_context.take_body() {
+        Some(body) => body,
+        None => return Box::new(future::ok(HashMap::new())),
+    };
+
+    Box::new(
+        body.map_err(crate::server::HandlerError::from)
+            .fold(Vec::new(), |mut form, chunk| {
+                if form.len() + chunk.len() > MAX_FORM_BYTES {
+                    Err(crate::server::HandlerError::from(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "form too large",
+                    )))
+                } else {
+                    form.extend_from_slice(&chunk);
+                    Ok(form)
+                }
+            })
+            .map(|form| crate::utils::parse_form(&String::from_utf8_lossy(&form))),
+    )
+}
+
+// Characters allowed unencoded in a path and query, besides alphanumerics.
+// Browsers drop tabs and newlines from Location, so "/\t/evil.com" would
+// otherwise become "//evil.com".
+const NEXT_PUNCTUATION: &str = "-._~!$&'()*+,;=:@/?%";
+
+// Only paths on this server, so the login form can't be used as an open
+// redirect.
+fn safe_next(next: Option<&String>) -> String {
+    match next {
+        Some(next)
+            if next.starts_with('/')
+                && !next.starts_with("//")
+                && next
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || NEXT_PUNCTUATION.contains(c)) =>
+        {
+            next.clone()
+        }
+        _ => "/".to_string(),
+    }
+}
+
+fn html_response(
+    status_code: StatusCode,
+    html: Result<String, horrorshow::Error>,
+) -> Response<Body> {
+    match html {
+        Ok(html) => crate::server::build_response_string(
+            status_code,
+            Cow::from(html),
+            crate::server::text_html_content_type_header_value(),
+        ),
+        Err(e) => {
+            warn!("error rendering page: {}", e);
+            crate::server::build_response_status(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+fn login_page(
+    status_code: StatusCode,
+    session_manager: &crate::session::SessionManager,
+    next: &str,
+    error: Option<&str>,
+) -> Response<Body> {
+    let csrf_token = match crate::session::new_csrf_token() {
+        Ok(csrf_token) => csrf_token,
+        Err(e) => {
+            warn!("error generating csrf token: {}", e);
+            return crate::server::build_response_status(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    };
+
+    let error_message = error.unwrap_or("");
+
+    let html = html! {
+        : doctype::HTML;
+        html {
+          head {
+            title: "Login";
+            meta(name = "viewport", content = "width=device-width, initial-scale=1");
+            link(rel = "stylesheet", type = "text/css", href = "/style.css");
+          }
+          body {
+            h2 {
+              : "Login"
+            }
+            @ if !error_message.is_empty() {
+              p {
+                : error_message
+              }
+            }
+            form(method = "post", action = crate::auth::LOGIN_PATH) {
+              input(type = "hidden", name = crate::auth::CSRF_TOKEN_PARAMETER, value = &csrf_token);
+              input(type = "hidden", name = "next", value = next);
+              label(for = "username") {
+                : "Username"
+              }
+              br;
+              input(type = "text", id = "username", name = "username", autocomplete = "username", autofocus);
+              br;
+              label(for = "password") {
+                : "Password"
+              }
+              br;
+              input(type = "password", id = "password", name = "password", autocomplete = "current-password");
+              br;
+              input(type = "submit", value = "Login");
+            }
+          }
+        }
+    }
+    .into_string();
+
+    let mut response = html_response(status_code, html);
+    response
+        .headers_mut()
+        .append(SET_COOKIE, session_manager.login_csrf_cookie(&csrf_token));
+    response
+}
+
+fn login_succeeded(
+    session_manager: &crate::session::SessionManager,
+    username: &str,
+    next: &str,
+) -> Response<Body> {
+    let session = match session_manager.create_session(username) {
+        Ok(session) => session,
+        Err(e) => {
+            warn!("error creating session: {}", e);
+            return crate::server::build_response_status(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    };
+
+    info!("user '{}' logged in", username);
+
+    let mut response = crate::server::build_response_redirect(StatusCode::SEE_OTHER, next);
+    let headers = response.headers_mut();
+    headers.append(SET_COOKIE, session_manager.session_cookie(&session));
+    headers.append(SET_COOKIE, session_manager.clear_login_csrf_cookie());
+    response
+}
+
+pub struct LoginHandler {
+    authenticator: crate::auth::Authenticator,
+    session_manager: Arc<crate::session::SessionManager>,
+}
+
+impl LoginHandler {
+    pub fn new(authenticator: crate::auth::Authenticator) -> Option<Self> {
+        let session_manager = Arc::clone(authenticator.session_manager()?);
+
+        Some(LoginHandler {
+            authenticator,
+            session_manager,
+        })
+    }
+}
+
+impl crate::server::RequestHandler for LoginHandler {
+    fn handle(&self, req_context: &crate::server::RequestContext) -> crate::server::ResponseFuture {
+        let req = req_context.req();
+
+        match *req.method() {
+            Method::GET | Method::HEAD => {
+                let query = crate::utils::parse_form(req.uri().query().unwrap_or(""));
+                Box::new(future::ok(login_page(
+                    StatusCode::OK,
+                    &self.session_manager,
+                    &safe_next(query.get("next")),
+                    None,
+                )))
+            }
+            Method::POST => {
+                let login_csrf_token = self.session_manager.login_csrf_from_headers(req.headers());
+                let authenticator = self.authenticator.clone();
+                let session_manager = Arc::clone(&self.session_manager);
+
+                Box::new(read_form(req_context).and_then(
+                    move |form| -> crate::server::ResponseFuture {
+                        let next = safe_next(form.get("next"));
+
+                        let csrf_valid = match (
+                            login_csrf_token,
+                            form.get(crate::auth::CSRF_TOKEN_PARAMETER),
+                        ) {
+                            (Some(cookie_token), Some(form_token)) => {
+                                crate::utils::constant_time_eq(
+                                    cookie_token.as_bytes(),
+                                    form_token.as_bytes(),
+                                )
+                            }
+                            _ => false,
+                        };
+
+                        if !csrf_valid {
+                            return Box::new(future::ok(login_page(
+                                StatusCode::FORBIDDEN,
+                                &session_manager,
+                                &next,
+                                Some("The login form expired, please try again."),
+                            )));
+                        }
+
+                        let username = form.get("username").cloned().unwrap_or_default();
+                        let password = form.get("password").cloned().unwrap_or_default();
+
+                        Box::new(authenticator.verify_password(username, password).map(
+                            move |principal| match principal {
+                                Some(principal) => {
+                                    login_succeeded(&session_manager, principal.name(), &next)
+                                }
+                                None => login_page(
+                                    StatusCode::UNAUTHORIZED,
+                                    &session_manager,
+                                    &next,
+                                    Some("Invalid username or password."),
+                                ),
+                            },
+                        ))
+                    },
+                ))
+            }
+            _ => Box::new(future::ok(crate::server::build_response_status(
+                StatusCode::METHOD_NOT_ALLOWED,
+            ))),
+        }
+    }
+}
+
+pub struct LogoutHandler {
+    session_manager: Arc<crate::session::SessionManager>,
+}
+
+impl LogoutHandler {
+    pub fn new(authenticator: &crate::auth::Authenticator) -> Option<Self> {
+        Some(LogoutHandler {
+            session_manager: Arc::clone(authenticator.session_manager()?),
+        })
+    }
+}
+
+impl crate::server::RequestHandler for LogoutHandler {
+    fn handle(&self, req_context: &crate::server::RequestContext) -> crate::server::ResponseFuture {
+        let req = req_context.req();
+
+        let session = self.session_manager.session_from_headers(req.headers());
+
+        match (req.method(), session) {
+            (&Method::GET, Some(session)) | (&Method::HEAD, Some(session)) => {
+                let html = html! {
+                    : doctype::HTML;
+                    html {
+                      head {
+                        title: "Logout";
+                        meta(name = "viewport", content = "width=device-width, initial-scale=1");
+                        link(rel = "stylesheet", type = "text/css", href = "/style.css");
+                      }
+                      body {
+                        form(method = "post", action = crate::auth::LOGOUT_PATH) {
+                          input(type = "hidden", name = crate::auth::CSRF_TOKEN_PARAMETER, value = session.csrf_token());
+                          input(type = "submit", value = "Logout");
+                        }
+                      }
+                    }
+                }
+                .into_string();
+
+                Box::new(future::ok(html_response(StatusCode::OK, html)))
+            }
+            (&Method::GET, None) | (&Method::HEAD, None) => {
+                Box::new(future::ok(crate::server::build_response_redirect(
+                    StatusCode::SEE_OTHER,
+                    crate::auth::LOGIN_PATH,
+                )))
+            }
+            (&Method::POST, session) => {
+                let session_manager = Arc::clone(&self.session_manager);
+
+                Box::new(read_form(req_context).map(move |form| {
+                    if let Some(session) = session {
+                        let csrf_valid =
+                            form.get(crate::auth::CSRF_TOKEN_PARAMETER)
+                                .is_some_and(|form_token| {
+                                    crate::utils::constant_time_eq(
+                                        form_token.as_bytes(),
+                                        session.csrf_token().as_bytes(),
+                                    )
+                                });
+
+                        if !csrf_valid {
+                            warn!(
+                                "missing or invalid csrf token from '{}'",
+                                session.username()
+                            );
+                            return crate::server::build_response_status(StatusCode::FORBIDDEN);
+                        }
+
+                        session_manager.revoke_sessions(&session);
+                        info!("user '{}' logged out", session.username());
+                    }
+
+                    let mut response = crate::server::build_response_redirect(
+                        StatusCode::SEE_OTHER,
+                        crate::auth::LOGIN_PATH,
+                    );
+                    response
+                        .headers_mut()
+                        .append(SET_COOKIE, session_manager.clear_session_cookie());
+                    response
+                }))
+            }
+            _ => Box::new(future::ok(crate::server::build_response_status(
+                StatusCode::METHOD_NOT_ALLOWED,
+            ))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn next(value: &str) -> String {
+        safe_next(Some(&value.to_string()))
+    }
+
+    #[test]
+    fn safe_next_keeps_local_paths() {
+        assert_eq!(next("/commands/ifconfig"), "/commands/ifconfig");
+        assert_eq!(next("/logs/?format=json&x=%20"), "/logs/?format=json&x=%20");
+        assert_eq!(safe_next(None), "/");
+    }
+
+    #[test]
+    fn safe_next_rejects_other_hosts() {
+        assert_eq!(next("//evil.com"), "/");
+        assert_eq!(next("/\\evil.com"), "/");
+        assert_eq!(next("https://evil.com"), "/");
+        assert_eq!(next("evil.com"), "/");
+    }
+
+    #[test]
+    fn safe_next_rejects_control_characters() {
+        assert_eq!(next("/\t/evil.com"), "/");
+        assert_eq!(next("/\r\n/evil.com"), "/");
+        assert_eq!(next("/\n"), "/");
+        assert_eq!(next("/caf\u{e9}"), "/");
+        assert_eq!(next("/a b"), "/");
+    }
+}
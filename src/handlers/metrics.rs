@@ -0,0 +1,18 @@
+use futures::future;
+
+use hyper::header::HeaderValue;
+use hyper::StatusCode;
+
+use std::borrow::Cow;
+
+pub struct MetricsHandler;
+
+impl crate::server::RequestHandler for MetricsHandler {
+    fn handle(&self, req_context: &crate::server::RequestContext) -> crate::server::ResponseFuture {
+        Box::new(future::ok(crate::server::build_response_string(
+            StatusCode::OK,
+            Cow::from(req_context.app_context().metrics().render()),
+            HeaderValue::from_static("text/plain; version=0.0.4"),
+        )))
+    }
+}
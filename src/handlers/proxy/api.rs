@@ -1,15 +1,17 @@
-use futures::Future;
+use futures::{future, Future};
 
 use hyper::rt::Stream;
-use hyper::{StatusCode, Uri};
+use hyper::{Body, Request, StatusCode, Uri};
 
 use serde_derive::Serialize;
 
 use std::borrow::Cow;
 use std::sync::Arc;
+use std::time::Instant;
 
 #[derive(Default)]
 struct ResponseInfo {
+    status_code: Option<u16>,
     version: String,
     status: String,
     headers: String,
@@ -17,6 +19,7 @@ struct ResponseInfo {
 }
 
 struct InnerAPIHandler {
+    api_path: String,
     uri: Uri,
 }
 
@@ -24,11 +27,38 @@ impl InnerAPIHandler {
     fn fetch_proxy(
         &self,
         http_client: &crate::server::HyperHttpClient,
+        metrics: &Arc<crate::metrics::Metrics>,
+        request_id: &str,
+        mut span: Option<crate::trace::Span>,
     ) -> Box<Future<Item = ResponseInfo, Error = crate::server::HandlerError> + Send> {
+        let start_time = Instant::now();
+        let metrics_clone = Arc::clone(metrics);
+        let api_path = self.api_path.clone();
+
+        let mut request_builder = Request::get(self.uri.clone());
+        request_builder.header(crate::request_id::X_REQUEST_ID, request_id);
+
+        if let Some(span) = &mut span {
+            span.set_attribute("http.method", "GET");
+            span.set_attribute("http.url", self.uri.to_string());
+            request_builder.header(crate::trace::TRACEPARENT, span.context().traceparent());
+        }
+
+        let upstream_request = match request_builder.body(Body::empty()) {
+            Ok(upstream_request) => upstream_request,
+            Err(e) => {
+                return Box::new(future::ok(ResponseInfo {
+                    body: format!("proxy request error: {}", e),
+                    ..Default::default()
+                }))
+            }
+        };
+
         Box::new(
             http_client
-                .get(self.uri.clone())
+                .request(upstream_request)
                 .and_then(|response| {
+                    let status_code = Some(response.status().as_u16());
                     let version = format!("{:?}", response.version());
                     let status = format!("{}", response.status());
                     let headers = format!("{:#?}", response.headers());
@@ -36,25 +66,51 @@ impl InnerAPIHandler {
                         .into_body()
                         .concat2()
                         .then(move |result| match result {
-                            Ok(body) => Ok(ResponseInfo {
-                                version,
-                                status,
-                                headers,
-                                body: String::from_utf8_lossy(&body).into_owned(),
-                            }),
-                            Err(e) => Ok(ResponseInfo {
-                                version,
-                                status,
-                                headers,
-                                body: format!("proxy body error: {}", e),
-                            }),
+                            Ok(body) => Ok((
+                                true,
+                                ResponseInfo {
+                                    status_code,
+                                    version,
+                                    status,
+                                    headers,
+                                    body: String::from_utf8_lossy(&body).into_owned(),
+                                },
+                            )),
+                            Err(e) => Ok((
+                                false,
+                                ResponseInfo {
+                                    status_code,
+                                    version,
+                                    status,
+                                    headers,
+                                    body: format!("proxy body error: {}", e),
+                                },
+                            )),
                         })
                 })
                 .or_else(|err| {
-                    Ok(ResponseInfo {
-                        body: format!("proxy error: {}", err),
-                        ..Default::default()
-                    })
+                    Ok((
+                        false,
+                        ResponseInfo {
+                            body: format!("proxy error: {}", err),
+                            ..Default::default()
+                        },
+                    ))
+                })
+                .map(move |(success, response_info)| {
+                    metrics_clone.observe_proxy(&api_path, success, start_time.elapsed());
+
+                    if let Some(mut span) = span {
+                        if let Some(status_code) = response_info.status_code {
+                            span.set_attribute("http.status_code", i64::from(status_code));
+                        }
+                        if !success {
+                            span.set_error();
+                        }
+                        span.end();
+                    }
+
+                    response_info
                 }),
         )
     }
@@ -69,7 +125,10 @@ impl APIHandler {
         let uri = proxy_info.url().parse()?;
 
         Ok(APIHandler {
-            inner: Arc::new(InnerAPIHandler { uri }),
+            inner: Arc::new(InnerAPIHandler {
+                api_path: proxy_info.api_path().clone(),
+                uri,
+            }),
         })
     }
 }
@@ -91,9 +150,16 @@ impl crate::server::RequestHandler for APIHandler {
 
         let http_client = req_context.app_context().http_client();
 
+        let metrics = req_context.app_context().metrics();
+
+        let span = req_context.start_child_span(
+            format!("proxy {}", self.inner.api_path),
+            crate::trace::SpanKind::Client,
+        );
+
         Box::new(
             self.inner
-                .fetch_proxy(http_client)
+                .fetch_proxy(http_client, metrics, req_context.request_id(), span)
                 .and_then(move |response_info| {
                     let api_response = APIResponse {
                         now: crate::utils::local_time_now_to_string(),
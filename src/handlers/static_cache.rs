@@ -0,0 +1,40 @@
+use futures::future;
+
+use hyper::StatusCode;
+
+use std::borrow::Cow;
+use std::fmt::Write;
+use std::sync::Arc;
+
+pub struct StaticCacheHandler {
+    memory_cache: Arc<crate::handlers::static_file::StaticFileMemoryCache>,
+}
+
+impl StaticCacheHandler {
+    pub fn new(memory_cache: Arc<crate::handlers::static_file::StaticFileMemoryCache>) -> Self {
+        StaticCacheHandler { memory_cache }
+    }
+}
+
+impl crate::server::RequestHandler for StaticCacheHandler {
+    fn handle(&self, _: &crate::server::RequestContext) -> crate::server::ResponseFuture {
+        let mut body = String::new();
+
+        writeln!(body, "hits: {}", self.memory_cache.hits()).unwrap();
+        writeln!(body, "misses: {}", self.memory_cache.misses()).unwrap();
+        writeln!(body).unwrap();
+
+        for (path, size) in self.memory_cache.entry_sizes() {
+            match size {
+                Some(size) => writeln!(body, "{} {} bytes", path.display(), size).unwrap(),
+                None => writeln!(body, "{} not cached", path.display()).unwrap(),
+            }
+        }
+
+        Box::new(future::ok(crate::server::build_response_string(
+            StatusCode::OK,
+            Cow::from(body),
+            crate::server::text_plain_content_type_header_value(),
+        )))
+    }
+}
@@ -0,0 +1,521 @@
+use chrono::prelude::{DateTime, Local};
+
+use futures::{future, Future};
+
+use horrorshow::helper::doctype;
+use horrorshow::Template;
+use horrorshow::{append_html, html};
+
+use hyper::header::{HeaderMap, ACCEPT};
+use hyper::StatusCode;
+
+use log::warn;
+
+use percent_encoding::{utf8_percent_encode, PATH_SEGMENT_ENCODE_SET};
+
+use serde_derive::Serialize;
+
+use std::borrow::Cow;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+enum ResolvedPath {
+    File(PathBuf),
+    Directory(PathBuf),
+}
+
+#[derive(Serialize)]
+struct DirectoryEntry {
+    name: String,
+    directory: bool,
+    size: u64,
+    modified: String,
+}
+
+#[derive(Serialize)]
+struct DirectoryListing {
+    path: String,
+    #[serde(skip)]
+    at_root: bool,
+    entries: Vec<DirectoryEntry>,
+}
+
+struct InnerStaticDirectoryHandler {
+    http_path: String,
+    root_path: PathBuf,
+    options: crate::handlers::static_file::StaticFileOptions,
+    index_file: Option<String>,
+    directory_listing: bool,
+    show_hidden: bool,
+}
+
+impl InnerStaticDirectoryHandler {
+    // path is already decoded and normalized by the server, so only the
+    // part below http_path needs splitting.
+    fn relative_segments(&self, path: &str) -> Option<Vec<String>> {
+        if !crate::server::path_matches_prefix(path, &self.http_path) {
+            return None;
+        }
+
+        let mut segments = Vec::new();
+
+        for segment in path[self.http_path.len()..].split('/') {
+            match segment {
+                "" | "." => continue,
+                ".." => return None,
+                s if is_hidden(s) && !self.show_hidden => return None,
+                s => segments.push(s.to_string()),
+            }
+        }
+
+        Some(segments)
+    }
+
+    // canonicalize resolves symlinks, so a link pointing outside of
+    // root_path is caught by the starts_with check.
+    fn canonicalize_under_root(&self, path: &Path) -> io::Result<PathBuf> {
+        let canonical_path = fs::canonicalize(path)?;
+
+        if !canonical_path.starts_with(&self.root_path) {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("{:?} escapes {:?}", canonical_path, self.root_path),
+            ));
+        }
+
+        Ok(canonical_path)
+    }
+
+    fn resolve_path(&self, segments: &[String]) -> io::Result<ResolvedPath> {
+        let mut path = self.root_path.clone();
+        for segment in segments {
+            path.push(segment);
+        }
+
+        let canonical_path = self.canonicalize_under_root(&path)?;
+
+        if !fs::metadata(&canonical_path)?.is_dir() {
+            return Ok(ResolvedPath::File(canonical_path));
+        }
+
+        if let Some(index_file) = &self.index_file {
+            let index_path = canonical_path.join(index_file);
+            if index_path.is_file() {
+                return Ok(ResolvedPath::File(
+                    self.canonicalize_under_root(&index_path)?,
+                ));
+            }
+        }
+
+        Ok(ResolvedPath::Directory(canonical_path))
+    }
+}
+
+fn system_time_to_string(system_time: io::Result<SystemTime>) -> String {
+    match system_time {
+        Ok(system_time) => crate::utils::local_time_to_string(DateTime::<Local>::from(system_time)),
+        Err(_) => String::new(),
+    }
+}
+
+fn is_hidden(name: &str) -> bool {
+    name.starts_with('.')
+}
+
+fn read_directory_entries(
+    directory_path: &Path,
+    show_hidden: bool,
+) -> io::Result<Vec<DirectoryEntry>> {
+    let mut entries = Vec::new();
+
+    for dir_entry in fs::read_dir(directory_path)? {
+        let dir_entry = dir_entry?;
+        let name = dir_entry.file_name().to_string_lossy().into_owned();
+
+        if is_hidden(&name) && !show_hidden {
+            continue;
+        }
+
+        let metadata = match dir_entry.metadata() {
+            Ok(metadata) => metadata,
+            Err(_) => continue,
+        };
+
+        entries.push(DirectoryEntry {
+            name,
+            directory: metadata.is_dir(),
+            size: metadata.len(),
+            modified: system_time_to_string(metadata.modified()),
+        });
+    }
+
+    entries.sort_by(|a, b| b.directory.cmp(&a.directory).then(a.name.cmp(&b.name)));
+
+    Ok(entries)
+}
+
+fn wants_json(req_headers: &HeaderMap, query: Option<&str>) -> bool {
+    let json_query = query
+        .map(|q| q.split('&').any(|p| p == "format=json"))
+        .unwrap_or(false);
+
+    let json_accept = req_headers
+        .get(ACCEPT)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.contains("application/json"))
+        .unwrap_or(false);
+
+    json_query || json_accept
+}
+
+fn entry_href(entry: &DirectoryEntry) -> String {
+    let mut href = utf8_percent_encode(&entry.name, PATH_SEGMENT_ENCODE_SET).to_string();
+    if entry.directory {
+        href.push('/');
+    }
+    href
+}
+
+fn build_listing_json_response(listing: &DirectoryListing) -> ::hyper::Response<::hyper::Body> {
+    match ::serde_json::to_string(listing) {
+        Ok(json_string) => crate::server::build_response_string(
+            StatusCode::OK,
+            Cow::from(json_string),
+            crate::server::application_json_content_type_header_value(),
+        ),
+        Err(_) => crate::server::build_response_status(StatusCode::INTERNAL_SERVER_ERROR),
+    }
+}
+
+fn build_listing_html_response(listing: &DirectoryListing) -> ::hyper::Response<::hyper::Body> {
+    let mut title = String::new();
+    title.push_str("Index of ");
+    title.push_str(&listing.path);
+
+    let html_result = html! {
+        : doctype::HTML;
+        html {
+          head {
+            title: &title;
+            meta(name = "viewport", content = "width=device-width, initial-scale=1");
+            link(rel = "stylesheet", type = "text/css", href = "/style.css");
+          }
+          body {
+            h2 {
+              : &title
+            }
+            table {
+              tr {
+                th { : "Name" }
+                th { : "Size" }
+                th { : "Last Modified" }
+              }
+              @ if !listing.at_root {
+                tr {
+                  td {
+                    a(href = "..") {
+                      : ".."
+                    }
+                  }
+                  td {}
+                  td {}
+                }
+              }
+              @ for entry in &listing.entries {
+                tr {
+                  td {
+                    a(href = entry_href(entry)) {
+                      : &entry.name
+                    }
+                  }
+                  td {
+                    : entry.size
+                  }
+                  td {
+                    : &entry.modified
+                  }
+                }
+              }
+            }
+          }
+        }
+    }
+    .into_string();
+
+    match html_result {
+        Ok(html) => crate::server::build_response_string(
+            StatusCode::OK,
+            Cow::from(html),
+            crate::server::text_html_content_type_header_value(),
+        ),
+        Err(_) => crate::server::build_response_status(StatusCode::INTERNAL_SERVER_ERROR),
+    }
+}
+
+pub struct StaticDirectoryHandler {
+    inner: Arc<InnerStaticDirectoryHandler>,
+}
+
+impl StaticDirectoryHandler {
+    pub fn new(
+        static_directory_info: &crate::config::StaticDirectoryInfo,
+        compressed_file_cache: &Arc<crate::handlers::static_file::CompressedFileCache>,
+    ) -> Result<Self, Box<::std::error::Error>> {
+        let http_path = static_directory_info
+            .http_path()
+            .trim_end_matches('/')
+            .to_string();
+
+        let root_path = fs::canonicalize(static_directory_info.fs_path())?;
+
+        let options = crate::handlers::static_file::StaticFileOptions::new(
+            None,
+            static_directory_info.cache_control(),
+            static_directory_info.etag(),
+            static_directory_info.precompressed(),
+            if static_directory_info.compress() {
+                Some(Arc::clone(compressed_file_cache))
+            } else {
+                None
+            },
+        )?;
+
+        Ok(StaticDirectoryHandler {
+            inner: Arc::new(InnerStaticDirectoryHandler {
+                http_path,
+                root_path,
+                options,
+                index_file: static_directory_info.index_file().clone(),
+                directory_listing: static_directory_info.directory_listing(),
+                show_hidden: static_directory_info.show_hidden(),
+            }),
+        })
+    }
+}
+
+impl crate::server::RequestHandler for StaticDirectoryHandler {
+    fn handle(&self, req_context: &crate::server::RequestContext) -> crate::server::ResponseFuture {
+        let uri = req_context.req().uri();
+        let request_path = uri.path().to_string();
+        let json = wants_json(req_context.req().headers(), uri.query());
+
+        let segments = match self.inner.relative_segments(req_context.path()) {
+            Some(segments) => segments,
+            None => {
+                warn!("rejecting static directory path {}", request_path);
+                return Box::new(future::ok(crate::server::build_response_status(
+                    StatusCode::NOT_FOUND,
+                )));
+            }
+        };
+
+        let at_root = segments.is_empty();
+        let file_request_info =
+            crate::handlers::static_file::FileRequestInfo::new(req_context.req());
+        let inner_clone = Arc::clone(&self.inner);
+        let resolve_inner_clone = Arc::clone(&self.inner);
+
+        Box::new(
+            crate::utils::blocking_io(move || resolve_inner_clone.resolve_path(&segments)).then(
+                move |resolve_result| -> crate::server::ResponseFuture {
+                    match resolve_result {
+                        Ok(ResolvedPath::File(file_path)) => {
+                            crate::handlers::static_file::serve_file(
+                                file_request_info,
+                                file_path,
+                                inner_clone.options.clone(),
+                            )
+                        }
+                        Ok(ResolvedPath::Directory(_)) if !request_path.ends_with('/') => {
+                            let mut location = request_path;
+                            location.push('/');
+                            Box::new(future::ok(crate::server::build_response_redirect(
+                                StatusCode::MOVED_PERMANENTLY,
+                                &location,
+                            )))
+                        }
+                        Ok(ResolvedPath::Directory(directory_path)) => {
+                            if !inner_clone.directory_listing {
+                                return Box::new(future::ok(crate::server::build_response_status(
+                                    StatusCode::NOT_FOUND,
+                                )));
+                            }
+
+                            let show_hidden = inner_clone.show_hidden;
+                            Box::new(
+                                crate::utils::blocking_io(move || {
+                                    read_directory_entries(&directory_path, show_hidden)
+                                })
+                                .then(move |entries_result| {
+                                    match entries_result {
+                                        Ok(entries) => {
+                                            let listing = DirectoryListing {
+                                                path: request_path,
+                                                at_root,
+                                                entries,
+                                            };
+                                            if json {
+                                                Ok(build_listing_json_response(&listing))
+                                            } else {
+                                                Ok(build_listing_html_response(&listing))
+                                            }
+                                        }
+                                        Err(_) => Ok(crate::server::build_response_status(
+                                            StatusCode::NOT_FOUND,
+                                        )),
+                                    }
+                                }),
+                            )
+                        }
+                        Err(e) => {
+                            if e.kind() == io::ErrorKind::PermissionDenied {
+                                warn!("rejecting static directory path {}: {}", request_path, e);
+                            }
+                            Box::new(future::ok(crate::server::build_response_status(
+                                StatusCode::NOT_FOUND,
+                            )))
+                        }
+                    }
+                },
+            ),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::os::unix::fs::symlink;
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = ::std::env::temp_dir().join(format!(
+            "rust_hyper_static_directory_{}_{}",
+            name,
+            ::std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        fs::canonicalize(dir).unwrap()
+    }
+
+    fn handler(root_path: PathBuf) -> InnerStaticDirectoryHandler {
+        InnerStaticDirectoryHandler {
+            http_path: "/files".to_string(),
+            root_path,
+            options: crate::handlers::static_file::StaticFileOptions::new(
+                None,
+                "no-cache",
+                crate::config::ETagType::Disabled,
+                false,
+                None,
+            )
+            .unwrap(),
+            index_file: Some("index.html".to_string()),
+            directory_listing: true,
+            show_hidden: false,
+        }
+    }
+
+    #[test]
+    fn relative_segments_refuses_hidden_names() {
+        let dir = test_dir("hidden_segments");
+        let mut handler = handler(dir.clone());
+
+        assert_eq!(
+            handler.relative_segments("/files/a/b.txt"),
+            Some(vec!["a".to_string(), "b.txt".to_string()])
+        );
+        assert_eq!(handler.relative_segments("/files/.env"), None);
+        assert_eq!(handler.relative_segments("/files/.git/config"), None);
+        assert_eq!(handler.relative_segments("/files/../secret"), None);
+
+        handler.show_hidden = true;
+        assert_eq!(
+            handler.relative_segments("/files/.env"),
+            Some(vec![".env".to_string()])
+        );
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn read_directory_entries_hides_dotfiles() {
+        let dir = test_dir("hidden_entries");
+        fs::write(dir.join(".env"), "secret").unwrap();
+        fs::create_dir(dir.join(".git")).unwrap();
+        fs::write(dir.join("visible.txt"), "visible").unwrap();
+
+        let names = |show_hidden| {
+            read_directory_entries(&dir, show_hidden)
+                .unwrap()
+                .into_iter()
+                .map(|entry| entry.name)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(names(false), vec!["visible.txt".to_string()]);
+        assert_eq!(
+            names(true),
+            vec![
+                ".git".to_string(),
+                ".env".to_string(),
+                "visible.txt".to_string()
+            ]
+        );
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn listing_links_to_parent_only_below_root() {
+        let body = |at_root| {
+            let listing = DirectoryListing {
+                path: "/files/".to_string(),
+                at_root,
+                entries: Vec::new(),
+            };
+            let resp = build_listing_html_response(&listing);
+            let chunk = ::hyper::rt::Stream::concat2(resp.into_body())
+                .wait()
+                .unwrap();
+            String::from_utf8(chunk.to_vec()).unwrap()
+        };
+
+        assert!(!body(true).contains("href=\"..\""));
+        assert!(body(false).contains("href=\"..\""));
+    }
+
+    #[test]
+    fn resolve_path_serves_index_file() {
+        let dir = test_dir("index");
+        fs::create_dir(dir.join("sub")).unwrap();
+        fs::write(dir.join("sub").join("index.html"), "index").unwrap();
+
+        match handler(dir.clone()).resolve_path(&["sub".to_string()]) {
+            Ok(ResolvedPath::File(path)) => assert_eq!(path, dir.join("sub").join("index.html")),
+            _ => panic!("expected the index file"),
+        }
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn resolve_path_rejects_symlinked_index_outside_root() {
+        let dir = test_dir("symlinked_index");
+        let root = dir.join("root");
+        fs::create_dir_all(root.join("sub")).unwrap();
+        fs::write(dir.join("secret"), "secret").unwrap();
+        symlink(dir.join("secret"), root.join("sub").join("index.html")).unwrap();
+
+        match handler(root).resolve_path(&["sub".to_string()]) {
+            Err(e) => assert_eq!(e.kind(), io::ErrorKind::PermissionDenied),
+            _ => panic!("expected the symlinked index file to be rejected"),
+        }
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,167 +1,525 @@
-use chrono::prelude::{DateTime, Utc};
+mod conditional;
+mod encoding;
+mod file_stream;
+mod memory_cache;
+mod range;
+
+pub use self::encoding::CompressedFileCache;
+pub use self::memory_cache::{CachedFile, StaticFileMemoryCache};
+
+use bytes::Bytes;
 
 use futures::{future, Future};
 
-use hyper::header::{HeaderValue, CACHE_CONTROL, CONTENT_TYPE, IF_MODIFIED_SINCE, LAST_MODIFIED};
-use hyper::{Response, StatusCode};
+use hyper::header::{
+    HeaderMap, HeaderValue, ACCEPT_ENCODING, ACCEPT_RANGES, CACHE_CONTROL, CONTENT_ENCODING,
+    CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE, ETAG, LAST_MODIFIED, VARY,
+};
+use hyper::{Body, Method, Request, Response, StatusCode};
 
-use std::time::SystemTime;
+use log::warn;
 
-pub struct StaticFileHandler {
-    file_path: String,
-    content_type_header_value: HeaderValue,
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+type FileResponseFuture = Box<Future<Item = Response<Body>, Error = io::Error> + Send>;
+
+#[derive(Clone)]
+pub struct FileRequestInfo {
+    method: Method,
+    headers: HeaderMap,
+}
+
+impl FileRequestInfo {
+    pub fn new(req: &Request<Body>) -> Self {
+        FileRequestInfo {
+            method: req.method().clone(),
+            headers: req.headers().clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct StaticFileOptions {
+    content_type_header_value: Option<HeaderValue>,
     cache_control_header_value: HeaderValue,
+    etag_type: crate::config::ETagType,
+    precompressed: bool,
+    compressed_file_cache: Option<Arc<CompressedFileCache>>,
 }
 
-impl StaticFileHandler {
+impl StaticFileOptions {
     pub fn new(
-        file_path: String,
-        content_type: &str,
+        content_type: Option<&str>,
         cache_control: &str,
+        etag_type: crate::config::ETagType,
+        precompressed: bool,
+        compressed_file_cache: Option<Arc<CompressedFileCache>>,
     ) -> Result<Self, Box<::std::error::Error>> {
-        let content_type_header_value = HeaderValue::from_str(content_type)?;
+        let content_type_header_value = match content_type {
+            Some(content_type) => Some(HeaderValue::from_str(content_type)?),
+            None => None,
+        };
         let cache_control_header_value = HeaderValue::from_str(cache_control)?;
 
-        Ok(StaticFileHandler {
-            file_path,
+        Ok(StaticFileOptions {
             content_type_header_value,
             cache_control_header_value,
+            etag_type,
+            precompressed,
+            compressed_file_cache,
         })
     }
-}
 
-fn get_if_modified_since_time(
-    req_context: &crate::server::RequestContext,
-) -> Option<DateTime<Utc>> {
-    let req_headers = req_context.req().headers();
+    fn content_type_header_value(&self, file_path: &Path) -> HeaderValue {
+        match &self.content_type_header_value {
+            Some(content_type_header_value) => content_type_header_value.clone(),
+            None => HeaderValue::from_str(&crate::utils::content_type_for_path(file_path))
+                .unwrap_or_else(|_| HeaderValue::from_static("application/octet-stream")),
+        }
+    }
 
-    let mut return_value = None;
+    fn negotiates_encoding(&self) -> bool {
+        self.precompressed || self.compressed_file_cache.is_some()
+    }
 
-    if let Some(header_value) = req_headers.get(IF_MODIFIED_SINCE) {
-        if let Ok(str_value) = header_value.to_str() {
-            if let Ok(dt) = DateTime::parse_from_rfc2822(str_value) {
-                return_value = Some(dt.with_timezone(&Utc));
-            }
+    fn acceptable_encodings(&self, req_headers: &HeaderMap) -> Vec<crate::compression::Encoding> {
+        if self.negotiates_encoding() && req_headers.contains_key(ACCEPT_ENCODING) {
+            crate::compression::acceptable_encodings(req_headers)
+        } else {
+            Vec::new()
         }
     }
 
-    return_value
+    fn compresses(&self, content_type_header_value: &HeaderValue) -> bool {
+        self.compressed_file_cache.is_some()
+            && content_type_header_value
+                .to_str()
+                .map(crate::compression::is_compressible_content_type)
+                .unwrap_or(false)
+    }
 }
 
-fn file_modified_since_header(
-    if_modified_since_time_option: &Option<DateTime<Utc>>,
-    file_last_modified_result: &::std::io::Result<SystemTime>,
-) -> bool {
-    let mut return_value = true;
-
-    if let (Some(if_modified_since_time), Ok(file_last_modified)) =
-        (if_modified_since_time_option, file_last_modified_result)
-    {
-        let utc_file_last_modified = crate::utils::system_time_to_utc(*file_last_modified);
-        return_value = if_modified_since_time.timestamp() < utc_file_last_modified.timestamp();
-    }
+pub struct StaticFileHandler {
+    file_path: String,
+    options: StaticFileOptions,
+    memory_cache: Option<(Arc<StaticFileMemoryCache>, PathBuf)>,
+}
+
+impl StaticFileHandler {
+    pub fn new(
+        file_path: String,
+        options: StaticFileOptions,
+        memory_cache: Option<&Arc<StaticFileMemoryCache>>,
+    ) -> Self {
+        // A file that can't be registered is simply served from disk.
+        let memory_cache = memory_cache.and_then(|memory_cache| {
+            match memory_cache.register(&file_path, options.precompressed) {
+                Ok(cache_key) => Some((Arc::clone(memory_cache), cache_key)),
+                Err(e) => {
+                    warn!("unable to cache {}: {}", file_path, e);
+                    None
+                }
+            }
+        });
 
-    return_value
+        StaticFileHandler {
+            file_path,
+            options,
+            memory_cache,
+        }
+    }
 }
 
-fn build_last_modified_header(
-    modified_result: &::std::io::Result<SystemTime>,
-) -> Option<HeaderValue> {
-    let mut return_value = None;
+enum BodySource {
+    File(tokio_fs::File),
+    Memory(Bytes),
+}
 
-    if let Ok(modified) = modified_result {
-        let utc_modified = crate::utils::system_time_to_utc(*modified);
+fn add_common_headers(
+    response_builder: &mut ::hyper::http::response::Builder,
+    options: &StaticFileOptions,
+    validators: &conditional::Validators,
+) {
+    response_builder.header(CACHE_CONTROL, options.cache_control_header_value.clone());
 
-        let last_modified_value = utc_modified.format("%a, %d %b %Y %H:%M:%S GMT").to_string();
+    if options.negotiates_encoding() {
+        response_builder.header(VARY, HeaderValue::from_static("Accept-Encoding"));
+    }
 
-        return_value = Some(HeaderValue::from_str(&last_modified_value).unwrap())
+    if let Some(last_modified_header_value) = validators.last_modified_header_value() {
+        response_builder.header(LAST_MODIFIED, last_modified_header_value);
     }
 
-    return_value
+    if let Some(etag_header_value) = validators.etag_header_value() {
+        response_builder.header(ETAG, etag_header_value);
+    }
 }
 
 fn build_file_response(
-    content_type_header_value_clone: HeaderValue,
-    cache_control_header_value_clone: HeaderValue,
-    last_modified_header_value_option: Option<HeaderValue>,
-    file: tokio_fs::File,
-    metadata: ::std::fs::Metadata,
-) -> Box<Future<Item = ::hyper::Response<::hyper::Body>, Error = ::std::io::Error> + Send> {
-    let buf: Vec<u8> = Vec::with_capacity(metadata.len() as usize);
-    Box::new(
-        ::tokio_io::io::read_to_end(file, buf).and_then(move |read_result| {
-            let mut response_builder = Response::builder();
+    req_headers: &HeaderMap,
+    content_type_header_value: HeaderValue,
+    options: &StaticFileOptions,
+    validators: &conditional::Validators,
+    body_source: BodySource,
+    complete_length: u64,
+) -> Response<Body> {
+    let mut range_request = if conditional::if_range_matches(req_headers, validators) {
+        range::parse_range_header(req_headers, complete_length)
+    } else {
+        range::RangeRequest::None
+    };
+
+    // Without a boundary the whole file is sent instead of a multipart body.
+    let boundary = match range_request {
+        range::RangeRequest::Satisfiable(ref ranges) if ranges.len() > 1 => {
+            match range::generate_boundary() {
+                Ok(boundary) => boundary,
+                Err(e) => {
+                    warn!("error generating multipart boundary: {}", e);
+                    range_request = range::RangeRequest::None;
+                    String::new()
+                }
+            }
+        }
+        _ => String::new(),
+    };
+
+    let mut response_builder = Response::builder();
+    response_builder.header(ACCEPT_RANGES, "bytes");
+    add_common_headers(&mut response_builder, options, validators);
+
+    let body = match range_request {
+        range::RangeRequest::None => {
             response_builder.status(StatusCode::OK);
-            response_builder.header(CONTENT_TYPE, content_type_header_value_clone);
-            response_builder.header(CACHE_CONTROL, cache_control_header_value_clone);
+            response_builder.header(CONTENT_TYPE, content_type_header_value);
+            response_builder.header(CONTENT_LENGTH, complete_length);
 
-            if let Some(last_modified_header_value) = last_modified_header_value_option {
-                response_builder.header(LAST_MODIFIED, last_modified_header_value);
+            match body_source {
+                BodySource::File(file) => {
+                    Body::wrap_stream(file_stream::FileStream::new(file, complete_length))
+                }
+                BodySource::Memory(data) => Body::from(data),
             }
+        }
+        range::RangeRequest::Unsatisfiable => {
+            response_builder.status(StatusCode::RANGE_NOT_SATISFIABLE);
+            response_builder.header(
+                CONTENT_RANGE,
+                range::unsatisfied_content_range(complete_length).as_str(),
+            );
 
-            Ok(response_builder.body(From::from(read_result.1)).unwrap())
-        }),
-    )
+            Body::empty()
+        }
+        range::RangeRequest::Satisfiable(ref ranges) if ranges.len() == 1 => {
+            let range = &ranges[0];
+
+            response_builder.status(StatusCode::PARTIAL_CONTENT);
+            response_builder.header(CONTENT_TYPE, content_type_header_value);
+            response_builder.header(CONTENT_RANGE, range.content_range(complete_length).as_str());
+            response_builder.header(CONTENT_LENGTH, range.length());
+
+            match body_source {
+                BodySource::File(file) => {
+                    Body::wrap_stream(file_stream::FileStream::new_range(file, range))
+                }
+                BodySource::Memory(data) => {
+                    let start = range.start() as usize;
+                    let end = start + range.length() as usize;
+                    Body::from(data.slice(start, end))
+                }
+            }
+        }
+        range::RangeRequest::Satisfiable(ranges) => {
+            let multipart_body = range::MultipartBody::new(
+                &ranges,
+                content_type_header_value
+                    .to_str()
+                    .unwrap_or("application/octet-stream"),
+                complete_length,
+                &boundary,
+            );
+
+            response_builder.status(StatusCode::PARTIAL_CONTENT);
+            response_builder.header(
+                CONTENT_TYPE,
+                format!("multipart/byteranges; boundary={}", boundary).as_str(),
+            );
+            response_builder.header(CONTENT_LENGTH, multipart_body.content_length());
+
+            match body_source {
+                BodySource::File(file) => Body::wrap_stream(multipart_body.into_stream(file)),
+                BodySource::Memory(data) => Body::from(multipart_body.into_bytes(&data)),
+            }
+        }
+    };
+
+    response_builder.body(body).unwrap()
+}
+
+// Encoded responses are always complete; a Range header is ignored since
+// ranges would apply to the encoded bytes rather than the file.
+fn encoded_response_builder(
+    content_type_header_value: HeaderValue,
+    options: &StaticFileOptions,
+    validators: &conditional::Validators,
+    encoding: crate::compression::Encoding,
+) -> ::hyper::http::response::Builder {
+    let mut response_builder = Response::builder();
+    response_builder.status(StatusCode::OK);
+    response_builder.header(CONTENT_TYPE, content_type_header_value);
+    response_builder.header(CONTENT_ENCODING, encoding.header_value());
+    add_common_headers(&mut response_builder, options, validators);
+    response_builder
+}
+
+fn build_encoded_response(
+    content_type_header_value: HeaderValue,
+    options: &StaticFileOptions,
+    validators: &conditional::Validators,
+    file_path: &Path,
+    metadata: &::std::fs::Metadata,
+    encoding_choice: encoding::EncodingChoice,
+) -> FileResponseFuture {
+    let mut response_builder = encoded_response_builder(
+        content_type_header_value,
+        options,
+        validators,
+        encoding_choice.encoding(),
+    );
+
+    match (encoding_choice.source(), &options.compressed_file_cache) {
+        (encoding::EncodedSource::Sibling(sibling_path), _) => Box::new(
+            encoding::open_sibling(sibling_path.clone())
+                .and_then(|file| file.metadata())
+                .map(move |(file, sibling_metadata)| {
+                    response_builder.header(CONTENT_LENGTH, sibling_metadata.len());
+                    response_builder
+                        .body(Body::wrap_stream(file_stream::FileStream::new(
+                            file,
+                            sibling_metadata.len(),
+                        )))
+                        .unwrap()
+                }),
+        ),
+        (encoding::EncodedSource::OnTheFly, Some(compressed_file_cache)) => Box::new(
+            Arc::clone(compressed_file_cache)
+                .get_or_compress(
+                    file_path.to_path_buf(),
+                    encoding_choice.encoding(),
+                    metadata,
+                )
+                .map(move |data| {
+                    response_builder.header(CONTENT_LENGTH, data.len() as u64);
+                    response_builder.body(Body::from(data)).unwrap()
+                }),
+        ),
+        (encoding::EncodedSource::OnTheFly, None) => Box::new(future::err(io::Error::other(
+            "on the fly compression without compressed_file_cache",
+        ))),
+    }
 }
 
+// A 304 carries only the headers a cache needs to refresh its stored
+// response; Last-Modified is left out when an ETag is available.
 fn build_not_modified(
-    content_type_header_value_clone: HeaderValue,
-    cache_control_header_value_clone: HeaderValue,
-    last_modified_header_value_option: Option<HeaderValue>,
-) -> Box<Future<Item = ::hyper::Response<::hyper::Body>, Error = ::std::io::Error> + Send> {
+    options: &StaticFileOptions,
+    validators: &conditional::Validators,
+) -> Response<Body> {
     let mut response_builder = Response::builder();
     response_builder.status(StatusCode::NOT_MODIFIED);
-    response_builder.header(CONTENT_TYPE, content_type_header_value_clone);
-    response_builder.header(CACHE_CONTROL, cache_control_header_value_clone);
+    response_builder.header(CACHE_CONTROL, options.cache_control_header_value.clone());
 
-    if let Some(last_modified_header_value) = last_modified_header_value_option {
-        response_builder.header(LAST_MODIFIED, last_modified_header_value);
+    if options.negotiates_encoding() {
+        response_builder.header(VARY, HeaderValue::from_static("Accept-Encoding"));
+    }
+
+    match validators.etag_header_value() {
+        Some(etag_header_value) => {
+            response_builder.header(ETAG, etag_header_value);
+        }
+        None => {
+            if let Some(last_modified_header_value) = validators.last_modified_header_value() {
+                response_builder.header(LAST_MODIFIED, last_modified_header_value);
+            }
+        }
     }
 
-    Box::new(future::ok(
-        response_builder.body(::hyper::Body::empty()).unwrap(),
-    ))
+    response_builder.body(Body::empty()).unwrap()
 }
 
-impl crate::server::RequestHandler for StaticFileHandler {
-    fn handle(&self, req_context: &crate::server::RequestContext) -> crate::server::ResponseFuture {
-        let file_path_clone = self.file_path.clone();
-        let content_type_header_value_clone = self.content_type_header_value.clone();
-        let cache_control_header_value_clone = self.cache_control_header_value.clone();
-
-        let if_modified_since_time_option = get_if_modified_since_time(&req_context);
-
-        Box::new(
-            ::tokio_fs::file::File::open(file_path_clone)
-                .and_then(move |file| {
-                    file.metadata().and_then(move |metadata_result| {
-                        let (file, metadata) = metadata_result;
-                        let file_modified = metadata.modified();
-
-                        let last_modified_header_value_option =
-                            build_last_modified_header(&file_modified);
-
-                        if file_modified_since_header(
-                            &if_modified_since_time_option,
-                            &file_modified,
-                        ) {
-                            build_file_response(
-                                content_type_header_value_clone,
-                                cache_control_header_value_clone,
-                                last_modified_header_value_option,
-                                file,
-                                metadata,
-                            )
-                        } else {
-                            build_not_modified(
-                                content_type_header_value_clone,
-                                cache_control_header_value_clone,
-                                last_modified_header_value_option,
-                            )
+pub fn serve_file<P>(
+    request_info: FileRequestInfo,
+    file_path: P,
+    options: StaticFileOptions,
+) -> crate::server::ResponseFuture
+where
+    P: AsRef<Path> + Send + 'static,
+{
+    let file_path = file_path.as_ref().to_path_buf();
+
+    let content_type_header_value = options.content_type_header_value(&file_path);
+
+    let acceptable_encodings = options.acceptable_encodings(&request_info.headers);
+
+    let compress = options.compresses(&content_type_header_value);
+
+    Box::new(
+        ::tokio_fs::file::File::open(file_path.clone())
+            .and_then(|file| file.metadata())
+            .and_then(move |(file, metadata)| {
+                encoding::choose_encoding(
+                    file_path.clone(),
+                    &metadata,
+                    acceptable_encodings,
+                    options.precompressed,
+                    compress,
+                )
+                .and_then(move |encoding_choice| -> FileResponseFuture {
+                    let validators =
+                        conditional::Validators::from_metadata(&metadata, options.etag_type)
+                            .with_encoding(encoding_choice.as_ref().map(|c| c.encoding()));
+
+                    match conditional::evaluate_preconditions(
+                        &request_info.method,
+                        &request_info.headers,
+                        &validators,
+                    ) {
+                        conditional::Precondition::Proceed => match encoding_choice {
+                            None => Box::new(future::ok(build_file_response(
+                                &request_info.headers,
+                                content_type_header_value,
+                                &options,
+                                &validators,
+                                BodySource::File(file),
+                                metadata.len(),
+                            ))),
+                            Some(encoding_choice) => build_encoded_response(
+                                content_type_header_value,
+                                &options,
+                                &validators,
+                                &file_path,
+                                &metadata,
+                                encoding_choice,
+                            ),
+                        },
+                        conditional::Precondition::NotModified => {
+                            Box::new(future::ok(build_not_modified(&options, &validators)))
                         }
-                    })
+                        conditional::Precondition::Failed => Box::new(future::ok(
+                            crate::server::build_response_status(StatusCode::PRECONDITION_FAILED),
+                        )),
+                    }
                 })
-                .or_else(|_| Ok(crate::server::build_response_status(StatusCode::NOT_FOUND))),
-        )
+            })
+            .or_else(|_| Ok(crate::server::build_response_status(StatusCode::NOT_FOUND))),
+    )
+}
+
+// Serves a file held in memory, either preloaded by StaticFileMemoryCache or
+// compiled into the binary.  Encoded copies come from pre-compressed siblings
+// loaded with the file, or are compressed once and kept alongside it.
+pub fn serve_cached_file(
+    request_info: FileRequestInfo,
+    cached_file: Arc<CachedFile>,
+    file_path: &Path,
+    options: &StaticFileOptions,
+) -> crate::server::ResponseFuture {
+    let content_type_header_value = options.content_type_header_value(file_path);
+
+    let acceptable_encodings = options.acceptable_encodings(&request_info.headers);
+
+    let compress = options.compresses(&content_type_header_value);
+
+    let encoding = acceptable_encodings
+        .iter()
+        .cloned()
+        .find(|&encoding| cached_file.encoded(encoding).is_some())
+        .or_else(|| {
+            if compress {
+                acceptable_encodings.first().cloned()
+            } else {
+                None
+            }
+        });
+
+    let validators = conditional::Validators::new(
+        cached_file.last_modified(),
+        cached_file.opaque_tag(),
+        options.etag_type,
+    )
+    .with_encoding(encoding);
+
+    let response_future: FileResponseFuture = match conditional::evaluate_preconditions(
+        &request_info.method,
+        &request_info.headers,
+        &validators,
+    ) {
+        conditional::Precondition::Proceed => match encoding {
+            None => Box::new(future::ok(build_file_response(
+                &request_info.headers,
+                content_type_header_value,
+                options,
+                &validators,
+                BodySource::Memory(cached_file.data().clone()),
+                cached_file.data().len() as u64,
+            ))),
+            Some(encoding) => {
+                let mut response_builder = encoded_response_builder(
+                    content_type_header_value,
+                    options,
+                    &validators,
+                    encoding,
+                );
+
+                let encoded_data = cached_file.encoded(encoding);
+
+                let encoded_data_future: Box<Future<Item = Bytes, Error = io::Error> + Send> =
+                    match encoded_data {
+                        Some(data) => Box::new(future::ok(data)),
+                        None => Box::new(crate::utils::blocking_io(move || {
+                            let data = Bytes::from(crate::compression::compress(
+                                encoding,
+                                None,
+                                cached_file.data(),
+                            )?);
+                            cached_file.insert_encoded(encoding, data.clone());
+                            Ok(data)
+                        })),
+                    };
+
+                Box::new(encoded_data_future.map(move |data| {
+                    response_builder.header(CONTENT_LENGTH, data.len() as u64);
+                    response_builder.body(Body::from(data)).unwrap()
+                }))
+            }
+        },
+        conditional::Precondition::NotModified => {
+            Box::new(future::ok(build_not_modified(options, &validators)))
+        }
+        conditional::Precondition::Failed => Box::new(future::ok(
+            crate::server::build_response_status(StatusCode::PRECONDITION_FAILED),
+        )),
+    };
+
+    Box::new(response_future.or_else(|_| {
+        Ok(crate::server::build_response_status(
+            StatusCode::INTERNAL_SERVER_ERROR,
+        ))
+    }))
+}
+
+impl crate::server::RequestHandler for StaticFileHandler {
+    fn handle(&self, req_context: &crate::server::RequestContext) -> crate::server::ResponseFuture {
+        let request_info = FileRequestInfo::new(req_context.req());
+
+        if let Some((memory_cache, cache_key)) = &self.memory_cache {
+            if let Some(cached_file) = memory_cache.get(cache_key) {
+                return serve_cached_file(request_info, cached_file, cache_key, &self.options);
+            }
+        }
+
+        serve_file(request_info, self.file_path.clone(), self.options.clone())
     }
 }
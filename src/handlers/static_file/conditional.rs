@@ -0,0 +1,263 @@
+use chrono::prelude::{DateTime, Utc};
+
+use hyper::header::{
+    HeaderMap, HeaderName, HeaderValue, IF_MATCH, IF_MODIFIED_SINCE, IF_NONE_MATCH, IF_RANGE,
+    IF_UNMODIFIED_SINCE,
+};
+use hyper::Method;
+
+use std::os::unix::fs::MetadataExt;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub struct Validators {
+    last_modified: Option<SystemTime>,
+    etag: Option<String>,
+}
+
+impl Validators {
+    pub fn new(
+        last_modified: Option<SystemTime>,
+        opaque_tag: &str,
+        etag_type: crate::config::ETagType,
+    ) -> Self {
+        Validators {
+            last_modified,
+            etag: build_etag(etag_type, opaque_tag),
+        }
+    }
+
+    pub fn from_metadata(
+        metadata: &::std::fs::Metadata,
+        etag_type: crate::config::ETagType,
+    ) -> Self {
+        Validators::new(
+            metadata.modified().ok(),
+            &metadata_opaque_tag(metadata),
+            etag_type,
+        )
+    }
+
+    pub fn last_modified_header_value(&self) -> Option<HeaderValue> {
+        self.last_modified.map(|last_modified| {
+            let utc_modified = crate::utils::system_time_to_utc(last_modified);
+
+            let last_modified_value = utc_modified.format("%a, %d %b %Y %H:%M:%S GMT").to_string();
+
+            HeaderValue::from_str(&last_modified_value).unwrap()
+        })
+    }
+
+    pub fn etag_header_value(&self) -> Option<HeaderValue> {
+        self.etag
+            .as_ref()
+            .and_then(|etag| HeaderValue::from_str(etag).ok())
+    }
+
+    // Each content coding is a different representation and needs its own
+    // entity tag: "abc" becomes "abc-gz", W/"abc" becomes W/"abc-gz".
+    pub fn with_encoding(mut self, encoding: Option<crate::compression::Encoding>) -> Self {
+        if let (Some(encoding), Some(etag)) = (encoding, &self.etag) {
+            let mut encoded_etag = etag.trim_end_matches('"').to_string();
+            encoded_etag.push('-');
+            encoded_etag.push_str(encoding.file_extension());
+            encoded_etag.push('"');
+            self.etag = Some(encoded_etag);
+        }
+        self
+    }
+
+    fn last_modified_timestamp(&self) -> Option<i64> {
+        self.last_modified
+            .map(|last_modified| crate::utils::system_time_to_utc(last_modified).timestamp())
+    }
+}
+
+pub fn metadata_opaque_tag(metadata: &::std::fs::Metadata) -> String {
+    let mtime_nanos = match metadata.modified().map(|m| m.duration_since(UNIX_EPOCH)) {
+        Ok(Ok(duration)) => {
+            u128::from(duration.as_secs()) * 1_000_000_000 + u128::from(duration.subsec_nanos())
+        }
+        _ => 0,
+    };
+
+    format!(
+        "{:x}-{:x}-{:x}",
+        metadata.ino(),
+        metadata.len(),
+        mtime_nanos
+    )
+}
+
+fn build_etag(etag_type: crate::config::ETagType, opaque_tag: &str) -> Option<String> {
+    match etag_type {
+        crate::config::ETagType::Strong => Some(format!("\"{}\"", opaque_tag)),
+        crate::config::ETagType::Weak => Some(format!("W/\"{}\"", opaque_tag)),
+        crate::config::ETagType::Disabled => None,
+    }
+}
+
+pub enum Precondition {
+    Proceed,
+    NotModified,
+    Failed,
+}
+
+fn get_header_str<'a>(req_headers: &'a HeaderMap, header_name: &HeaderName) -> Option<&'a str> {
+    req_headers.get(header_name).and_then(|v| v.to_str().ok())
+}
+
+fn get_header_timestamp(req_headers: &HeaderMap, header_name: &HeaderName) -> Option<i64> {
+    get_header_str(req_headers, header_name)
+        .and_then(|str_value| DateTime::parse_from_rfc2822(str_value).ok())
+        .map(|dt| dt.with_timezone(&Utc).timestamp())
+}
+
+fn opaque_tag(etag: &str) -> &str {
+    etag.trim_start_matches("W/")
+}
+
+fn is_weak(etag: &str) -> bool {
+    etag.starts_with("W/")
+}
+
+// Matches an If-Match / If-None-Match list against our ETag.  Strong
+// comparison requires both tags to be strong and identical, weak comparison
+// only looks at the opaque tags.  "*" matches any existing representation,
+// so it applies even when ETags are disabled.
+fn etag_list_matches(header_str: &str, etag: Option<&String>, strong_comparison: bool) -> bool {
+    header_str
+        .split(',')
+        .map(|t| t.trim())
+        .any(|candidate| match etag {
+            _ if candidate == "*" => true,
+            None => false,
+            Some(etag) if strong_comparison => {
+                !is_weak(candidate) && !is_weak(etag) && candidate == etag
+            }
+            Some(etag) => opaque_tag(candidate) == opaque_tag(etag),
+        })
+}
+
+// Evaluates preconditions in the order given by RFC 7232 section 6.
+pub fn evaluate_preconditions(
+    method: &Method,
+    req_headers: &HeaderMap,
+    validators: &Validators,
+) -> Precondition {
+    if let Some(if_match) = get_header_str(req_headers, &IF_MATCH) {
+        if !etag_list_matches(if_match, validators.etag.as_ref(), true) {
+            return Precondition::Failed;
+        }
+    } else if let Some(if_unmodified_since) =
+        get_header_timestamp(req_headers, &IF_UNMODIFIED_SINCE)
+    {
+        match validators.last_modified_timestamp() {
+            Some(last_modified) if last_modified <= if_unmodified_since => {}
+            _ => return Precondition::Failed,
+        }
+    }
+
+    let is_get_or_head = *method == Method::GET || *method == Method::HEAD;
+
+    if let Some(if_none_match) = get_header_str(req_headers, &IF_NONE_MATCH) {
+        if etag_list_matches(if_none_match, validators.etag.as_ref(), false) {
+            return if is_get_or_head {
+                Precondition::NotModified
+            } else {
+                Precondition::Failed
+            };
+        }
+    } else if is_get_or_head {
+        if let (Some(if_modified_since), Some(last_modified)) = (
+            get_header_timestamp(req_headers, &IF_MODIFIED_SINCE),
+            validators.last_modified_timestamp(),
+        ) {
+            if last_modified <= if_modified_since {
+                return Precondition::NotModified;
+            }
+        }
+    }
+
+    Precondition::Proceed
+}
+
+// A Range request is only honoured when If-Range is absent or still matches
+// the current representation.
+pub fn if_range_matches(req_headers: &HeaderMap, validators: &Validators) -> bool {
+    let if_range = match get_header_str(req_headers, &IF_RANGE) {
+        Some(if_range) => if_range.trim(),
+        None => return req_headers.get(IF_RANGE).is_none(),
+    };
+
+    if if_range.starts_with('"') || is_weak(if_range) {
+        match &validators.etag {
+            Some(etag) => !is_weak(if_range) && !is_weak(etag) && if_range == etag,
+            None => false,
+        }
+    } else {
+        match (
+            get_header_timestamp(req_headers, &IF_RANGE),
+            validators.last_modified_timestamp(),
+        ) {
+            (Some(if_range_time), Some(last_modified)) => if_range_time == last_modified,
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::config::ETagType;
+
+    fn evaluate(
+        method: Method,
+        header_name: HeaderName,
+        header_value: &str,
+        etag_type: ETagType,
+    ) -> Precondition {
+        let mut req_headers = HeaderMap::new();
+        req_headers.insert(header_name, HeaderValue::from_str(header_value).unwrap());
+
+        let validators = Validators::new(Some(UNIX_EPOCH), "abc", etag_type);
+
+        evaluate_preconditions(&method, &req_headers, &validators)
+    }
+
+    #[test]
+    fn wildcard_matches_without_etags() {
+        match evaluate(Method::GET, IF_MATCH, "*", ETagType::Disabled) {
+            Precondition::Proceed => {}
+            _ => panic!("If-Match: * should proceed"),
+        }
+        match evaluate(Method::GET, IF_NONE_MATCH, "*", ETagType::Disabled) {
+            Precondition::NotModified => {}
+            _ => panic!("If-None-Match: * should be not modified for GET"),
+        }
+        match evaluate(Method::PUT, IF_NONE_MATCH, "*", ETagType::Disabled) {
+            Precondition::Failed => {}
+            _ => panic!("If-None-Match: * should fail for PUT"),
+        }
+        match evaluate(Method::GET, IF_MATCH, "\"abc\"", ETagType::Disabled) {
+            Precondition::Failed => {}
+            _ => panic!("If-Match with a tag should fail without etags"),
+        }
+    }
+
+    #[test]
+    fn strong_and_weak_comparison() {
+        match evaluate(Method::GET, IF_MATCH, "\"abc\"", ETagType::Strong) {
+            Precondition::Proceed => {}
+            _ => panic!("If-Match should match the strong etag"),
+        }
+        match evaluate(Method::GET, IF_MATCH, "W/\"abc\"", ETagType::Strong) {
+            Precondition::Failed => {}
+            _ => panic!("If-Match uses strong comparison"),
+        }
+        match evaluate(Method::GET, IF_NONE_MATCH, "W/\"abc\"", ETagType::Strong) {
+            Precondition::NotModified => {}
+            _ => panic!("If-None-Match uses weak comparison"),
+        }
+    }
+}
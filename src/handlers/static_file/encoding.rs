@@ -0,0 +1,318 @@
+use bytes::Bytes;
+
+use futures::{future, Future};
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::io::Read;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use crate::compression::Encoding;
+
+// Larger files are always sent uncompressed unless a pre-compressed sibling
+// exists, so on-the-fly compression never holds more than this per entry.
+const MAX_COMPRESS_FILE_SIZE: u64 = 8 * 1024 * 1024;
+const MAX_CACHE_ENTRIES: usize = 256;
+
+pub enum EncodedSource {
+    Sibling(PathBuf),
+    OnTheFly,
+}
+
+pub struct EncodingChoice {
+    encoding: Encoding,
+    source: EncodedSource,
+}
+
+impl EncodingChoice {
+    pub fn encoding(&self) -> Encoding {
+        self.encoding
+    }
+
+    pub fn source(&self) -> &EncodedSource {
+        &self.source
+    }
+}
+
+fn sibling_path(file_path: &Path, encoding: Encoding) -> PathBuf {
+    let mut sibling_path = file_path.as_os_str().to_owned();
+    sibling_path.push(".");
+    sibling_path.push(encoding.file_extension());
+    PathBuf::from(sibling_path)
+}
+
+// A sibling older than the original is stale and ignored.  Symlinks are
+// ignored as well: only file_path itself has been checked against a static
+// directory's root, and a link could point anywhere.
+pub fn find_sibling(
+    file_path: &Path,
+    modified: Option<SystemTime>,
+    acceptable_encodings: &[Encoding],
+) -> Option<(Encoding, PathBuf)> {
+    for &encoding in acceptable_encodings {
+        let sibling_path = sibling_path(file_path, encoding);
+
+        if let Ok(sibling_metadata) = fs::symlink_metadata(&sibling_path) {
+            let fresh = match (modified, sibling_metadata.modified().ok()) {
+                (Some(modified), Some(sibling_modified)) => sibling_modified >= modified,
+                _ => false,
+            };
+
+            if sibling_metadata.is_file() && fresh {
+                return Some((encoding, sibling_path));
+            }
+        }
+    }
+
+    None
+}
+
+// O_NOFOLLOW so a sibling replaced by a symlink after find_sibling still
+// isn't followed.
+fn sibling_open_options() -> fs::OpenOptions {
+    let mut open_options = fs::OpenOptions::new();
+    open_options.read(true).custom_flags(::libc::O_NOFOLLOW);
+    open_options
+}
+
+pub fn open_sibling(sibling_path: PathBuf) -> ::tokio_fs::file::OpenFuture<PathBuf> {
+    ::tokio_fs::file::OpenOptions::from(sibling_open_options()).open(sibling_path)
+}
+
+pub fn read_sibling(sibling_path: &Path) -> io::Result<Vec<u8>> {
+    let mut data = Vec::new();
+    sibling_open_options()
+        .open(sibling_path)?
+        .read_to_end(&mut data)?;
+    Ok(data)
+}
+
+pub fn choose_encoding(
+    file_path: PathBuf,
+    metadata: &fs::Metadata,
+    acceptable_encodings: Vec<Encoding>,
+    precompressed: bool,
+    compress: bool,
+) -> Box<Future<Item = Option<EncodingChoice>, Error = io::Error> + Send> {
+    let compress = compress && metadata.len() <= MAX_COMPRESS_FILE_SIZE;
+
+    if acceptable_encodings.is_empty() || !(precompressed || compress) {
+        return Box::new(future::ok(None));
+    }
+
+    let modified = metadata.modified().ok();
+
+    Box::new(crate::utils::blocking_io(move || {
+        if precompressed {
+            if let Some((encoding, sibling_path)) =
+                find_sibling(&file_path, modified, &acceptable_encodings)
+            {
+                return Ok(Some(EncodingChoice {
+                    encoding,
+                    source: EncodedSource::Sibling(sibling_path),
+                }));
+            }
+        }
+
+        if compress {
+            return Ok(Some(EncodingChoice {
+                encoding: acceptable_encodings[0],
+                source: EncodedSource::OnTheFly,
+            }));
+        }
+
+        Ok(None)
+    }))
+}
+
+struct CompressedFile {
+    modified: Option<SystemTime>,
+    length: u64,
+    data: Bytes,
+    last_used: u64,
+}
+
+struct CompressedFileEntries {
+    entries: HashMap<(PathBuf, Encoding), CompressedFile>,
+    // incremented on every access to order entries by recency
+    clock: u64,
+}
+
+// Compressed copies of files keyed by path and encoding.  Entries are
+// replaced whenever the file's modification time or size changes, and the
+// least recently used one is evicted when the cache is full.
+pub struct CompressedFileCache {
+    entries: Mutex<CompressedFileEntries>,
+}
+
+impl CompressedFileCache {
+    pub fn new() -> Self {
+        CompressedFileCache {
+            entries: Mutex::new(CompressedFileEntries {
+                entries: HashMap::new(),
+                clock: 0,
+            }),
+        }
+    }
+
+    fn get(
+        &self,
+        file_path: &Path,
+        encoding: Encoding,
+        modified: Option<SystemTime>,
+        length: u64,
+    ) -> Option<Bytes> {
+        let mut entries = self.entries.lock().unwrap();
+        entries.clock += 1;
+        let clock = entries.clock;
+
+        match entries
+            .entries
+            .get_mut(&(file_path.to_path_buf(), encoding))
+        {
+            Some(entry) if entry.modified == modified && entry.length == length => {
+                entry.last_used = clock;
+                Some(entry.data.clone())
+            }
+            _ => None,
+        }
+    }
+
+    fn insert(
+        &self,
+        file_path: PathBuf,
+        encoding: Encoding,
+        modified: Option<SystemTime>,
+        length: u64,
+        data: Bytes,
+    ) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.clock += 1;
+        let clock = entries.clock;
+
+        let key = (file_path, encoding);
+
+        if entries.entries.len() >= MAX_CACHE_ENTRIES && !entries.entries.contains_key(&key) {
+            let least_recently_used = entries
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(key, _)| key.clone());
+
+            if let Some(least_recently_used) = least_recently_used {
+                entries.entries.remove(&least_recently_used);
+            }
+        }
+
+        entries.entries.insert(
+            key,
+            CompressedFile {
+                modified,
+                length,
+                data,
+                last_used: clock,
+            },
+        );
+    }
+
+    pub fn get_or_compress(
+        self: Arc<Self>,
+        file_path: PathBuf,
+        encoding: Encoding,
+        metadata: &fs::Metadata,
+    ) -> Box<Future<Item = Bytes, Error = io::Error> + Send> {
+        let modified = metadata.modified().ok();
+        let length = metadata.len();
+
+        if let Some(data) = self.get(&file_path, encoding, modified, length) {
+            return Box::new(future::ok(data));
+        }
+
+        Box::new(crate::utils::blocking_io(move || {
+            let data = Bytes::from(crate::compression::compress(
+                encoding,
+                None,
+                &fs::read(&file_path)?,
+            )?);
+
+            self.insert(file_path, encoding, modified, length, data.clone());
+
+            Ok(data)
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::os::unix::fs::symlink;
+
+    #[test]
+    fn find_sibling_ignores_symlinks() {
+        let dir =
+            ::std::env::temp_dir().join(format!("rust_hyper_encoding_{}", ::std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        let file_path = dir.join("index.html");
+        fs::write(&file_path, "index").unwrap();
+        fs::write(dir.join("secret"), "secret").unwrap();
+        symlink(dir.join("secret"), dir.join("index.html.gz")).unwrap();
+        fs::write(dir.join("index.html.br"), "br").unwrap();
+
+        let modified = fs::metadata(&file_path).unwrap().modified().ok();
+
+        assert!(find_sibling(&file_path, modified, &[Encoding::Gzip]).is_none());
+        assert!(read_sibling(&dir.join("index.html.gz")).is_err());
+
+        match find_sibling(&file_path, modified, &[Encoding::Gzip, Encoding::Brotli]) {
+            Some((Encoding::Brotli, sibling_path)) => {
+                assert_eq!(sibling_path, dir.join("index.html.br"))
+            }
+            _ => panic!("expected the brotli sibling"),
+        }
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn compressed_file_cache_evicts_least_recently_used() {
+        let cache = CompressedFileCache::new();
+        let path = |i: usize| PathBuf::from(format!("/file{}", i));
+
+        for i in 0..MAX_CACHE_ENTRIES {
+            cache.insert(path(i), Encoding::Gzip, None, 1, Bytes::from(vec![i as u8]));
+        }
+
+        // file0 is used again, so file1 is now the least recently used
+        assert!(cache.get(&path(0), Encoding::Gzip, None, 1).is_some());
+
+        cache.insert(
+            path(MAX_CACHE_ENTRIES),
+            Encoding::Gzip,
+            None,
+            1,
+            Bytes::from_static(b"new"),
+        );
+
+        assert_eq!(
+            cache.entries.lock().unwrap().entries.len(),
+            MAX_CACHE_ENTRIES
+        );
+        assert!(cache.get(&path(0), Encoding::Gzip, None, 1).is_some());
+        assert!(cache.get(&path(1), Encoding::Gzip, None, 1).is_none());
+        assert!(cache.get(&path(2), Encoding::Gzip, None, 1).is_some());
+        assert!(cache
+            .get(&path(MAX_CACHE_ENTRIES), Encoding::Gzip, None, 1)
+            .is_some());
+
+        // a changed file is a miss
+        assert!(cache.get(&path(2), Encoding::Gzip, None, 2).is_none());
+        assert!(cache.get(&path(2), Encoding::Brotli, None, 1).is_none());
+    }
+}
@@ -0,0 +1,80 @@
+use futures::{try_ready, Async, Poll, Stream};
+
+use hyper::Chunk;
+
+use tokio_io::AsyncRead;
+
+use std::cmp;
+use std::io;
+use std::io::SeekFrom;
+
+const CHUNK_SIZE: u64 = 64 * 1024;
+
+// Reads at most `remaining` bytes from `file` in CHUNK_SIZE pieces.  hyper only
+// polls the body when the connection can take more data, so at most one chunk
+// per response is held in memory regardless of file size.
+pub struct FileStream {
+    file: ::tokio_fs::File,
+    seek_position: Option<u64>,
+    remaining: u64,
+    buffer: Option<Vec<u8>>,
+}
+
+impl FileStream {
+    pub fn new(file: ::tokio_fs::File, length: u64) -> Self {
+        FileStream {
+            file,
+            seek_position: None,
+            remaining: length,
+            buffer: None,
+        }
+    }
+
+    pub fn new_range(file: ::tokio_fs::File, range: &super::range::ByteRange) -> Self {
+        let mut file_stream = FileStream::new(file, 0);
+        file_stream.set_range(range);
+        file_stream
+    }
+
+    pub fn set_range(&mut self, range: &super::range::ByteRange) {
+        self.seek_position = Some(range.start());
+        self.remaining = range.length();
+    }
+}
+
+impl Stream for FileStream {
+    type Item = Chunk;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<Chunk>, io::Error> {
+        if let Some(seek_position) = self.seek_position {
+            try_ready!(self.file.poll_seek(SeekFrom::Start(seek_position)));
+            self.seek_position = None;
+        }
+
+        if self.remaining == 0 {
+            return Ok(Async::Ready(None));
+        }
+
+        let read_size = cmp::min(CHUNK_SIZE, self.remaining) as usize;
+
+        let mut buffer = self.buffer.take().unwrap_or_else(|| vec![0; read_size]);
+
+        match self.file.poll_read(&mut buffer)? {
+            Async::NotReady => {
+                self.buffer = Some(buffer);
+                Ok(Async::NotReady)
+            }
+            Async::Ready(0) => {
+                // file was truncated after we read its metadata
+                self.remaining = 0;
+                Ok(Async::Ready(None))
+            }
+            Async::Ready(bytes_read) => {
+                buffer.truncate(bytes_read);
+                self.remaining -= bytes_read as u64;
+                Ok(Async::Ready(Some(Chunk::from(buffer))))
+            }
+        }
+    }
+}
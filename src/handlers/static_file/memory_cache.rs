@@ -0,0 +1,407 @@
+use bytes::Bytes;
+
+use log::{info, warn};
+
+use notify::{DebouncedEvent, RecursiveMode, Watcher};
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeSet, HashMap};
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{mpsc, Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime};
+
+use crate::compression::Encoding;
+
+// Files larger than this are served from disk even when caching is enabled.
+const MAX_CACHED_FILE_SIZE: u64 = 1024 * 1024;
+const WATCHER_DELAY: Duration = Duration::from_millis(500);
+
+pub struct CachedFile {
+    data: Bytes,
+    last_modified: Option<SystemTime>,
+    opaque_tag: String,
+    encoded: Mutex<HashMap<Encoding, Bytes>>,
+}
+
+impl CachedFile {
+    // Data compiled into the binary has no modification time, so its entity
+    // tag is derived from the content instead.
+    pub fn from_static(data: &'static [u8]) -> Self {
+        let mut hasher = DefaultHasher::new();
+        data.hash(&mut hasher);
+
+        CachedFile {
+            data: Bytes::from_static(data),
+            last_modified: None,
+            opaque_tag: format!("{:x}-{:x}", data.len(), hasher.finish()),
+            encoded: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn data(&self) -> &Bytes {
+        &self.data
+    }
+
+    pub fn last_modified(&self) -> Option<SystemTime> {
+        self.last_modified
+    }
+
+    pub fn opaque_tag(&self) -> &str {
+        &self.opaque_tag
+    }
+
+    pub fn encoded(&self, encoding: Encoding) -> Option<Bytes> {
+        self.encoded.lock().unwrap().get(&encoding).cloned()
+    }
+
+    pub fn insert_encoded(&self, encoding: Encoding, data: Bytes) {
+        self.encoded.lock().unwrap().insert(encoding, data);
+    }
+}
+
+fn load_cached_file(path: &Path, precompressed: bool) -> io::Result<CachedFile> {
+    let metadata = fs::metadata(path)?;
+
+    if !metadata.is_file() {
+        return Err(io::Error::other("not a regular file"));
+    }
+
+    if metadata.len() > MAX_CACHED_FILE_SIZE {
+        return Err(io::Error::other(format!(
+            "larger than {} bytes",
+            MAX_CACHED_FILE_SIZE
+        )));
+    }
+
+    let data = Bytes::from(fs::read(path)?);
+
+    let mut encoded = HashMap::new();
+    if precompressed {
+        let modified = metadata.modified().ok();
+        for &encoding in &[Encoding::Brotli, Encoding::Gzip] {
+            if let Some((encoding, sibling_path)) =
+                super::encoding::find_sibling(path, modified, &[encoding])
+            {
+                encoded.insert(
+                    encoding,
+                    Bytes::from(super::encoding::read_sibling(&sibling_path)?),
+                );
+            }
+        }
+    }
+
+    Ok(CachedFile {
+        data,
+        last_modified: metadata.modified().ok(),
+        opaque_tag: super::conditional::metadata_opaque_tag(&metadata),
+        encoded: Mutex::new(encoded),
+    })
+}
+
+// Watched directories use canonical paths, so cache keys do as well to line
+// up with the paths reported in watcher events.
+fn cache_key(path: &Path) -> io::Result<PathBuf> {
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| io::Error::other("path has no file name"))?;
+
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+
+    Ok(fs::canonicalize(parent)?.join(file_name))
+}
+
+// Preloaded copies of static files.  Entries are reloaded when the watcher
+// thread sees the underlying file change and dropped when it disappears.
+pub struct StaticFileMemoryCache {
+    registered: RwLock<HashMap<PathBuf, bool>>,
+    entries: RwLock<HashMap<PathBuf, Arc<CachedFile>>>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl StaticFileMemoryCache {
+    pub fn new() -> Self {
+        StaticFileMemoryCache {
+            registered: RwLock::new(HashMap::new()),
+            entries: RwLock::new(HashMap::new()),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    pub fn register(&self, path: &str, precompressed: bool) -> io::Result<PathBuf> {
+        let key = cache_key(Path::new(path))?;
+
+        self.registered
+            .write()
+            .unwrap()
+            .insert(key.clone(), precompressed);
+
+        self.reload(&key);
+
+        Ok(key)
+    }
+
+    fn reload(&self, key: &Path) {
+        let precompressed = match self.registered.read().unwrap().get(key) {
+            Some(&precompressed) => precompressed,
+            None => return,
+        };
+
+        match load_cached_file(key, precompressed) {
+            Ok(cached_file) => {
+                info!("cached {:?} ({} bytes)", key, cached_file.data.len());
+                self.entries
+                    .write()
+                    .unwrap()
+                    .insert(key.to_path_buf(), Arc::new(cached_file));
+            }
+            Err(e) => {
+                warn!("not caching {:?}: {}", key, e);
+                self.entries.write().unwrap().remove(key);
+            }
+        }
+    }
+
+    // Sibling .br/.gz files invalidate the file they belong to.
+    fn reload_for_event_path(&self, event_path: &Path) {
+        let mut key = event_path.to_path_buf();
+
+        if let Some(extension) = event_path.extension() {
+            if extension == "br" || extension == "gz" {
+                key = event_path.with_extension("");
+            }
+        }
+
+        self.reload(&key);
+        if key != event_path {
+            self.reload(event_path);
+        }
+    }
+
+    fn reload_all(&self) {
+        let keys: Vec<PathBuf> = self.registered.read().unwrap().keys().cloned().collect();
+
+        for key in keys {
+            self.reload(&key);
+        }
+    }
+
+    pub fn get(&self, key: &Path) -> Option<Arc<CachedFile>> {
+        let result = self.entries.read().unwrap().get(key).cloned();
+
+        match result {
+            Some(_) => self.hits.fetch_add(1, Ordering::Relaxed),
+            None => self.misses.fetch_add(1, Ordering::Relaxed),
+        };
+
+        result
+    }
+
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    pub fn misses(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.registered.read().unwrap().is_empty()
+    }
+
+    // (path, cached size in bytes or None when served from disk)
+    pub fn entry_sizes(&self) -> Vec<(PathBuf, Option<usize>)> {
+        let entries = self.entries.read().unwrap();
+
+        let mut keys: Vec<PathBuf> = self.registered.read().unwrap().keys().cloned().collect();
+        keys.sort();
+
+        keys.into_iter()
+            .map(|key| {
+                let size = entries.get(&key).map(|e| e.data.len());
+                (key, size)
+            })
+            .collect()
+    }
+
+    fn run_watcher(self: Arc<Self>, receiver: mpsc::Receiver<DebouncedEvent>) {
+        loop {
+            match receiver.recv() {
+                Ok(DebouncedEvent::Create(path))
+                | Ok(DebouncedEvent::Write(path))
+                | Ok(DebouncedEvent::Chmod(path))
+                | Ok(DebouncedEvent::Remove(path)) => self.reload_for_event_path(&path),
+                Ok(DebouncedEvent::Rename(from_path, to_path)) => {
+                    self.reload_for_event_path(&from_path);
+                    self.reload_for_event_path(&to_path);
+                }
+                Ok(DebouncedEvent::Rescan) => self.reload_all(),
+                Ok(DebouncedEvent::Error(e, path)) => {
+                    warn!("static file watcher error {:?}: {}", path, e)
+                }
+                Ok(DebouncedEvent::NoticeWrite(_)) | Ok(DebouncedEvent::NoticeRemove(_)) => {}
+                Err(e) => {
+                    warn!("static file watcher recv error {}", e);
+                    return;
+                }
+            }
+        }
+    }
+
+    // Watches the parent directory of every registered file so that files
+    // replaced by rename (as most editors and deploy scripts do) are noticed.
+    pub fn start_watching(self: &Arc<Self>) -> Result<(), Box<::std::error::Error>> {
+        let directories: BTreeSet<PathBuf> = self
+            .registered
+            .read()
+            .unwrap()
+            .keys()
+            .filter_map(|key| key.parent().map(|p| p.to_path_buf()))
+            .collect();
+
+        let (sender, receiver) = mpsc::channel();
+
+        let mut watcher = ::notify::watcher(sender, WATCHER_DELAY)?;
+
+        for directory in &directories {
+            watcher.watch(directory, RecursiveMode::NonRecursive)?;
+        }
+
+        let cache_clone = Arc::clone(self);
+
+        ::std::thread::Builder::new()
+            .name("static_file_watcher".to_string())
+            .spawn(move || {
+                // the watcher stops when dropped, so it lives on this thread
+                let _watcher = watcher;
+                cache_clone.run_watcher(receiver);
+            })?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = ::std::env::temp_dir().join(format!(
+            "rust_hyper_memory_cache_{}_{}",
+            name,
+            ::std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    // run_watcher returns once the sender is gone, so feeding it a closed
+    // channel applies the events synchronously.
+    fn apply_events(cache: &Arc<StaticFileMemoryCache>, events: Vec<DebouncedEvent>) {
+        let (sender, receiver) = mpsc::channel();
+        for event in events {
+            sender.send(event).unwrap();
+        }
+        drop(sender);
+        Arc::clone(cache).run_watcher(receiver);
+    }
+
+    fn cached_data(cache: &StaticFileMemoryCache, key: &Path) -> Option<Bytes> {
+        cache.get(key).map(|cached_file| cached_file.data().clone())
+    }
+
+    #[test]
+    fn get_counts_hits_and_misses() {
+        let dir = test_dir("counters");
+        fs::write(dir.join("a.txt"), "a").unwrap();
+
+        let cache = StaticFileMemoryCache::new();
+        let key = cache
+            .register(dir.join("a.txt").to_str().unwrap(), false)
+            .unwrap();
+
+        assert!(cache.get(&key).is_some());
+        assert!(cache.get(&key).is_some());
+        assert!(cache.get(&dir.join("other.txt")).is_none());
+
+        assert_eq!(cache.hits(), 2);
+        assert_eq!(cache.misses(), 1);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn watcher_events_reload_and_drop_entries() {
+        let dir = test_dir("watcher");
+        fs::write(dir.join("a.txt"), "old").unwrap();
+
+        let cache = Arc::new(StaticFileMemoryCache::new());
+        let key = cache
+            .register(dir.join("a.txt").to_str().unwrap(), false)
+            .unwrap();
+        let dir = key.parent().unwrap().to_path_buf();
+
+        fs::write(&key, "new").unwrap();
+        assert_eq!(cached_data(&cache, &key), Some(Bytes::from_static(b"old")));
+        apply_events(&cache, vec![DebouncedEvent::Write(key.clone())]);
+        assert_eq!(cached_data(&cache, &key), Some(Bytes::from_static(b"new")));
+
+        fs::write(dir.join("a.txt.tmp"), "renamed").unwrap();
+        fs::rename(dir.join("a.txt.tmp"), &key).unwrap();
+        apply_events(
+            &cache,
+            vec![DebouncedEvent::Rename(dir.join("a.txt.tmp"), key.clone())],
+        );
+        assert_eq!(
+            cached_data(&cache, &key),
+            Some(Bytes::from_static(b"renamed"))
+        );
+
+        fs::remove_file(&key).unwrap();
+        apply_events(&cache, vec![DebouncedEvent::Remove(key.clone())]);
+        assert!(cache.get(&key).is_none());
+        assert_eq!(cache.entry_sizes(), vec![(key.clone(), None)]);
+
+        fs::write(&key, "back").unwrap();
+        apply_events(&cache, vec![DebouncedEvent::Rescan]);
+        assert_eq!(cached_data(&cache, &key), Some(Bytes::from_static(b"back")));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn sibling_events_reload_the_original() {
+        let dir = test_dir("sibling");
+        fs::write(dir.join("a.txt"), "a").unwrap();
+
+        let cache = Arc::new(StaticFileMemoryCache::new());
+        let key = cache
+            .register(dir.join("a.txt").to_str().unwrap(), true)
+            .unwrap();
+        let gz_path = key.with_extension("txt.gz");
+
+        assert!(cache.get(&key).unwrap().encoded(Encoding::Gzip).is_none());
+
+        fs::write(&gz_path, "gzipped").unwrap();
+        apply_events(&cache, vec![DebouncedEvent::Create(gz_path.clone())]);
+        assert_eq!(
+            cache.get(&key).unwrap().encoded(Encoding::Gzip),
+            Some(Bytes::from_static(b"gzipped"))
+        );
+
+        fs::remove_file(&gz_path).unwrap();
+        apply_events(&cache, vec![DebouncedEvent::Remove(gz_path)]);
+        assert!(cache.get(&key).unwrap().encoded(Encoding::Gzip).is_none());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
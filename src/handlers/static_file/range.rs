@@ -0,0 +1,360 @@
+use futures::{try_ready, Async, Poll, Stream};
+
+use hyper::header::{HeaderMap, RANGE};
+use hyper::Chunk;
+
+use std::collections::VecDeque;
+use std::io;
+
+// Requests asking for more ranges than this get the whole file instead.
+// Overlapping and adjacent ranges are merged, so no byte is sent twice.
+const MAX_RANGES: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ByteRange {
+    start: u64,
+    end: u64,
+}
+
+impl ByteRange {
+    pub fn start(&self) -> u64 {
+        self.start
+    }
+
+    pub fn length(&self) -> u64 {
+        self.end - self.start + 1
+    }
+
+    pub fn content_range(&self, complete_length: u64) -> String {
+        format!("bytes {}-{}/{}", self.start, self.end, complete_length)
+    }
+}
+
+pub fn unsatisfied_content_range(complete_length: u64) -> String {
+    format!("bytes */{}", complete_length)
+}
+
+pub enum RangeRequest {
+    None,
+    Satisfiable(Vec<ByteRange>),
+    Unsatisfiable,
+}
+
+fn parse_range_spec(spec: &str, complete_length: u64) -> Result<Option<ByteRange>, ()> {
+    let mut split = spec.trim().splitn(2, '-');
+    let first = split.next().ok_or(())?.trim();
+    let last = split.next().ok_or(())?.trim();
+
+    if first.is_empty() {
+        // suffix range: the last N bytes
+        let suffix_length: u64 = last.parse().map_err(|_| ())?;
+        if suffix_length == 0 || complete_length == 0 {
+            return Ok(None);
+        }
+        let start = complete_length.saturating_sub(suffix_length);
+        return Ok(Some(ByteRange {
+            start,
+            end: complete_length - 1,
+        }));
+    }
+
+    let start: u64 = first.parse().map_err(|_| ())?;
+    let end = if last.is_empty() {
+        None
+    } else {
+        Some(last.parse::<u64>().map_err(|_| ())?)
+    };
+
+    if let Some(end) = end {
+        if end < start {
+            return Err(());
+        }
+    }
+
+    if start >= complete_length {
+        return Ok(None);
+    }
+
+    let end = match end {
+        Some(end) if end < complete_length => end,
+        _ => complete_length - 1,
+    };
+
+    Ok(Some(ByteRange { start, end }))
+}
+
+pub fn parse_range_header(req_headers: &HeaderMap, complete_length: u64) -> RangeRequest {
+    let header_value = match req_headers.get(RANGE).and_then(|v| v.to_str().ok()) {
+        Some(header_value) => header_value,
+        None => return RangeRequest::None,
+    };
+
+    let specs = match header_value
+        .trim()
+        .splitn(2, '=')
+        .collect::<Vec<_>>()
+        .as_slice()
+    {
+        [unit, specs] if unit.trim() == "bytes" => specs.to_string(),
+        _ => return RangeRequest::None,
+    };
+
+    let mut ranges = Vec::new();
+
+    for spec in specs.split(',') {
+        match parse_range_spec(spec, complete_length) {
+            Ok(Some(range)) => ranges.push(range),
+            Ok(None) => {}
+            // a syntactically invalid Range header is ignored as a whole
+            Err(()) => return RangeRequest::None,
+        }
+    }
+
+    if ranges.len() > MAX_RANGES {
+        RangeRequest::None
+    } else if ranges.is_empty() {
+        RangeRequest::Unsatisfiable
+    } else {
+        RangeRequest::Satisfiable(merge_ranges(ranges))
+    }
+}
+
+fn merge_ranges(mut ranges: Vec<ByteRange>) -> Vec<ByteRange> {
+    ranges.sort_by_key(|range| range.start);
+
+    let mut merged: Vec<ByteRange> = Vec::with_capacity(ranges.len());
+
+    for range in ranges {
+        match merged.last_mut() {
+            Some(last) if range.start <= last.end.saturating_add(1) => {
+                last.end = last.end.max(range.end);
+            }
+            _ => merged.push(range),
+        }
+    }
+
+    merged
+}
+
+pub fn generate_boundary() -> io::Result<String> {
+    let mut bytes = [0; 8];
+    crate::utils::fill_random(&mut bytes)?;
+
+    Ok(format!("rust_hyper_{:016x}", u64::from_be_bytes(bytes)))
+}
+
+pub struct MultipartBody {
+    parts: VecDeque<(Vec<u8>, ByteRange)>,
+    trailer: Vec<u8>,
+    content_length: u64,
+}
+
+impl MultipartBody {
+    pub fn new(
+        ranges: &[ByteRange],
+        content_type: &str,
+        complete_length: u64,
+        boundary: &str,
+    ) -> Self {
+        let mut parts = VecDeque::with_capacity(ranges.len());
+        let mut content_length = 0;
+
+        for range in ranges {
+            let part_header = format!(
+                "\r\n--{}\r\nContent-Type: {}\r\nContent-Range: {}\r\n\r\n",
+                boundary,
+                content_type,
+                range.content_range(complete_length)
+            )
+            .into_bytes();
+
+            content_length += part_header.len() as u64 + range.length();
+            parts.push_back((part_header, *range));
+        }
+
+        let trailer = format!("\r\n--{}--\r\n", boundary).into_bytes();
+        content_length += trailer.len() as u64;
+
+        MultipartBody {
+            parts,
+            trailer,
+            content_length,
+        }
+    }
+
+    pub fn content_length(&self) -> u64 {
+        self.content_length
+    }
+
+    pub fn into_stream(self, file: ::tokio_fs::File) -> MultipartStream {
+        MultipartStream {
+            file_stream: super::file_stream::FileStream::new(file, 0),
+            parts: self.parts,
+            trailer: Some(self.trailer),
+            reading: false,
+        }
+    }
+
+    // Builds the whole body at once for files already held in memory.
+    pub fn into_bytes(self, data: &[u8]) -> Vec<u8> {
+        let mut body = Vec::with_capacity(self.content_length as usize);
+
+        for (part_header, range) in self.parts {
+            let start = range.start as usize;
+            let end = range.end as usize + 1;
+
+            body.extend_from_slice(&part_header);
+            body.extend_from_slice(&data[start..end]);
+        }
+
+        body.extend_from_slice(&self.trailer);
+        body
+    }
+}
+
+// Emits each part header followed by the bytes of its range, reusing a single
+// open file and seeking between parts.
+pub struct MultipartStream {
+    file_stream: super::file_stream::FileStream,
+    parts: VecDeque<(Vec<u8>, ByteRange)>,
+    trailer: Option<Vec<u8>>,
+    reading: bool,
+}
+
+impl Stream for MultipartStream {
+    type Item = Chunk;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<Chunk>, io::Error> {
+        if self.reading {
+            match try_ready!(self.file_stream.poll()) {
+                Some(chunk) => return Ok(Async::Ready(Some(chunk))),
+                None => self.reading = false,
+            }
+        }
+
+        match self.parts.pop_front() {
+            Some((part_header, range)) => {
+                self.file_stream.set_range(&range);
+                self.reading = true;
+                Ok(Async::Ready(Some(Chunk::from(part_header))))
+            }
+            None => Ok(Async::Ready(self.trailer.take().map(Chunk::from))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use hyper::header::HeaderValue;
+
+    fn parse(range: &str, complete_length: u64) -> RangeRequest {
+        let mut headers = HeaderMap::new();
+        headers.insert(RANGE, HeaderValue::from_str(range).unwrap());
+        parse_range_header(&headers, complete_length)
+    }
+
+    fn satisfiable(range: &str, complete_length: u64) -> Vec<(u64, u64)> {
+        match parse(range, complete_length) {
+            RangeRequest::Satisfiable(ranges) => ranges.iter().map(|r| (r.start, r.end)).collect(),
+            _ => panic!("expected {} to be satisfiable", range),
+        }
+    }
+
+    #[test]
+    fn parse_range_header_single_ranges() {
+        assert_eq!(satisfiable("bytes=0-9", 100), vec![(0, 9)]);
+        assert_eq!(satisfiable("bytes=90-", 100), vec![(90, 99)]);
+        assert_eq!(satisfiable("bytes=-10", 100), vec![(90, 99)]);
+        assert_eq!(satisfiable("bytes=-500", 100), vec![(0, 99)]);
+        assert_eq!(satisfiable("bytes=50-500", 100), vec![(50, 99)]);
+    }
+
+    #[test]
+    fn parse_range_header_ignores_invalid_headers() {
+        for range in &["bytes=9-0", "bytes=a-b", "bytes=5", "items=0-9", "0-9"] {
+            match parse(range, 100) {
+                RangeRequest::None => {}
+                _ => panic!("expected {} to be ignored", range),
+            }
+        }
+
+        match parse_range_header(&HeaderMap::new(), 100) {
+            RangeRequest::None => {}
+            _ => panic!("expected no range without a Range header"),
+        }
+    }
+
+    #[test]
+    fn parse_range_header_unsatisfiable() {
+        for (range, complete_length) in &[("bytes=100-", 100), ("bytes=-0", 100), ("bytes=-5", 0)] {
+            match parse(range, *complete_length) {
+                RangeRequest::Unsatisfiable => {}
+                _ => panic!("expected {} to be unsatisfiable", range),
+            }
+        }
+
+        // unsatisfiable specs are dropped when another one is satisfiable
+        assert_eq!(satisfiable("bytes=200-300, 0-0", 100), vec![(0, 0)]);
+        assert_eq!(unsatisfied_content_range(100), "bytes */100");
+    }
+
+    #[test]
+    fn parse_range_header_merges_overlapping_ranges() {
+        assert_eq!(satisfiable("bytes=0-9, 5-19", 100), vec![(0, 19)]);
+        assert_eq!(satisfiable("bytes=0-9, 10-19", 100), vec![(0, 19)]);
+        assert_eq!(satisfiable("bytes=0-9, 0-9, 0-9", 100), vec![(0, 9)]);
+        assert_eq!(satisfiable("bytes=50-59, 0-9", 100), vec![(0, 9), (50, 59)]);
+        assert_eq!(satisfiable("bytes=0-, -10", 100), vec![(0, 99)]);
+    }
+
+    #[test]
+    fn parse_range_header_limits_range_count() {
+        let ranges = |count: u64| {
+            (0..count)
+                .map(|i| format!("{}-{}", i * 2, i * 2))
+                .collect::<Vec<_>>()
+                .join(",")
+        };
+
+        assert_eq!(
+            satisfiable(&format!("bytes={}", ranges(MAX_RANGES as u64)), 100).len(),
+            MAX_RANGES
+        );
+
+        match parse(&format!("bytes={}", ranges(MAX_RANGES as u64 + 1)), 100) {
+            RangeRequest::None => {}
+            _ => panic!("expected too many ranges to be ignored"),
+        }
+    }
+
+    #[test]
+    fn generate_boundary_is_random() {
+        let boundary = generate_boundary().unwrap();
+        assert!(boundary.starts_with("rust_hyper_"));
+        assert_eq!(boundary.len(), "rust_hyper_".len() + 16);
+        assert_ne!(boundary, generate_boundary().unwrap());
+    }
+
+    #[test]
+    fn multipart_body_framing() {
+        let ranges = [
+            ByteRange { start: 0, end: 2 },
+            ByteRange { start: 7, end: 9 },
+        ];
+        let multipart_body = MultipartBody::new(&ranges, "text/plain", 10, "b");
+        let content_length = multipart_body.content_length();
+
+        let body = multipart_body.into_bytes(b"0123456789");
+
+        assert_eq!(
+            String::from_utf8(body.clone()).unwrap(),
+            "\r\n--b\r\nContent-Type: text/plain\r\nContent-Range: bytes 0-2/10\r\n\r\n012\
+             \r\n--b\r\nContent-Type: text/plain\r\nContent-Range: bytes 7-9/10\r\n\r\n789\
+             \r\n--b--\r\n"
+        );
+        assert_eq!(content_length, body.len() as u64);
+    }
+}
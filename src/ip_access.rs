@@ -0,0 +1,348 @@
+use hyper::header::HeaderMap;
+
+use log::warn;
+
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr};
+
+pub const X_FORWARDED_FOR: &str = "x-forwarded-for";
+
+// Clients on dual-stack sockets show up as ::ffff:a.b.c.d; treat them as the
+// IPv4 address so IPv4 rules apply.
+fn canonical_ip(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => {
+            let segments = v6.segments();
+            if segments[..5].iter().all(|s| *s == 0) && segments[5] == 0xffff {
+                let octets = v6.octets();
+                IpAddr::V4(Ipv4Addr::new(
+                    octets[12], octets[13], octets[14], octets[15],
+                ))
+            } else {
+                ip
+            }
+        }
+        IpAddr::V4(_) => ip,
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct IpNetwork {
+    address: IpAddr,
+    prefix_length: u32,
+}
+
+impl IpNetwork {
+    fn parse(network: &str) -> Result<Self, Box<::std::error::Error>> {
+        let mut split = network.trim().splitn(2, '/');
+        let address = canonical_ip(split.next().unwrap_or("").parse()?);
+
+        let max_prefix_length = match address {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+
+        let prefix_length = match split.next() {
+            Some(prefix_length) => prefix_length.parse()?,
+            None => max_prefix_length,
+        };
+
+        if prefix_length > max_prefix_length {
+            return Err(format!("invalid prefix length in '{}'", network).into());
+        }
+
+        Ok(IpNetwork {
+            address,
+            prefix_length,
+        })
+    }
+
+    fn contains(&self, ip: IpAddr) -> bool {
+        match (self.address, ip) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix_length).unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix_length).unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for IpNetwork {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.address, self.prefix_length)
+    }
+}
+
+fn parse_networks(networks: &[String]) -> Result<Vec<IpNetwork>, Box<::std::error::Error>> {
+    networks
+        .iter()
+        .map(|network| {
+            IpNetwork::parse(network)
+                .map_err(|e| format!("invalid ip network '{}': {}", network, e).into())
+        })
+        .collect()
+}
+
+struct IpRules {
+    name: String,
+    allow: Vec<IpNetwork>,
+    deny: Vec<IpNetwork>,
+}
+
+impl IpRules {
+    fn new(
+        name: String,
+        allow: &[String],
+        deny: &[String],
+    ) -> Result<Self, Box<::std::error::Error>> {
+        Ok(IpRules {
+            name,
+            allow: parse_networks(allow)?,
+            deny: parse_networks(deny)?,
+        })
+    }
+
+    // Returns a description of the rule that denied ip.
+    fn check(&self, ip: IpAddr) -> Result<(), String> {
+        if let Some(network) = self.deny.iter().find(|network| network.contains(ip)) {
+            return Err(format!("{} deny {}", self.name, network));
+        }
+
+        if !self.allow.is_empty() && !self.allow.iter().any(|network| network.contains(ip)) {
+            return Err(format!("{} allow list", self.name));
+        }
+
+        Ok(())
+    }
+}
+
+pub struct IpAccessControl {
+    trusted_proxies: Vec<IpNetwork>,
+    global_rules: IpRules,
+    route_rules: crate::server::PrefixRules<IpRules>,
+}
+
+impl IpAccessControl {
+    pub fn new(
+        ip_access_info: &crate::config::IpAccessInfo,
+    ) -> Result<Self, Box<::std::error::Error>> {
+        let mut route_rules = Vec::with_capacity(ip_access_info.routes().len());
+        for route_info in ip_access_info.routes() {
+            route_rules.push((
+                route_info.path_prefix().clone(),
+                IpRules::new(
+                    format!("route {}", route_info.path_prefix()),
+                    route_info.allow(),
+                    route_info.deny(),
+                )?,
+            ));
+        }
+
+        Ok(IpAccessControl {
+            trusted_proxies: parse_networks(ip_access_info.trusted_proxies())?,
+            global_rules: IpRules::new(
+                "global".to_string(),
+                ip_access_info.allow(),
+                ip_access_info.deny(),
+            )?,
+            route_rules: crate::server::PrefixRules::new(route_rules),
+        })
+    }
+
+    fn is_trusted_proxy(&self, ip: IpAddr) -> bool {
+        self.trusted_proxies
+            .iter()
+            .any(|network| network.contains(ip))
+    }
+
+    // The client is the last X-Forwarded-For hop not added by a trusted
+    // proxy; the header is ignored unless the peer itself is trusted.
+    pub fn client_ip(&self, peer_ip: IpAddr, headers: &HeaderMap) -> IpAddr {
+        let mut client_ip = canonical_ip(peer_ip);
+
+        if !self.is_trusted_proxy(client_ip) {
+            return client_ip;
+        }
+
+        let forwarded_for: Vec<&str> = headers
+            .get_all(X_FORWARDED_FOR)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .collect();
+
+        for hop in forwarded_for.iter().rev() {
+            match hop.trim().parse() {
+                Ok(hop_ip) => {
+                    client_ip = canonical_ip(hop_ip);
+                    if !self.is_trusted_proxy(client_ip) {
+                        break;
+                    }
+                }
+                Err(_) => break,
+            }
+        }
+
+        client_ip
+    }
+
+    pub fn is_allowed(&self, client_ip: IpAddr, path: &str) -> bool {
+        let route_rules = self.route_rules.lookup(path);
+
+        let result = self
+            .global_rules
+            .check(client_ip)
+            .and_then(|_| route_rules.map_or(Ok(()), |rules| rules.check(client_ip)));
+
+        match result {
+            Ok(()) => true,
+            Err(rule) => {
+                warn!("denied {} access to {} by {}", client_ip, path, rule);
+                false
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use hyper::header::HeaderValue;
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    fn ip_access_control(ip_access_json: &str) -> IpAccessControl {
+        let ip_access_info: crate::config::IpAccessInfo =
+            ::serde_json::from_str(ip_access_json).unwrap();
+        IpAccessControl::new(&ip_access_info).unwrap()
+    }
+
+    #[test]
+    fn ip_network_prefix_lengths() {
+        let network = IpNetwork::parse("192.168.1.0/24").unwrap();
+        assert!(network.contains(ip("192.168.1.255")));
+        assert!(!network.contains(ip("192.168.2.1")));
+        assert!(!network.contains(ip("::1")));
+
+        let everything = IpNetwork::parse("0.0.0.0/0").unwrap();
+        assert!(everything.contains(ip("1.2.3.4")));
+        assert!(everything.contains(ip("255.255.255.255")));
+
+        let single = IpNetwork::parse("10.0.0.1/32").unwrap();
+        assert!(single.contains(ip("10.0.0.1")));
+        assert!(!single.contains(ip("10.0.0.2")));
+
+        let plain = IpNetwork::parse("10.0.0.1").unwrap();
+        assert_eq!(plain.prefix_length, 32);
+        assert!(!plain.contains(ip("10.0.0.2")));
+
+        let v6 = IpNetwork::parse("fd00::/8").unwrap();
+        assert!(v6.contains(ip("fd12::1")));
+        assert!(!v6.contains(ip("fe80::1")));
+        assert!(!v6.contains(ip("10.0.0.1")));
+
+        assert!(IpNetwork::parse("::/0")
+            .unwrap()
+            .contains(ip("2001:db8::1")));
+
+        let v6_single = IpNetwork::parse("2001:db8::1/128").unwrap();
+        assert!(v6_single.contains(ip("2001:db8::1")));
+        assert!(!v6_single.contains(ip("2001:db8::2")));
+    }
+
+    #[test]
+    fn ip_network_rejects_invalid_networks() {
+        assert!(IpNetwork::parse("10.0.0.0/33").is_err());
+        assert!(IpNetwork::parse("::/129").is_err());
+        assert!(IpNetwork::parse("10.0.0.0/x").is_err());
+        assert!(IpNetwork::parse("not an address").is_err());
+    }
+
+    #[test]
+    fn canonical_ip_unmaps_ipv4_mapped_addresses() {
+        assert_eq!(canonical_ip(ip("::ffff:192.168.1.2")), ip("192.168.1.2"));
+        assert_eq!(canonical_ip(ip("::1")), ip("::1"));
+        assert_eq!(canonical_ip(ip("::192.168.1.2")), ip("::192.168.1.2"));
+        assert_eq!(canonical_ip(ip("10.0.0.1")), ip("10.0.0.1"));
+
+        assert!(IpNetwork::parse("10.0.0.0/8")
+            .unwrap()
+            .contains(canonical_ip(ip("::ffff:10.1.2.3"))));
+    }
+
+    #[test]
+    fn deny_rules_win_over_allow_rules() {
+        let ip_access_control = ip_access_control(
+            r#"{
+                "allow": ["10.0.0.0/8"],
+                "deny": ["10.0.0.5"],
+                "routes": [
+                    { "path_prefix": "/admin", "allow": ["10.0.1.0/24"] },
+                    { "path_prefix": "/admin/open", "deny": ["10.0.1.9"] }
+                ]
+            }"#,
+        );
+
+        assert!(ip_access_control.is_allowed(ip("10.0.0.4"), "/"));
+        assert!(!ip_access_control.is_allowed(ip("10.0.0.5"), "/"));
+        assert!(!ip_access_control.is_allowed(ip("192.168.1.1"), "/"));
+
+        // route rules apply on top of the global ones
+        assert!(ip_access_control.is_allowed(ip("10.0.1.1"), "/admin/x"));
+        assert!(!ip_access_control.is_allowed(ip("10.0.0.4"), "/admin/x"));
+
+        // the longest prefix wins, so /admin's allow list doesn't apply
+        assert!(ip_access_control.is_allowed(ip("10.0.0.4"), "/admin/open"));
+        assert!(!ip_access_control.is_allowed(ip("10.0.1.9"), "/admin/open"));
+    }
+
+    #[test]
+    fn client_ip_walks_trusted_proxies() {
+        let ip_access_control =
+            ip_access_control(r#"{ "trusted_proxies": ["127.0.0.1", "10.0.0.0/8"] }"#);
+
+        let client_ip = |peer_ip: &str, forwarded_for: &[&str]| {
+            let mut headers = HeaderMap::new();
+            for value in forwarded_for {
+                headers.append(X_FORWARDED_FOR, HeaderValue::from_str(value).unwrap());
+            }
+            ip_access_control.client_ip(ip(peer_ip), &headers)
+        };
+
+        // untrusted peers can't pick their address
+        assert_eq!(client_ip("192.0.2.1", &["198.51.100.1"]), ip("192.0.2.1"));
+
+        assert_eq!(client_ip("127.0.0.1", &[]), ip("127.0.0.1"));
+        assert_eq!(
+            client_ip("127.0.0.1", &["198.51.100.1"]),
+            ip("198.51.100.1")
+        );
+        assert_eq!(
+            client_ip("::ffff:127.0.0.1", &["198.51.100.1, 10.0.0.2"]),
+            ip("198.51.100.1")
+        );
+        // a spoofed first hop is ignored once an untrusted hop is found
+        assert_eq!(
+            client_ip("127.0.0.1", &["203.0.113.7", "198.51.100.1, 10.0.0.2"]),
+            ip("198.51.100.1")
+        );
+        // an unparseable hop ends the walk
+        assert_eq!(
+            client_ip("127.0.0.1", &["198.51.100.1, garbage, 10.0.0.2"]),
+            ip("10.0.0.2")
+        );
+        // all hops trusted: the first one is the client
+        assert_eq!(
+            client_ip("127.0.0.1", &["10.0.0.3, 10.0.0.2"]),
+            ip("10.0.0.3")
+        );
+    }
+}
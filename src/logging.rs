@@ -1,72 +1,338 @@
+mod file;
+mod queue;
+mod syslog;
+
 use chrono::prelude::Local;
 
+use log::{Level, LevelFilter};
+
+use std::collections::BTreeMap;
+use std::io;
 use std::io::Write;
-use std::sync::mpsc;
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+struct LogMessage {
+    level: Level,
+    line: String,
+}
 
-fn run_logging_output_thread(receiver: mpsc::Receiver<String>) {
-    let mut stdout = ::std::io::stdout();
+trait LogWriter: Send {
+    fn write_message(&mut self, level: Level, line: &str) -> io::Result<()>;
 
-    loop {
-        match receiver.recv() {
-            Ok(first_msg) => {
-                stdout
-                    .write(first_msg.as_bytes())
-                    .expect("run_logging_output_thread error writing first_msg to stdout");
-                loop {
-                    match receiver.try_recv() {
-                        Ok(next_msg) => {
-                            stdout.write(next_msg.as_bytes()).expect(
-                                "run_logging_output_thread error writing next_msg to stdout",
-                            );
-                        }
-                        Err(mpsc::TryRecvError::Empty) => break,
-                        Err(mpsc::TryRecvError::Disconnected) => {
-                            stdout
-                                .write(
-                                    "run_logging_output_thread try_recv disconnected error"
-                                        .as_bytes(),
-                                )
-                                .expect("run_logging_output_thread error writing to stdout");
-                        }
-                    }
+    fn flush(&mut self) -> io::Result<()>;
+
+    // Called before retrying a writer whose last write failed.
+    fn reopen(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+struct StdoutWriter {
+    stdout: io::Stdout,
+}
+
+impl LogWriter for StdoutWriter {
+    fn write_message(&mut self, _: Level, line: &str) -> io::Result<()> {
+        self.stdout.write_all(line.as_bytes())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stdout.flush()
+    }
+}
+
+// A writer that failed keeps dropping messages until a reopen and write
+// succeed again.  Failures and recoveries are reported on stderr, since the
+// log itself may be what is broken.
+struct OutputWriter {
+    writer: Box<LogWriter>,
+    failed_messages: u64,
+}
+
+impl OutputWriter {
+    fn report(message: String) {
+        let _ = writeln!(io::stderr(), "logging_output: {}", message);
+    }
+
+    fn write_messages(&mut self, messages: &[LogMessage]) {
+        if self.failed_messages > 0 {
+            if let Err(e) = self.writer.reopen() {
+                self.failed_messages += messages.len() as u64;
+                Self::report(format!("reopen failed: {}", e));
+                return;
+            }
+        }
+
+        let mut result = Ok(());
+        let mut written = 0;
+        for msg in messages {
+            result = self.writer.write_message(msg.level, &msg.line);
+            if result.is_err() {
+                break;
+            }
+            written += 1;
+        }
+        let result = result.and_then(|_| self.writer.flush());
+
+        match result {
+            Ok(()) => {
+                if self.failed_messages > 0 {
+                    Self::report(format!(
+                        "output recovered after dropping {} messages",
+                        self.failed_messages
+                    ));
+                    self.failed_messages = 0;
                 }
             }
             Err(e) => {
-                stdout
-                    .write(format!("run_logging_output_thread recv error {}\n", e).as_bytes())
-                    .expect("run_logging_output_thread error writing to stdout");
+                if self.failed_messages == 0 {
+                    Self::report(format!("write failed, dropping messages: {}", e));
+                }
+                self.failed_messages += (messages.len() - written) as u64;
             }
         }
+    }
+}
 
-        stdout
-            .flush()
-            .expect("run_logging_output_thread error flushing stdout");
+fn dropped_messages_line(dropped: u64) -> LogMessage {
+    LogMessage {
+        level: Level::Warn,
+        line: format!(
+            "{} [{}] {} {} - dropped {} log messages because the logging queue was full\n",
+            Local::now().format("%Y-%m-%d %H:%M:%S%.9f %z"),
+            ::std::thread::current().name().unwrap_or("UNKNOWN"),
+            Level::Warn,
+            module_path!(),
+            dropped
+        ),
     }
 }
 
-pub fn initialize_logging() -> Result<(), Box<::std::error::Error>> {
-    let (sender, receiver) = mpsc::channel();
+fn run_logging_output_thread(
+    queue: Arc<queue::LogQueue<LogMessage>>,
+    writers: Vec<Box<LogWriter>>,
+    dropped_report_interval: Duration,
+) {
+    let mut writers: Vec<OutputWriter> = writers
+        .into_iter()
+        .map(|writer| OutputWriter {
+            writer,
+            failed_messages: 0,
+        })
+        .collect();
+
+    let mut unreported_dropped = 0;
+    let mut last_dropped_report = Instant::now();
+
+    loop {
+        let (mut messages, dropped) = queue.pop_all(dropped_report_interval);
+        unreported_dropped += dropped;
+
+        if unreported_dropped > 0 && last_dropped_report.elapsed() >= dropped_report_interval {
+            messages.push(dropped_messages_line(unreported_dropped));
+            unreported_dropped = 0;
+            last_dropped_report = Instant::now();
+        }
 
+        if messages.is_empty() {
+            continue;
+        }
+
+        for writer in writers.iter_mut() {
+            writer.write_messages(&messages);
+        }
+    }
+}
+
+fn parse_level(level: &str) -> Result<LevelFilter, Box<::std::error::Error>> {
+    LevelFilter::from_str(level).map_err(|_| format!("invalid log level '{}'", level).into())
+}
+
+struct LevelConfiguration {
+    level: LevelFilter,
+    module_levels: BTreeMap<String, LevelFilter>,
+}
+
+impl LevelConfiguration {
+    // The most specific module override wins, as with env_logger.
+    fn level_for_target(&self, target: &str) -> LevelFilter {
+        self.module_levels
+            .iter()
+            .filter(|(module, _)| {
+                target == module.as_str()
+                    || (target.starts_with(module.as_str())
+                        && target[module.len()..].starts_with("::"))
+            })
+            .max_by_key(|(module, _)| module.len())
+            .map(|(_, level)| *level)
+            .unwrap_or(self.level)
+    }
+
+    fn max_level(&self) -> LevelFilter {
+        self.module_levels
+            .values()
+            .cloned()
+            .fold(self.level, ::std::cmp::max)
+    }
+}
+
+// Handle for inspecting and changing log levels while the server runs.
+#[derive(Clone)]
+pub struct LogLevelControl {
+    levels: Arc<RwLock<LevelConfiguration>>,
+}
+
+impl LogLevelControl {
+    pub fn describe(&self) -> String {
+        let levels = self.levels.read().unwrap();
+
+        let mut description = format!("level: {}\n", levels.level);
+        for (module, level) in &levels.module_levels {
+            description.push_str(&format!("{}: {}\n", module, level));
+        }
+        description
+    }
+
+    // Sets the global level, or a module override when module is given.  A
+    // module level of "default" removes the override.
+    pub fn set_level(
+        &self,
+        module: Option<&str>,
+        level: &str,
+    ) -> Result<(), Box<::std::error::Error>> {
+        let mut levels = self.levels.write().unwrap();
+
+        match module {
+            Some(module) if level == "default" => {
+                levels.module_levels.remove(module);
+            }
+            Some(module) => {
+                levels
+                    .module_levels
+                    .insert(module.to_string(), parse_level(level)?);
+            }
+            None => levels.level = parse_level(level)?,
+        }
+
+        ::log::set_max_level(levels.max_level());
+
+        Ok(())
+    }
+}
+
+fn build_writer(
+    output_info: &crate::config::LogOutputInfo,
+) -> Result<Box<LogWriter>, Box<::std::error::Error>> {
+    Ok(match output_info {
+        crate::config::LogOutputInfo::Stdout => Box::new(StdoutWriter {
+            stdout: io::stdout(),
+        }),
+        crate::config::LogOutputInfo::File { path, rotation } => {
+            Box::new(file::RotatingFileWriter::new(path, rotation.clone())?)
+        }
+        crate::config::LogOutputInfo::Syslog { socket_path } => {
+            Box::new(syslog::SyslogWriter::new(socket_path))
+        }
+    })
+}
+
+// Warnings and errors go straight to stderr for the command line tools
+// that don't start the server.  The returned control only satisfies the
+// log level handler, since there is no server to change levels through.
+pub fn initialize_stderr_logging() -> Result<LogLevelControl, Box<::std::error::Error>> {
+    ::fern::Dispatch::new()
+        .level(LevelFilter::Warn)
+        .format(|out, message, record| {
+            out.finish(format_args!(
+                "{} {} - {}",
+                record.level(),
+                record.target(),
+                message
+            ))
+        })
+        .chain(io::stderr())
+        .apply()?;
+
+    Ok(LogLevelControl {
+        levels: Arc::new(RwLock::new(LevelConfiguration {
+            level: LevelFilter::Warn,
+            module_levels: BTreeMap::new(),
+        })),
+    })
+}
+
+pub fn initialize_logging(
+    logging_info: &crate::config::LoggingInfo,
+) -> Result<LogLevelControl, Box<::std::error::Error>> {
+    let mut module_levels = BTreeMap::new();
+    for (module, level) in logging_info.module_levels() {
+        module_levels.insert(module.clone(), parse_level(level)?);
+    }
+
+    let levels = Arc::new(RwLock::new(LevelConfiguration {
+        level: parse_level(logging_info.level())?,
+        module_levels,
+    }));
+
+    let mut writers = Vec::with_capacity(logging_info.outputs().len());
+    for output_info in logging_info.outputs() {
+        writers.push(build_writer(output_info)?);
+    }
+
+    let queue = Arc::new(queue::LogQueue::new(
+        logging_info.queue_capacity(),
+        logging_info.overflow_policy(),
+    ));
+    let dropped_report_interval =
+        Duration::from_secs(logging_info.dropped_report_interval_seconds().max(1));
+
+    let output_queue = Arc::clone(&queue);
     ::std::thread::Builder::new()
         .name("logging_output".to_string())
         .spawn(move || {
-            run_logging_output_thread(receiver);
+            run_logging_output_thread(output_queue, writers, dropped_report_interval);
         })?;
 
+    let filter_levels = Arc::clone(&levels);
+
     ::fern::Dispatch::new()
-        .level(::log::LevelFilter::Info)
+        .level(LevelFilter::Trace)
+        .filter(move |metadata| {
+            metadata.level()
+                <= filter_levels
+                    .read()
+                    .unwrap()
+                    .level_for_target(metadata.target())
+        })
         .format(|out, message, record| {
+            let request_id = match crate::request_id::current() {
+                Some(request_id) => format!(" [{}]", request_id),
+                None => String::new(),
+            };
+
             out.finish(format_args!(
-                "{} [{}] {} {} - {}",
+                "{} [{}]{} {} {} - {}",
                 Local::now().format("%Y-%m-%d %H:%M:%S%.9f %z"),
                 ::std::thread::current().name().unwrap_or("UNKNOWN"),
+                request_id,
                 record.level(),
                 record.target(),
                 message
             ))
         })
-        .chain(sender)
+        .chain(::fern::Output::call(move |record| {
+            queue.push(LogMessage {
+                level: record.level(),
+                line: format!("{}\n", record.args()),
+            });
+        }))
         .apply()?;
 
-    Ok(())
+    // fern sets the max level from Dispatch::level; narrow it to what is
+    // actually enabled so disabled records are skipped cheaply.
+    ::log::set_max_level(levels.read().unwrap().max_level());
+
+    Ok(LogLevelControl { levels })
 }
@@ -0,0 +1,114 @@
+use log::Level;
+
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+fn open_append(path: &PathBuf) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+// Rotated files are renamed to path.1, path.2, ... with path.1 the newest;
+// at most max_files of them are kept.  The rotation interval is measured
+// from when the file was opened, so it restarts with the process.
+pub struct RotatingFileWriter {
+    path: PathBuf,
+    rotation: Option<crate::config::LogRotationInfo>,
+    writer: BufWriter<File>,
+    size: u64,
+    opened_at: Instant,
+}
+
+impl RotatingFileWriter {
+    pub fn new(path: &str, rotation: Option<crate::config::LogRotationInfo>) -> io::Result<Self> {
+        let path = PathBuf::from(path);
+        let file = open_append(&path)?;
+        let size = file.metadata()?.len();
+
+        Ok(RotatingFileWriter {
+            path,
+            rotation,
+            writer: BufWriter::new(file),
+            size,
+            opened_at: Instant::now(),
+        })
+    }
+
+    fn rotated_path(&self, index: usize) -> PathBuf {
+        let mut rotated_path = self.path.as_os_str().to_owned();
+        rotated_path.push(format!(".{}", index));
+        PathBuf::from(rotated_path)
+    }
+
+    fn should_rotate(&self, additional_bytes: u64) -> bool {
+        let rotation = match &self.rotation {
+            Some(rotation) => rotation,
+            None => return false,
+        };
+
+        let size_exceeded = match rotation.max_size_bytes() {
+            Some(max_size_bytes) => self.size > 0 && self.size + additional_bytes > max_size_bytes,
+            None => false,
+        };
+
+        let interval_elapsed = match rotation.interval_seconds() {
+            Some(interval_seconds) => {
+                self.opened_at.elapsed() >= Duration::from_secs(interval_seconds)
+            }
+            None => false,
+        };
+
+        size_exceeded || interval_elapsed
+    }
+
+    fn rotate(&mut self, max_files: usize) -> io::Result<()> {
+        self.writer.flush()?;
+
+        if max_files == 0 {
+            ::std::fs::remove_file(&self.path)?;
+        } else {
+            for index in (1..max_files).rev() {
+                match ::std::fs::rename(self.rotated_path(index), self.rotated_path(index + 1)) {
+                    Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+                    result => result?,
+                }
+            }
+            ::std::fs::rename(&self.path, self.rotated_path(1))?;
+        }
+
+        self.writer = BufWriter::new(open_append(&self.path)?);
+        self.size = 0;
+        self.opened_at = Instant::now();
+
+        Ok(())
+    }
+}
+
+impl super::LogWriter for RotatingFileWriter {
+    fn write_message(&mut self, _: Level, line: &str) -> io::Result<()> {
+        if self.should_rotate(line.len() as u64) {
+            let max_files = self.rotation.as_ref().map_or(0, |r| r.max_files());
+            self.rotate(max_files)?;
+        }
+
+        self.writer.write_all(line.as_bytes())?;
+        self.size += line.len() as u64;
+
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+
+    // The file may have been removed or its filesystem remounted since the
+    // failed write, so start over with a fresh handle.
+    fn reopen(&mut self) -> io::Result<()> {
+        let file = open_append(&self.path)?;
+        self.size = file.metadata()?.len();
+        self.writer = BufWriter::new(file);
+        Ok(())
+    }
+}
@@ -0,0 +1,131 @@
+use std::collections::VecDeque;
+use std::sync::{Condvar, Mutex};
+use std::time::Duration;
+
+use crate::config::LogOverflowPolicy;
+
+struct QueueState<T> {
+    items: VecDeque<T>,
+    dropped: u64,
+}
+
+// Bounded queue between the logging callers and the output thread.  What
+// happens when it is full is decided by the overflow policy; dropped messages
+// are counted so the output thread can report them.
+pub struct LogQueue<T> {
+    state: Mutex<QueueState<T>>,
+    not_empty: Condvar,
+    not_full: Condvar,
+    capacity: usize,
+    overflow_policy: LogOverflowPolicy,
+}
+
+impl<T> LogQueue<T> {
+    pub fn new(capacity: usize, overflow_policy: LogOverflowPolicy) -> Self {
+        LogQueue {
+            state: Mutex::new(QueueState {
+                items: VecDeque::with_capacity(capacity),
+                dropped: 0,
+            }),
+            not_empty: Condvar::new(),
+            not_full: Condvar::new(),
+            capacity: capacity.max(1),
+            overflow_policy,
+        }
+    }
+
+    pub fn push(&self, item: T) {
+        let mut state = self.state.lock().unwrap();
+
+        if state.items.len() >= self.capacity {
+            match self.overflow_policy {
+                LogOverflowPolicy::Block => {
+                    while state.items.len() >= self.capacity {
+                        state = self.not_full.wait(state).unwrap();
+                    }
+                }
+                LogOverflowPolicy::DropNewest => {
+                    state.dropped += 1;
+                    return;
+                }
+                LogOverflowPolicy::DropOldest => {
+                    state.items.pop_front();
+                    state.dropped += 1;
+                }
+            }
+        }
+
+        state.items.push_back(item);
+        self.not_empty.notify_one();
+    }
+
+    // Waits up to timeout for messages, then takes everything queued along
+    // with the number of messages dropped since the previous call.
+    pub fn pop_all(&self, timeout: Duration) -> (Vec<T>, u64) {
+        let mut state = self.state.lock().unwrap();
+
+        if state.items.is_empty() {
+            state = self.not_empty.wait_timeout(state, timeout).unwrap().0;
+        }
+
+        let items: Vec<T> = state.items.drain(..).collect();
+        let dropped = state.dropped;
+        state.dropped = 0;
+
+        self.not_full.notify_all();
+
+        (items, dropped)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Arc;
+    use std::thread;
+
+    const NO_WAIT: Duration = Duration::from_millis(0);
+
+    #[test]
+    fn drop_newest_keeps_queued_messages() {
+        let queue = LogQueue::new(1, LogOverflowPolicy::DropNewest);
+        queue.push(1);
+        queue.push(2);
+        queue.push(3);
+
+        assert_eq!(queue.pop_all(NO_WAIT), (vec![1], 2));
+        // the dropped count is reset once reported
+        assert_eq!(queue.pop_all(NO_WAIT), (vec![], 0));
+
+        queue.push(4);
+        assert_eq!(queue.pop_all(NO_WAIT), (vec![4], 0));
+    }
+
+    #[test]
+    fn drop_oldest_keeps_latest_messages() {
+        let queue = LogQueue::new(1, LogOverflowPolicy::DropOldest);
+        queue.push(1);
+        queue.push(2);
+        queue.push(3);
+
+        assert_eq!(queue.pop_all(NO_WAIT), (vec![3], 2));
+        assert_eq!(queue.pop_all(NO_WAIT), (vec![], 0));
+    }
+
+    #[test]
+    fn block_waits_for_pop_all() {
+        let queue = Arc::new(LogQueue::new(1, LogOverflowPolicy::Block));
+        queue.push(1);
+
+        let queue_clone = Arc::clone(&queue);
+        let pusher = thread::spawn(move || queue_clone.push(2));
+
+        thread::sleep(Duration::from_millis(50));
+        assert!(!pusher.is_finished());
+
+        assert_eq!(queue.pop_all(NO_WAIT), (vec![1], 0));
+        pusher.join().unwrap();
+        assert_eq!(queue.pop_all(NO_WAIT), (vec![2], 0));
+    }
+}
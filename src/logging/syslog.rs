@@ -0,0 +1,72 @@
+use log::Level;
+
+use std::io;
+use std::os::unix::net::UnixDatagram;
+use std::path::PathBuf;
+
+const SYSLOG_IDENT: &str = "rust_hyper";
+const FACILITY_USER: u8 = 1;
+
+fn severity(level: Level) -> u8 {
+    match level {
+        Level::Error => 3,
+        Level::Warn => 4,
+        Level::Info => 6,
+        Level::Debug | Level::Trace => 7,
+    }
+}
+
+// Sends each record as one RFC 3164 style datagram to the local syslog
+// socket.  A record that can't be sent is dropped and the socket reconnected
+// for the next one, so a restarting syslog daemon doesn't stop the server.
+pub struct SyslogWriter {
+    socket_path: PathBuf,
+    socket: Option<UnixDatagram>,
+    pid: u32,
+}
+
+impl SyslogWriter {
+    pub fn new(socket_path: &str) -> Self {
+        SyslogWriter {
+            socket_path: PathBuf::from(socket_path),
+            socket: None,
+            pid: ::std::process::id(),
+        }
+    }
+
+    fn connected_socket(&mut self) -> io::Result<&UnixDatagram> {
+        if self.socket.is_none() {
+            let socket = UnixDatagram::unbound()?;
+            socket.connect(&self.socket_path)?;
+            self.socket = Some(socket);
+        }
+
+        Ok(self.socket.as_ref().unwrap())
+    }
+}
+
+impl super::LogWriter for SyslogWriter {
+    fn write_message(&mut self, level: Level, line: &str) -> io::Result<()> {
+        let message = format!(
+            "<{}>{}[{}]: {}",
+            FACILITY_USER * 8 + severity(level),
+            SYSLOG_IDENT,
+            self.pid,
+            line.trim_end()
+        );
+
+        let result = self
+            .connected_socket()
+            .and_then(|socket| socket.send(message.as_bytes()));
+
+        if result.is_err() {
+            self.socket = None;
+        }
+
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
@@ -1,10 +1,31 @@
+mod access_log;
+mod assets;
+mod auth;
+mod command_metrics;
+mod compression;
 mod config;
+mod cors;
 mod environment;
 mod handlers;
+mod ip_access;
 mod logging;
+mod metrics;
+mod rate_limit;
+mod redaction;
+mod request_id;
+mod sandbox;
+mod security_headers;
 mod server;
+mod session;
+mod trace;
 mod utils;
 
+use futures::Future;
+
+use log::{info, warn};
+
+use std::sync::Arc;
+
 fn install_panic_hook() {
     let original_panic_hook = std::panic::take_hook();
 
@@ -17,14 +38,20 @@ fn install_panic_hook() {
 fn build_route_configuration(
     config: &config::Configuration,
     environment: &environment::Environment,
+    metrics: &Arc<metrics::Metrics>,
+    memory_cache: &Arc<handlers::static_file::StaticFileMemoryCache>,
+    background_tasks: &mut Vec<server::BackgroundTask>,
+    log_level_control: logging::LogLevelControl,
+    authenticator: &Option<auth::Authenticator>,
 ) -> Result<server::RouteConfiguration, Box<std::error::Error>> {
     let mut path_to_handler = server::RouteConfigurationHandlerMap::new();
-
-    let index_handler = handlers::index::IndexHandler::new(config, environment)?;
-    path_to_handler.insert("/".to_string(), Box::new(index_handler));
+    let mut prefix_to_handler = server::RouteConfigurationPrefixHandlerList::new();
 
     for command_info in config.commands() {
-        let api_handler = handlers::command::api::APIHandler::new(command_info.clone());
+        let api_handler = handlers::command::api::APIHandler::new(command_info.clone(), metrics)?;
+        if let Some(metrics_task) = api_handler.metrics_task(metrics) {
+            background_tasks.push(metrics_task);
+        }
         path_to_handler.insert(command_info.api_path().clone(), Box::new(api_handler));
 
         let html_handler = handlers::command::html::HTMLHandler::new(command_info.clone())?;
@@ -39,59 +66,454 @@ fn build_route_configuration(
         path_to_handler.insert(proxy_info.html_path().clone(), Box::new(html_handler));
     }
 
+    let compressed_file_cache = Arc::new(handlers::static_file::CompressedFileCache::new());
+
     for static_path_info in config.static_paths() {
-        let handler = handlers::static_file::StaticFileHandler::new(
-            static_path_info.fs_path().clone(),
-            static_path_info.content_type(),
+        let content_type = match static_path_info.content_type() {
+            Some(content_type) => content_type.clone(),
+            None => utils::content_type_for_path(static_path_info.fs_path()),
+        };
+
+        let options = handlers::static_file::StaticFileOptions::new(
+            Some(&content_type),
             static_path_info.cache_control(),
+            static_path_info.etag(),
+            static_path_info.precompressed(),
+            if static_path_info.compress() {
+                Some(Arc::clone(&compressed_file_cache))
+            } else {
+                None
+            },
         )?;
+
+        let handler = handlers::static_file::StaticFileHandler::new(
+            static_path_info.fs_path().clone(),
+            options,
+            if static_path_info.cache_in_memory() {
+                Some(memory_cache)
+            } else {
+                None
+            },
+        );
         path_to_handler.insert(static_path_info.http_path().clone(), Box::new(handler));
     }
 
-    let config_handler = Box::new(handlers::config::ConfigHandler::new(config));
+    for asset in served_assets(config) {
+        let handler = handlers::asset::AssetHandler::new(asset, &compressed_file_cache)?;
+        path_to_handler.insert(asset.http_path().to_string(), Box::new(handler));
+    }
+
+    for static_directory_info in config.static_directories() {
+        let handler = handlers::static_directory::StaticDirectoryHandler::new(
+            static_directory_info,
+            &compressed_file_cache,
+        )?;
+        prefix_to_handler.push((static_directory_info.http_path().clone(), Box::new(handler)));
+    }
+
+    if !memory_cache.is_empty() {
+        let static_cache_handler = Box::new(handlers::static_cache::StaticCacheHandler::new(
+            Arc::clone(memory_cache),
+        ));
+        path_to_handler.insert("/static_cache".to_string(), static_cache_handler);
+    }
+
+    path_to_handler.insert(
+        "/metrics".to_string(),
+        Box::new(handlers::metrics::MetricsHandler),
+    );
+
+    // Changing log levels affects the whole server, so the route is only
+    // added when a policy requires authentication for it.
+    let log_level_protected = authenticator.as_ref().is_some_and(|authenticator| {
+        authenticator.requires_authentication(handlers::log_level::LOG_LEVEL_PATH)
+    });
+    if log_level_protected {
+        let log_level_handler =
+            Box::new(handlers::log_level::LogLevelHandler::new(log_level_control));
+        path_to_handler.insert(
+            handlers::log_level::LOG_LEVEL_PATH.to_string(),
+            log_level_handler,
+        );
+    } else {
+        info!(
+            "not serving {} since authentication is not required for it",
+            handlers::log_level::LOG_LEVEL_PATH
+        );
+    }
+
+    if let Some(authenticator) = authenticator {
+        if let Some(login_handler) = handlers::login::LoginHandler::new(authenticator.clone()) {
+            path_to_handler.insert(auth::LOGIN_PATH.to_string(), Box::new(login_handler));
+        }
+        if let Some(logout_handler) = handlers::login::LogoutHandler::new(authenticator) {
+            path_to_handler.insert(auth::LOGOUT_PATH.to_string(), Box::new(logout_handler));
+        }
+    }
+
+    let redactor = redaction::Redactor::new(config.redaction());
+
+    let config_handler = Box::new(handlers::config::ConfigHandler::new(config, &redactor));
     path_to_handler.insert("/configuration".to_string(), config_handler);
 
-    let environment_handler = Box::new(handlers::environment::EnvironmentHandler::new(environment));
+    let environment_handler = Box::new(handlers::environment::EnvironmentHandler::new(
+        environment,
+        &redactor,
+    ));
     path_to_handler.insert("/environment".to_string(), environment_handler);
 
+    // built last so it only links to routes that exist
+    let index_handler = handlers::index::IndexHandler::new(config, environment, &path_to_handler)?;
+    path_to_handler.insert("/".to_string(), Box::new(index_handler));
+
     let not_found_handler = handlers::not_found::NotFoundHandler;
 
     Ok(server::RouteConfiguration::new(
         path_to_handler,
+        prefix_to_handler,
         Box::new(not_found_handler),
     ))
 }
 
 fn build_server_configuration(
     config: &config::Configuration,
+    metrics: Arc<metrics::Metrics>,
+    mut background_tasks: Vec<server::BackgroundTask>,
+    authenticator: Option<auth::Authenticator>,
 ) -> Result<server::ServerConfiguration, Box<std::error::Error>> {
     let listen_addr = config.server_info().listen_address().parse()?;
 
+    let ip_access_control = match config.ip_access() {
+        Some(ip_access_info) => Some(ip_access::IpAccessControl::new(ip_access_info)?),
+        None => None,
+    };
+
+    let security_headers = security_headers::SecurityHeaders::new(config.security_headers())?;
+
+    let cors = match config.cors() {
+        Some(cors_info) => Some(cors::Cors::new(cors_info)?),
+        None => None,
+    };
+
+    let rate_limiter = match config.rate_limiting() {
+        Some(rate_limiting_info) => {
+            let rate_limiter = rate_limit::RateLimiter::new(rate_limiting_info)?;
+            background_tasks.push(rate_limiter.prune_task());
+            Some(rate_limiter)
+        }
+        None => None,
+    };
+
+    let tracer = match config.tracing() {
+        Some(tracing_info) => {
+            let tracer = trace::Tracer::new(tracing_info)?;
+            background_tasks.push(tracer.export_task());
+            Some(tracer)
+        }
+        None => None,
+    };
+
     Ok(server::ServerConfiguration::new(
         listen_addr,
         config.server_info().tcp_nodelay(),
+        metrics,
+        background_tasks,
+        tracer,
+        server::RequestPipeline {
+            compression_info: config.server_info().compression().clone(),
+            access_logger: None,
+            authenticator,
+            ip_access_control,
+            rate_limiter,
+            security_headers,
+            cors,
+        },
     ))
 }
 
-fn main() {
-    install_panic_hook();
+struct Configurations {
+    route_configuration: server::RouteConfiguration,
+    server_configuration: server::ServerConfiguration,
+    memory_cache: Arc<handlers::static_file::StaticFileMemoryCache>,
+}
+
+// Built-in assets not replaced by a static_paths entry.
+fn served_assets(config: &config::Configuration) -> Vec<&'static assets::Asset> {
+    assets::ASSETS
+        .iter()
+        .filter(|asset| {
+            !config
+                .static_paths()
+                .iter()
+                .any(|static_path_info| static_path_info.http_path() == asset.http_path())
+        })
+        .collect()
+}
+
+// Everything serve builds before it starts the server.  Files are only
+// read, not created, and no threads are started, so check and routes can
+// use it too; serve does the rest in start_io.
+fn build_configurations(
+    config: &config::Configuration,
+    log_level_control: logging::LogLevelControl,
+) -> Result<Configurations, Box<std::error::Error>> {
+    let environment = environment::get_environment();
 
-    logging::initialize_logging().expect("failed to initialize logging");
+    let metrics = Arc::new(metrics::Metrics::new());
 
-    let config_file = std::env::args()
-        .nth(1)
-        .expect("config file required as command line argument");
+    let memory_cache = Arc::new(handlers::static_file::StaticFileMemoryCache::new());
 
-    let config = config::read_config(config_file).expect("error reading configuration file");
+    let mut background_tasks = Vec::new();
 
-    let environment = environment::get_environment().expect("error getting environment");
+    let authenticator = match config.authentication() {
+        Some(authentication_info) => Some(
+            auth::Authenticator::new(
+                authentication_info,
+                served_assets(config)
+                    .iter()
+                    .map(|asset| asset.http_path().to_string())
+                    .collect(),
+            )
+            .map_err(|e| format!("failed to build authenticator: {}", e))?,
+        ),
+        None => None,
+    };
 
-    let route_configuration = build_route_configuration(&config, &environment)
-        .expect("failed to build route_configuration");
+    let route_configuration = build_route_configuration(
+        config,
+        &environment,
+        &metrics,
+        &memory_cache,
+        &mut background_tasks,
+        log_level_control,
+        &authenticator,
+    )
+    .map_err(|e| format!("failed to build route_configuration: {}", e))?;
 
     let server_configuration =
-        build_server_configuration(&config).expect("failed to build server_configuration");
+        build_server_configuration(config, metrics, background_tasks, authenticator)
+            .map_err(|e| format!("failed to build server_configuration: {}", e))?;
 
-    server::run_forever(server_configuration, route_configuration)
-        .expect("server::run_forever failed");
+    Ok(Configurations {
+        route_configuration,
+        server_configuration,
+        memory_cache,
+    })
+}
+
+// Opens the access log and starts watching cached static files.
+fn start_io(
+    config: &config::Configuration,
+    configurations: &mut Configurations,
+) -> Result<(), Box<std::error::Error>> {
+    if let Some(access_log_info) = config.server_info().access_log() {
+        let access_logger = access_log::AccessLogger::new(access_log_info)
+            .map_err(|e| format!("failed to open access log: {}", e))?;
+        configurations
+            .server_configuration
+            .set_access_logger(access_logger);
+    }
+
+    if !configurations.memory_cache.is_empty() {
+        configurations
+            .memory_cache
+            .start_watching()
+            .map_err(|e| format!("failed to watch cached static files: {}", e))?;
+    }
+
+    Ok(())
+}
+
+fn read_config(config_file: &str) -> Result<config::Configuration, Box<std::error::Error>> {
+    config::read_config(config_file.to_string())
+        .map_err(|e| format!("error reading configuration file {}: {}", config_file, e).into())
+}
+
+fn warn_missing_targets(config: &config::Configuration) {
+    for missing_target in config::missing_targets(config) {
+        warn!("{}", missing_target);
+    }
+}
+
+fn serve(config_file: &str) -> Result<(), Box<std::error::Error>> {
+    let config = read_config(config_file)?;
+
+    let log_level_control = logging::initialize_logging(config.logging())
+        .map_err(|e| format!("failed to initialize logging: {}", e))?;
+
+    info!("read configuration from {}", config_file);
+
+    warn_missing_targets(&config);
+
+    let mut configurations = build_configurations(&config, log_level_control)?;
+
+    start_io(&config, &mut configurations)?;
+
+    server::run_forever(
+        configurations.server_configuration,
+        configurations.route_configuration,
+    )
+}
+
+fn check(config_file: &str) -> Result<(), Box<std::error::Error>> {
+    let config = read_config(config_file)?;
+
+    let missing_targets = config::missing_targets(&config);
+    if !missing_targets.is_empty() {
+        return Err(format!(
+            "missing targets in {}:\n  {}",
+            config_file,
+            missing_targets.join("\n  ")
+        )
+        .into());
+    }
+
+    build_configurations(&config, logging::initialize_stderr_logging()?)?;
+
+    println!("{} is valid", config_file);
+
+    Ok(())
+}
+
+fn print_routes(config_file: &str) -> Result<(), Box<std::error::Error>> {
+    let config = read_config(config_file)?;
+
+    let log_level_control = logging::initialize_stderr_logging()?;
+
+    warn_missing_targets(&config);
+
+    let route_configuration = build_configurations(&config, log_level_control)?.route_configuration;
+
+    let mut routes: Vec<(String, &server::RouteConfigurationHandler)> = route_configuration
+        .path_to_handler()
+        .iter()
+        .map(|(path, handler)| (path.clone(), handler))
+        .collect();
+    routes.sort_by(|a, b| a.0.cmp(&b.0));
+
+    // prefixes are listed in the order they are matched
+    for (prefix, handler) in route_configuration.prefix_to_handler() {
+        routes.push((format!("{}/*", prefix.trim_end_matches('/')), handler));
+    }
+
+    routes.push((
+        "(not found)".to_string(),
+        route_configuration.not_found_handler(),
+    ));
+
+    let crate_prefix = format!("{}::", module_path!());
+    let width = routes
+        .iter()
+        .map(|(route, _)| route.len())
+        .max()
+        .unwrap_or(0);
+
+    for (route, handler) in routes {
+        println!(
+            "{:width$}  {}",
+            route,
+            handler
+                .handler_type()
+                .trim_start_matches(crate_prefix.as_str()),
+            width = width
+        );
+    }
+
+    Ok(())
+}
+
+// command_id is the command's api_path or the last segment of it.
+fn run_command(config_file: &str, command_id: &str) -> Result<(), Box<std::error::Error>> {
+    let config = read_config(config_file)?;
+
+    logging::initialize_stderr_logging()?;
+
+    warn_missing_targets(&config);
+
+    let command_info = config
+        .commands()
+        .iter()
+        .find(|command_info| {
+            command_info.api_path() == command_id
+                || command_info.api_path().rsplit('/').next() == Some(command_id)
+        })
+        .ok_or_else(|| format!("no command '{}' in {}", command_id, config_file))?;
+
+    let metrics = Arc::new(metrics::Metrics::new());
+
+    let api_handler = handlers::command::api::APIHandler::new(command_info.clone(), &metrics)?;
+
+    let (output, sandbox_status) = api_handler.run_once(&metrics).wait()?;
+
+    if let Some(sandbox_status) = sandbox_status {
+        eprintln!("sandbox {}", sandbox_status);
+    }
+
+    print!("{}", output);
+
+    Ok(())
+}
+
+fn build_cli() -> clap::App<'static, 'static> {
+    let config_arg = || {
+        clap::Arg::with_name("config")
+            .help("configuration file")
+            .required(true)
+    };
+
+    clap::App::new("rust_hyper")
+        .version(concat!(
+            env!("CARGO_PKG_VERSION"),
+            " (",
+            env!("GIT_HASH"),
+            ")"
+        ))
+        .setting(clap::AppSettings::SubcommandRequiredElseHelp)
+        .subcommand(
+            clap::SubCommand::with_name("serve")
+                .about("Runs the server")
+                .arg(config_arg()),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("check")
+                .about("Validates the configuration and exits")
+                .arg(config_arg()),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("routes")
+                .about("Prints the route table with handler types")
+                .arg(config_arg()),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("run-command")
+                .about("Runs a configured command once")
+                .arg(config_arg())
+                .arg(
+                    clap::Arg::with_name("id")
+                        .help("api_path of the command, or its last path segment")
+                        .required(true),
+                ),
+        )
+}
+
+fn main() {
+    install_panic_hook();
+
+    let matches = build_cli().get_matches();
+
+    let result = match matches.subcommand() {
+        ("serve", Some(args)) => serve(args.value_of("config").unwrap()),
+        ("check", Some(args)) => check(args.value_of("config").unwrap()),
+        ("routes", Some(args)) => print_routes(args.value_of("config").unwrap()),
+        ("run-command", Some(args)) => run_command(
+            args.value_of("config").unwrap(),
+            args.value_of("id").unwrap(),
+        ),
+        _ => unreachable!("a subcommand is required"),
+    };
+
+    if let Err(e) = result {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
 }
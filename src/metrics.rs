@@ -0,0 +1,704 @@
+use log::warn;
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// Upper bounds in seconds, shared by all latency histograms.
+const LATENCY_BUCKETS: [f64; 13] = [
+    0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+struct Histogram {
+    bucket_counts: [u64; LATENCY_BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn new() -> Self {
+        Histogram {
+            bucket_counts: [0; LATENCY_BUCKETS.len()],
+            sum: 0.0,
+            count: 0,
+        }
+    }
+
+    fn observe(&mut self, duration: Duration) {
+        let seconds = crate::utils::duration_in_seconds_f64(duration);
+
+        for (i, upper_bound) in LATENCY_BUCKETS.iter().enumerate() {
+            if seconds <= *upper_bound {
+                self.bucket_counts[i] += 1;
+            }
+        }
+
+        self.sum += seconds;
+        self.count += 1;
+    }
+
+    fn render(&self, output: &mut String, name: &str, labels: &str) {
+        for (upper_bound, bucket_count) in LATENCY_BUCKETS.iter().zip(self.bucket_counts.iter()) {
+            writeln!(
+                output,
+                "{}_bucket{{{},le=\"{}\"}} {}",
+                name, labels, upper_bound, bucket_count
+            )
+            .unwrap();
+        }
+        writeln!(
+            output,
+            "{}_bucket{{{},le=\"+Inf\"}} {}",
+            name, labels, self.count
+        )
+        .unwrap();
+        writeln!(output, "{}_sum{{{}}} {}", name, labels, self.sum).unwrap();
+        writeln!(output, "{}_count{{{}}} {}", name, labels, self.count).unwrap();
+    }
+}
+
+// Escapes a label value as required by the Prometheus text format.
+fn escape_label_value(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+// Names written by the metrics below, which command output metrics may not
+// reuse.  Histograms also reserve their _bucket, _sum and _count series.
+const BUILT_IN_METRIC_NAMES: [&str; 14] = [
+    "http_requests_total",
+    "http_request_duration_seconds",
+    "http_requests_in_flight",
+    "http_open_connections",
+    "command_executions_total",
+    "command_duration_seconds",
+    "proxy_upstream_errors_total",
+    "proxy_upstream_duration_seconds",
+    "process_start_time_seconds",
+    "process_cpu_seconds_total",
+    "process_resident_memory_bytes",
+    "process_virtual_memory_bytes",
+    "process_threads",
+    "process_open_fds",
+];
+
+fn is_built_in_metric_name(name: &str) -> bool {
+    BUILT_IN_METRIC_NAMES.iter().any(|built_in_name| {
+        name == *built_in_name
+            || ["_bucket", "_sum", "_count"]
+                .iter()
+                .any(|suffix| name == format!("{}{}", built_in_name, suffix))
+    })
+}
+
+fn write_help_and_type(output: &mut String, name: &str, help: &str, metric_type: &str) {
+    writeln!(output, "# HELP {} {}", name, help).unwrap();
+    writeln!(output, "# TYPE {} {}", name, metric_type).unwrap();
+}
+
+#[derive(Default)]
+struct CommandMetrics {
+    durations: Option<Histogram>,
+    exit_codes: BTreeMap<Option<i32>, u64>,
+}
+
+#[derive(Default)]
+struct ProxyMetrics {
+    durations: Option<Histogram>,
+    errors: u64,
+}
+
+// Values extracted from command output, keyed by rendered label set.  Counter
+// values are exported as reported by the command.
+struct CommandOutputMetric {
+    command: String,
+    help: String,
+    metric_type: crate::config::CommandMetricType,
+    values: BTreeMap<String, f64>,
+}
+
+pub struct CommandOutputSample {
+    name: String,
+    labels: Vec<(String, String)>,
+    value: f64,
+}
+
+impl CommandOutputSample {
+    pub fn new(name: String, labels: Vec<(String, String)>, value: f64) -> Self {
+        CommandOutputSample {
+            name,
+            labels,
+            value,
+        }
+    }
+}
+
+pub struct Metrics {
+    start_time: SystemTime,
+    in_flight_requests: AtomicI64,
+    open_connections: AtomicI64,
+    requests: Mutex<BTreeMap<(String, u16), Histogram>>,
+    commands: Mutex<BTreeMap<String, CommandMetrics>>,
+    proxies: Mutex<BTreeMap<String, ProxyMetrics>>,
+    command_outputs: Mutex<BTreeMap<String, CommandOutputMetric>>,
+    system_units: SystemUnits,
+}
+
+// Decrements the gauge it was created from when dropped, so requests and
+// connections that end early are still accounted for.
+pub struct GaugeGuard {
+    metrics: Arc<Metrics>,
+    connection: bool,
+}
+
+impl Drop for GaugeGuard {
+    fn drop(&mut self) {
+        if self.connection {
+            self.metrics
+                .open_connections
+                .fetch_sub(1, Ordering::Relaxed);
+        } else {
+            self.metrics
+                .in_flight_requests
+                .fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Metrics {
+            start_time: SystemTime::now(),
+            in_flight_requests: AtomicI64::new(0),
+            open_connections: AtomicI64::new(0),
+            requests: Mutex::new(BTreeMap::new()),
+            commands: Mutex::new(BTreeMap::new()),
+            proxies: Mutex::new(BTreeMap::new()),
+            command_outputs: Mutex::new(BTreeMap::new()),
+            system_units: SystemUnits::query(),
+        }
+    }
+
+    pub fn request_started(self: &Arc<Self>) -> GaugeGuard {
+        self.in_flight_requests.fetch_add(1, Ordering::Relaxed);
+        GaugeGuard {
+            metrics: Arc::clone(self),
+            connection: false,
+        }
+    }
+
+    pub fn connection_opened(self: &Arc<Self>) -> GaugeGuard {
+        self.open_connections.fetch_add(1, Ordering::Relaxed);
+        GaugeGuard {
+            metrics: Arc::clone(self),
+            connection: true,
+        }
+    }
+
+    pub fn observe_request(&self, route: &str, status: u16, duration: Duration) {
+        self.requests
+            .lock()
+            .unwrap()
+            .entry((route.to_string(), status))
+            .or_insert_with(Histogram::new)
+            .observe(duration);
+    }
+
+    // exit_code is None when the command could not be run or was killed by a
+    // signal.
+    pub fn observe_command(&self, command: &str, exit_code: Option<i32>, duration: Duration) {
+        let mut commands = self.commands.lock().unwrap();
+        let command_metrics = commands.entry(command.to_string()).or_default();
+
+        command_metrics
+            .durations
+            .get_or_insert_with(Histogram::new)
+            .observe(duration);
+
+        *command_metrics.exit_codes.entry(exit_code).or_insert(0) += 1;
+    }
+
+    pub fn observe_proxy(&self, proxy: &str, success: bool, duration: Duration) {
+        let mut proxies = self.proxies.lock().unwrap();
+        let proxy_metrics = proxies.entry(proxy.to_string()).or_default();
+
+        proxy_metrics
+            .durations
+            .get_or_insert_with(Histogram::new)
+            .observe(duration);
+
+        if !success {
+            proxy_metrics.errors += 1;
+        }
+    }
+
+    // Reserves name for the output of command (its api path).  Each name
+    // may only be exported with one HELP and TYPE, so names of built-in
+    // metrics or of another command's metrics are rejected.
+    pub fn register_command_output(
+        &self,
+        command: &str,
+        name: &str,
+        help: &str,
+        metric_type: crate::config::CommandMetricType,
+    ) -> Result<(), String> {
+        if is_built_in_metric_name(name) {
+            return Err(format!(
+                "metric name '{}' is used by a built-in metric",
+                name
+            ));
+        }
+
+        let mut command_outputs = self.command_outputs.lock().unwrap();
+
+        match command_outputs.get(name) {
+            Some(existing) if existing.command != command => Err(format!(
+                "metric name '{}' is already used by {}",
+                name, existing.command
+            )),
+            Some(existing) if existing.metric_type != metric_type => Err(format!(
+                "metric '{}' is registered with different types",
+                name
+            )),
+            Some(_) => Ok(()),
+            None => {
+                command_outputs.insert(
+                    name.to_string(),
+                    CommandOutputMetric {
+                        command: command.to_string(),
+                        help: help.to_string(),
+                        metric_type,
+                        values: BTreeMap::new(),
+                    },
+                );
+                Ok(())
+            }
+        }
+    }
+
+    // Replaces every value of command's metrics, so series its latest output
+    // no longer produces are dropped rather than left stale.
+    pub fn set_command_outputs(&self, command: &str, samples: Vec<CommandOutputSample>) {
+        let mut command_outputs = self.command_outputs.lock().unwrap();
+
+        for command_output_metric in command_outputs.values_mut() {
+            if command_output_metric.command == command {
+                command_output_metric.values.clear();
+            }
+        }
+
+        for sample in samples {
+            let rendered_labels = sample
+                .labels
+                .iter()
+                .map(|(label_name, label_value)| {
+                    format!("{}=\"{}\"", label_name, escape_label_value(label_value))
+                })
+                .collect::<Vec<_>>()
+                .join(",");
+
+            match command_outputs.get_mut(&sample.name) {
+                Some(command_output_metric) if command_output_metric.command == command => {
+                    command_output_metric
+                        .values
+                        .insert(rendered_labels, sample.value);
+                }
+                _ => warn!("metric {} is not registered for {}", sample.name, command),
+            }
+        }
+    }
+
+    fn render_requests(&self, output: &mut String) {
+        let requests = self.requests.lock().unwrap();
+
+        write_help_and_type(
+            output,
+            "http_requests_total",
+            "Total HTTP requests by route and status.",
+            "counter",
+        );
+        for ((route, status), histogram) in requests.iter() {
+            writeln!(
+                output,
+                "http_requests_total{{route=\"{}\",status=\"{}\"}} {}",
+                escape_label_value(route),
+                status,
+                histogram.count
+            )
+            .unwrap();
+        }
+
+        write_help_and_type(
+            output,
+            "http_request_duration_seconds",
+            "HTTP request latency by route and status.",
+            "histogram",
+        );
+        for ((route, status), histogram) in requests.iter() {
+            histogram.render(
+                output,
+                "http_request_duration_seconds",
+                &format!(
+                    "route=\"{}\",status=\"{}\"",
+                    escape_label_value(route),
+                    status
+                ),
+            );
+        }
+
+        write_help_and_type(
+            output,
+            "http_requests_in_flight",
+            "HTTP requests currently being handled.",
+            "gauge",
+        );
+        writeln!(
+            output,
+            "http_requests_in_flight {}",
+            self.in_flight_requests.load(Ordering::Relaxed)
+        )
+        .unwrap();
+
+        write_help_and_type(
+            output,
+            "http_open_connections",
+            "Open client connections.",
+            "gauge",
+        );
+        writeln!(
+            output,
+            "http_open_connections {}",
+            self.open_connections.load(Ordering::Relaxed)
+        )
+        .unwrap();
+    }
+
+    fn render_commands(&self, output: &mut String) {
+        let commands = self.commands.lock().unwrap();
+
+        write_help_and_type(
+            output,
+            "command_executions_total",
+            "Command executions by command and exit code.",
+            "counter",
+        );
+        for (command, command_metrics) in commands.iter() {
+            for (exit_code, count) in &command_metrics.exit_codes {
+                writeln!(
+                    output,
+                    "command_executions_total{{command=\"{}\",exit_code=\"{}\"}} {}",
+                    escape_label_value(command),
+                    exit_code.map_or_else(|| "none".to_string(), |c| c.to_string()),
+                    count
+                )
+                .unwrap();
+            }
+        }
+
+        write_help_and_type(
+            output,
+            "command_duration_seconds",
+            "Command execution time.",
+            "histogram",
+        );
+        for (command, command_metrics) in commands.iter() {
+            if let Some(durations) = &command_metrics.durations {
+                durations.render(
+                    output,
+                    "command_duration_seconds",
+                    &format!("command=\"{}\"", escape_label_value(command)),
+                );
+            }
+        }
+    }
+
+    fn render_proxies(&self, output: &mut String) {
+        let proxies = self.proxies.lock().unwrap();
+
+        write_help_and_type(
+            output,
+            "proxy_upstream_errors_total",
+            "Failed upstream proxy requests.",
+            "counter",
+        );
+        for (proxy, proxy_metrics) in proxies.iter() {
+            writeln!(
+                output,
+                "proxy_upstream_errors_total{{proxy=\"{}\"}} {}",
+                escape_label_value(proxy),
+                proxy_metrics.errors
+            )
+            .unwrap();
+        }
+
+        write_help_and_type(
+            output,
+            "proxy_upstream_duration_seconds",
+            "Upstream proxy request latency.",
+            "histogram",
+        );
+        for (proxy, proxy_metrics) in proxies.iter() {
+            if let Some(durations) = &proxy_metrics.durations {
+                durations.render(
+                    output,
+                    "proxy_upstream_duration_seconds",
+                    &format!("proxy=\"{}\"", escape_label_value(proxy)),
+                );
+            }
+        }
+    }
+
+    fn render_command_outputs(&self, output: &mut String) {
+        let command_outputs = self.command_outputs.lock().unwrap();
+
+        for (name, command_output_metric) in command_outputs.iter() {
+            if command_output_metric.values.is_empty() {
+                continue;
+            }
+
+            let metric_type = match command_output_metric.metric_type {
+                crate::config::CommandMetricType::Gauge => "gauge",
+                crate::config::CommandMetricType::Counter => "counter",
+            };
+
+            write_help_and_type(output, name, &command_output_metric.help, metric_type);
+
+            for (rendered_labels, value) in &command_output_metric.values {
+                if rendered_labels.is_empty() {
+                    writeln!(output, "{} {}", name, value).unwrap();
+                } else {
+                    writeln!(output, "{}{{{}}} {}", name, rendered_labels, value).unwrap();
+                }
+            }
+        }
+    }
+
+    fn render_process(&self, output: &mut String) {
+        let process_stats = read_process_stats(&self.system_units);
+
+        let start_time_seconds = match self.start_time.duration_since(UNIX_EPOCH) {
+            Ok(duration) => crate::utils::duration_in_seconds_f64(duration),
+            Err(_) => 0.0,
+        };
+
+        write_help_and_type(
+            output,
+            "process_start_time_seconds",
+            "Start time of the process since unix epoch in seconds.",
+            "gauge",
+        );
+        writeln!(output, "process_start_time_seconds {}", start_time_seconds).unwrap();
+
+        if let Some(cpu_seconds) = process_stats.cpu_seconds {
+            write_help_and_type(
+                output,
+                "process_cpu_seconds_total",
+                "Total user and system CPU time spent in seconds.",
+                "counter",
+            );
+            writeln!(output, "process_cpu_seconds_total {}", cpu_seconds).unwrap();
+        }
+
+        if let Some(resident_memory_bytes) = process_stats.resident_memory_bytes {
+            write_help_and_type(
+                output,
+                "process_resident_memory_bytes",
+                "Resident memory size in bytes.",
+                "gauge",
+            );
+            writeln!(
+                output,
+                "process_resident_memory_bytes {}",
+                resident_memory_bytes
+            )
+            .unwrap();
+        }
+
+        if let Some(virtual_memory_bytes) = process_stats.virtual_memory_bytes {
+            write_help_and_type(
+                output,
+                "process_virtual_memory_bytes",
+                "Virtual memory size in bytes.",
+                "gauge",
+            );
+            writeln!(
+                output,
+                "process_virtual_memory_bytes {}",
+                virtual_memory_bytes
+            )
+            .unwrap();
+        }
+
+        if let Some(threads) = process_stats.threads {
+            write_help_and_type(
+                output,
+                "process_threads",
+                "Number of OS threads in the process.",
+                "gauge",
+            );
+            writeln!(output, "process_threads {}", threads).unwrap();
+        }
+
+        if let Some(open_fds) = process_stats.open_fds {
+            write_help_and_type(
+                output,
+                "process_open_fds",
+                "Number of open file descriptors.",
+                "gauge",
+            );
+            writeln!(output, "process_open_fds {}", open_fds).unwrap();
+        }
+    }
+
+    pub fn render(&self) -> String {
+        let mut output = String::new();
+
+        self.render_requests(&mut output);
+        self.render_commands(&mut output);
+        self.render_proxies(&mut output);
+        self.render_command_outputs(&mut output);
+        self.render_process(&mut output);
+
+        output
+    }
+}
+
+#[derive(Default)]
+struct ProcessStats {
+    cpu_seconds: Option<f64>,
+    resident_memory_bytes: Option<u64>,
+    virtual_memory_bytes: Option<u64>,
+    threads: Option<u64>,
+    open_fds: Option<usize>,
+}
+
+// Units of the values in /proc/self/stat, queried once since they can't
+// change while the process runs.  Page size is 16 KiB on some arm64 kernels.
+struct SystemUnits {
+    clock_ticks_per_second: f64,
+    page_size: u64,
+}
+
+impl SystemUnits {
+    fn query() -> Self {
+        let sysconf = |name, default| match unsafe { ::libc::sysconf(name) } {
+            value if value > 0 => value as u64,
+            _ => default,
+        };
+
+        SystemUnits {
+            clock_ticks_per_second: sysconf(::libc::_SC_CLK_TCK, 100) as f64,
+            page_size: sysconf(::libc::_SC_PAGESIZE, 4096),
+        }
+    }
+}
+
+// Process stats come from /proc, so are only available on Linux.
+fn read_process_stats(system_units: &SystemUnits) -> ProcessStats {
+    let mut process_stats = ProcessStats::default();
+
+    if let Ok(stat) = ::std::fs::read_to_string("/proc/self/stat") {
+        // the command name in field 2 may contain spaces, so split after it
+        if let Some(rest) = stat.rfind(')').map(|i| &stat[(i + 1)..]) {
+            let fields: Vec<&str> = rest.split_whitespace().collect();
+            // fields[0] is field 3 (state) in proc(5) numbering
+            let field = |n: usize| fields.get(n - 3).and_then(|f| f.parse::<u64>().ok());
+
+            if let (Some(utime), Some(stime)) = (field(14), field(15)) {
+                process_stats.cpu_seconds =
+                    Some((utime + stime) as f64 / system_units.clock_ticks_per_second);
+            }
+            process_stats.threads = field(20);
+            process_stats.virtual_memory_bytes = field(23);
+            process_stats.resident_memory_bytes =
+                field(24).map(|pages| pages * system_units.page_size);
+        }
+    }
+
+    if let Ok(read_dir) = ::std::fs::read_dir("/proc/self/fd") {
+        process_stats.open_fds = Some(read_dir.count());
+    }
+
+    process_stats
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::config::CommandMetricType;
+
+    fn sample(name: &str, value: f64) -> CommandOutputSample {
+        CommandOutputSample::new(name.to_string(), Vec::new(), value)
+    }
+
+    #[test]
+    fn system_units_come_from_sysconf() {
+        let system_units = SystemUnits::query();
+        assert!(system_units.clock_ticks_per_second > 0.0);
+        assert!(system_units.page_size >= 4096 && system_units.page_size.is_power_of_two());
+
+        let process_stats = read_process_stats(&system_units);
+        assert!(process_stats.resident_memory_bytes.unwrap() >= system_units.page_size);
+    }
+
+    #[test]
+    fn register_command_output_rejects_collisions() {
+        let metrics = Metrics::new();
+
+        assert!(metrics
+            .register_command_output("/a", "http_requests_total", "", CommandMetricType::Gauge)
+            .is_err());
+        assert!(metrics
+            .register_command_output(
+                "/a",
+                "command_duration_seconds_count",
+                "",
+                CommandMetricType::Gauge
+            )
+            .is_err());
+
+        assert!(metrics
+            .register_command_output("/a", "temperature", "", CommandMetricType::Gauge)
+            .is_ok());
+        assert!(metrics
+            .register_command_output("/a", "temperature", "", CommandMetricType::Gauge)
+            .is_ok());
+        assert!(metrics
+            .register_command_output("/a", "temperature", "", CommandMetricType::Counter)
+            .is_err());
+        assert!(metrics
+            .register_command_output("/b", "temperature", "", CommandMetricType::Gauge)
+            .is_err());
+    }
+
+    #[test]
+    fn set_command_outputs_replaces_previous_values() {
+        let metrics = Metrics::new();
+        metrics
+            .register_command_output("/a", "a_value", "A.", CommandMetricType::Gauge)
+            .unwrap();
+        metrics
+            .register_command_output("/b", "b_value", "B.", CommandMetricType::Gauge)
+            .unwrap();
+
+        metrics.set_command_outputs("/a", vec![sample("a_value", 1.0)]);
+        metrics.set_command_outputs("/b", vec![sample("b_value", 2.0)]);
+
+        let mut output = String::new();
+        metrics.render_command_outputs(&mut output);
+        assert!(output.contains("a_value 1\n"));
+        assert!(output.contains("b_value 2\n"));
+
+        metrics.set_command_outputs("/a", Vec::new());
+
+        let mut output = String::new();
+        metrics.render_command_outputs(&mut output);
+        assert!(!output.contains("a_value"));
+        assert!(output.contains("b_value 2\n"));
+    }
+}
@@ -0,0 +1,307 @@
+use futures::Stream;
+
+use hyper::header::{HeaderMap, HeaderValue, RETRY_AFTER};
+use hyper::{Body, Response, StatusCode};
+
+use log::{debug, warn};
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+pub const RATELIMIT_LIMIT: &str = "ratelimit-limit";
+pub const RATELIMIT_REMAINING: &str = "ratelimit-remaining";
+pub const RATELIMIT_RESET: &str = "ratelimit-reset";
+
+#[derive(Debug, Clone, Copy)]
+struct Limit {
+    requests_per_second: f64,
+    burst: f64,
+}
+
+impl Limit {
+    fn new(
+        rate_limit_info: &crate::config::RateLimitInfo,
+    ) -> Result<Self, Box<::std::error::Error>> {
+        let requests_per_second = rate_limit_info.requests_per_second();
+        if requests_per_second.is_nan()
+            || requests_per_second <= 0.0
+            || rate_limit_info.burst() == 0
+        {
+            return Err("rate limit requests_per_second and burst must be positive".into());
+        }
+
+        Ok(Limit {
+            requests_per_second,
+            burst: f64::from(rate_limit_info.burst()),
+        })
+    }
+
+    fn seconds_until(&self, tokens: f64, wanted_tokens: f64) -> u64 {
+        ((wanted_tokens - tokens).max(0.0) / self.requests_per_second).ceil() as u64
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn refill(&mut self, limit: &Limit, now: Instant) {
+        let elapsed = crate::utils::duration_in_seconds_f64(now.duration_since(self.updated));
+        self.tokens = (self.tokens + elapsed * limit.requests_per_second).min(limit.burst);
+        self.updated = now;
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Client {
+    User(String),
+    Ip(IpAddr),
+}
+
+// None for the global limit, or the index of the route limit.
+type BucketKey = (Option<usize>, Client);
+
+pub struct RateLimitStatus {
+    allowed: bool,
+    limit: u32,
+    remaining: u32,
+    reset_seconds: u64,
+    retry_after_seconds: u64,
+}
+
+impl RateLimitStatus {
+    pub fn allowed(&self) -> bool {
+        self.allowed
+    }
+
+    pub fn add_headers(&self, headers: &mut HeaderMap) {
+        headers.insert(RATELIMIT_LIMIT, HeaderValue::from(self.limit));
+        headers.insert(RATELIMIT_REMAINING, HeaderValue::from(self.remaining));
+        headers.insert(RATELIMIT_RESET, HeaderValue::from(self.reset_seconds));
+        if !self.allowed {
+            headers.insert(RETRY_AFTER, HeaderValue::from(self.retry_after_seconds));
+        }
+    }
+
+    pub fn too_many_requests_response(&self) -> Response<Body> {
+        let mut response = crate::server::build_response_status(StatusCode::TOO_MANY_REQUESTS);
+        self.add_headers(response.headers_mut());
+        response
+    }
+}
+
+struct InnerRateLimiter {
+    global_limit: Option<Limit>,
+    route_limits: crate::server::PrefixRules<Limit>,
+    prune_interval: Duration,
+    buckets: Mutex<HashMap<BucketKey, Bucket>>,
+}
+
+impl InnerRateLimiter {
+    fn limit(&self, index: Option<usize>) -> Option<Limit> {
+        match index {
+            None => self.global_limit,
+            Some(index) => self.route_limits.get(index).cloned(),
+        }
+    }
+
+    // Buckets that have refilled completely are the same as new ones, so
+    // they can be dropped.
+    fn prune(&self) {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        let buckets_before = buckets.len();
+
+        buckets.retain(|(index, _), bucket| match self.limit(*index) {
+            Some(limit) => {
+                bucket.refill(&limit, now);
+                bucket.tokens < limit.burst
+            }
+            None => false,
+        });
+
+        debug!(
+            "pruned {} of {} rate limit buckets",
+            buckets_before - buckets.len(),
+            buckets_before
+        );
+    }
+}
+
+#[derive(Clone)]
+pub struct RateLimiter {
+    inner: Arc<InnerRateLimiter>,
+}
+
+impl RateLimiter {
+    pub fn new(
+        rate_limiting_info: &crate::config::RateLimitingInfo,
+    ) -> Result<Self, Box<::std::error::Error>> {
+        if rate_limiting_info.prune_interval_seconds() == 0 {
+            return Err("rate limit prune_interval_seconds must be positive".into());
+        }
+
+        let global_limit = match rate_limiting_info.global() {
+            Some(rate_limit_info) => Some(Limit::new(rate_limit_info)?),
+            None => None,
+        };
+
+        let mut route_limits = Vec::with_capacity(rate_limiting_info.routes().len());
+        for route_info in rate_limiting_info.routes() {
+            route_limits.push((
+                route_info.path_prefix().clone(),
+                Limit::new(route_info.limit())?,
+            ));
+        }
+
+        Ok(RateLimiter {
+            inner: Arc::new(InnerRateLimiter {
+                global_limit,
+                route_limits: crate::server::PrefixRules::new(route_limits),
+                prune_interval: Duration::from_secs(rate_limiting_info.prune_interval_seconds()),
+                buckets: Mutex::new(HashMap::new()),
+            }),
+        })
+    }
+
+    // Takes a token from the global and route buckets if both have one.
+    // Returns None when no limit applies to path.
+    pub fn check(
+        &self,
+        principal: Option<&crate::auth::Principal>,
+        client_ip: IpAddr,
+        path: &str,
+    ) -> Option<RateLimitStatus> {
+        let client = match principal {
+            Some(principal) => Client::User(principal.name().clone()),
+            None => Client::Ip(client_ip),
+        };
+
+        self.check_client(client, path, true)
+    }
+
+    // Like check for an unauthenticated request, but without taking a
+    // token.  Run before authentication so a client ip that used up its
+    // tokens on failed attempts can't make the server verify more
+    // passwords.
+    pub fn peek_ip(&self, client_ip: IpAddr, path: &str) -> Option<RateLimitStatus> {
+        self.check_client(Client::Ip(client_ip), path, false)
+    }
+
+    fn check_client(
+        &self,
+        client: Client,
+        path: &str,
+        take_token: bool,
+    ) -> Option<RateLimitStatus> {
+        let route_limit = self.inner.route_limits.position(path).and_then(|index| {
+            let limit = self.inner.route_limits.get(index)?;
+            Some((Some(index), *limit))
+        });
+
+        let limits: Vec<(Option<usize>, Limit)> = self
+            .inner
+            .global_limit
+            .map(|limit| (None, limit))
+            .into_iter()
+            .chain(route_limit)
+            .collect();
+
+        if limits.is_empty() {
+            return None;
+        }
+
+        let now = Instant::now();
+        let mut buckets = self.inner.buckets.lock().unwrap();
+
+        let mut tokens = Vec::with_capacity(limits.len());
+        for (index, limit) in &limits {
+            let bucket = buckets
+                .entry((*index, client.clone()))
+                .or_insert_with(|| Bucket {
+                    tokens: limit.burst,
+                    updated: now,
+                });
+            bucket.refill(limit, now);
+            tokens.push(bucket.tokens);
+        }
+
+        let allowed = tokens.iter().all(|tokens| *tokens >= 1.0);
+        if allowed && take_token {
+            for ((index, _), tokens) in limits.iter().zip(tokens.iter_mut()) {
+                if let Some(bucket) = buckets.get_mut(&(*index, client.clone())) {
+                    bucket.tokens -= 1.0;
+                }
+                *tokens -= 1.0;
+            }
+        }
+
+        drop(buckets);
+
+        // report the limit closest to running out
+        let (limit, remaining_tokens) = limits
+            .iter()
+            .map(|(_, limit)| limit)
+            .zip(tokens.iter().cloned())
+            .min_by(|a, b| a.1.partial_cmp(&b.1).unwrap_or(::std::cmp::Ordering::Equal))
+            .unwrap();
+
+        let retry_after_seconds = limits
+            .iter()
+            .zip(tokens.iter())
+            .map(|((_, limit), tokens)| limit.seconds_until(*tokens, 1.0))
+            .max()
+            .unwrap_or(0)
+            .max(1);
+
+        Some(RateLimitStatus {
+            allowed,
+            limit: limit.burst as u32,
+            remaining: remaining_tokens.floor() as u32,
+            reset_seconds: limit.seconds_until(remaining_tokens, limit.burst),
+            retry_after_seconds,
+        })
+    }
+
+    pub fn prune_task(&self) -> crate::server::BackgroundTask {
+        let inner_clone = Arc::clone(&self.inner);
+
+        Box::new(
+            ::tokio_timer::Interval::new_interval(inner_clone.prune_interval)
+                .map_err(|e| warn!("rate limit prune interval error: {}", e))
+                .for_each(move |_| {
+                    inner_clone.prune();
+                    Ok(())
+                }),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn peek_ip_does_not_take_tokens() {
+        let rate_limiting_info: crate::config::RateLimitingInfo =
+            ::serde_json::from_str(r#"{"global": {"requests_per_second": 0.001, "burst": 2}}"#)
+                .unwrap();
+        let rate_limiter = RateLimiter::new(&rate_limiting_info).unwrap();
+        let client_ip: IpAddr = "192.0.2.1".parse().unwrap();
+
+        for _ in 0..3 {
+            assert!(rate_limiter.peek_ip(client_ip, "/").unwrap().allowed());
+        }
+
+        assert!(rate_limiter.check(None, client_ip, "/").unwrap().allowed());
+        assert!(rate_limiter.check(None, client_ip, "/").unwrap().allowed());
+        assert!(!rate_limiter.peek_ip(client_ip, "/").unwrap().allowed());
+
+        let other_ip: IpAddr = "192.0.2.2".parse().unwrap();
+        assert!(rate_limiter.peek_ip(other_ip, "/").unwrap().allowed());
+    }
+}
@@ -0,0 +1,231 @@
+use std::collections::HashSet;
+use std::fmt::Write;
+
+pub const REDACTED: &str = "<redacted>";
+
+// Case insensitive match where * matches any run of characters.
+fn glob_matches(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.to_uppercase().chars().collect();
+    let text: Vec<char> = text.to_uppercase().chars().collect();
+
+    let mut pattern_index = 0;
+    let mut text_index = 0;
+    // position of the last * and the text index it is currently matched to
+    let mut backtrack = None;
+
+    while text_index < text.len() {
+        if pattern_index < pattern.len() && pattern[pattern_index] == '*' {
+            backtrack = Some((pattern_index, text_index));
+            pattern_index += 1;
+        } else if pattern_index < pattern.len() && pattern[pattern_index] == text[text_index] {
+            pattern_index += 1;
+            text_index += 1;
+        } else if let Some((star_index, star_text_index)) = backtrack {
+            backtrack = Some((star_index, star_text_index + 1));
+            pattern_index = star_index + 1;
+            text_index = star_text_index + 1;
+        } else {
+            return false;
+        }
+    }
+
+    pattern[pattern_index..].iter().all(|c| *c == '*')
+}
+
+// Splits a `key: value` or `"key": value` line into the key, the key as
+// written, and the value.
+fn split_debug_field(line: &str) -> Option<(&str, &str, &str)> {
+    let key_end = match line.strip_prefix('"') {
+        Some(quoted) => quoted.find('"')? + 2,
+        None => line.find(|c: char| !(c.is_alphanumeric() || c == '_'))?,
+    };
+
+    let (written_key, rest) = line.split_at(key_end);
+    if written_key.is_empty() || !rest.starts_with(": ") {
+        return None;
+    }
+
+    Some((written_key.trim_matches('"'), written_key, &rest[2..]))
+}
+
+fn indent_length(line: &str) -> usize {
+    line.len() - line.trim_start().len()
+}
+
+pub struct Redactor {
+    patterns: Vec<String>,
+    allow: HashSet<String>,
+    deny: HashSet<String>,
+}
+
+impl Redactor {
+    pub fn new(redaction_info: &crate::config::RedactionInfo) -> Self {
+        Redactor {
+            patterns: redaction_info.patterns().clone(),
+            allow: redaction_info
+                .allow()
+                .iter()
+                .map(|key| key.to_uppercase())
+                .collect(),
+            deny: redaction_info
+                .deny()
+                .iter()
+                .map(|key| key.to_uppercase())
+                .collect(),
+        }
+    }
+
+    pub fn is_secret(&self, key: &str) -> bool {
+        let key = key.to_uppercase();
+
+        if self.allow.contains(&key) {
+            return false;
+        }
+
+        self.deny.contains(&key)
+            || self
+                .patterns
+                .iter()
+                .any(|pattern| glob_matches(pattern, &key))
+    }
+
+    // Works on pretty Debug ({:#?}) output, where every struct field and map
+    // entry starts its own line.  A multi-line value ends at the next line
+    // with the same indentation as its key.
+    pub fn redact_debug(&self, debug_string: &str) -> String {
+        let mut redacted = String::with_capacity(debug_string.len());
+        let mut lines = debug_string.lines();
+
+        while let Some(line) = lines.next() {
+            let indent = indent_length(line);
+
+            match split_debug_field(&line[indent..]) {
+                Some((key, written_key, value)) if self.is_secret(key) => {
+                    if value.ends_with('{') || value.ends_with('[') || value.ends_with('(') {
+                        for value_line in lines.by_ref() {
+                            if indent_length(value_line) == indent {
+                                break;
+                            }
+                        }
+                    }
+
+                    let _ = writeln!(
+                        redacted,
+                        "{}{}: {},",
+                        &line[..indent],
+                        written_key,
+                        REDACTED
+                    );
+                }
+                _ => {
+                    redacted.push_str(line);
+                    redacted.push('\n');
+                }
+            }
+        }
+
+        redacted
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::BTreeMap;
+
+    // fields are only read through Debug
+    #[allow(dead_code)]
+    #[derive(Debug)]
+    struct Credentials {
+        user: String,
+        api_token: Vec<String>,
+    }
+
+    #[allow(dead_code)]
+    #[derive(Debug)]
+    struct Settings {
+        name: String,
+        credentials: Credentials,
+        db_password: Option<Credentials>,
+        env_vars: BTreeMap<String, String>,
+        port: u16,
+    }
+
+    fn redactor() -> Redactor {
+        Redactor::new(&crate::config::RedactionInfo::default())
+    }
+
+    #[test]
+    fn glob_matches_patterns() {
+        assert!(glob_matches("*TOKEN*", "api_token"));
+        assert!(glob_matches("*TOKEN*", "TOKEN"));
+        assert!(glob_matches("*token", "MY_TOKEN"));
+        assert!(glob_matches("A*B*C", "aXXbYYc"));
+        assert!(glob_matches("A*B", "ABAB"));
+        assert!(glob_matches("*", ""));
+        assert!(!glob_matches("*TOKEN", "TOKENS"));
+        assert!(!glob_matches("A*B*C", "aXXbYY"));
+        assert!(!glob_matches("", "A"));
+    }
+
+    #[test]
+    fn split_debug_field_handles_plain_and_quoted_keys() {
+        assert_eq!(
+            split_debug_field("user: \"alice\","),
+            Some(("user", "user", "\"alice\","))
+        );
+        assert_eq!(
+            split_debug_field("\"MY KEY\": \"a: b\","),
+            Some(("MY KEY", "\"MY KEY\"", "\"a: b\","))
+        );
+        assert_eq!(
+            split_debug_field("credentials: Credentials {"),
+            Some(("credentials", "credentials", "Credentials {"))
+        );
+        assert_eq!(split_debug_field("\"alice\","), None);
+        assert_eq!(split_debug_field("},"), None);
+        assert_eq!(split_debug_field("user:\"alice\","), None);
+    }
+
+    #[test]
+    fn redact_debug_hides_single_and_multi_line_values() {
+        let mut env_vars = BTreeMap::new();
+        env_vars.insert("HOME".to_string(), "/root".to_string());
+        env_vars.insert("GITHUB_TOKEN".to_string(), "ghp_secret".to_string());
+
+        let settings = Settings {
+            name: "server".to_string(),
+            credentials: Credentials {
+                user: "alice".to_string(),
+                api_token: vec!["secret1".to_string(), "secret2".to_string()],
+            },
+            db_password: Some(Credentials {
+                user: "db".to_string(),
+                api_token: Vec::new(),
+            }),
+            env_vars,
+            port: 8080,
+        };
+
+        let redacted = redactor().redact_debug(&format!("{:#?}", settings));
+
+        assert!(!redacted.contains("secret"), "{}", redacted);
+        assert!(!redacted.contains("\"db\""), "{}", redacted);
+        assert!(redacted.contains("api_token: <redacted>,"), "{}", redacted);
+        assert!(
+            redacted.contains("db_password: <redacted>,"),
+            "{}",
+            redacted
+        );
+        assert!(
+            redacted.contains("\"GITHUB_TOKEN\": <redacted>,"),
+            "{}",
+            redacted
+        );
+        assert!(redacted.contains("\"HOME\": \"/root\","), "{}", redacted);
+        assert!(redacted.contains("user: \"alice\","), "{}", redacted);
+        assert!(redacted.contains("port: 8080,"), "{}", redacted);
+        assert!(redacted.ends_with("}\n"), "{}", redacted);
+    }
+}
@@ -0,0 +1,67 @@
+use futures::{Future, Poll};
+
+use hyper::header::{HeaderMap, HeaderName};
+
+use std::cell::RefCell;
+
+pub const X_REQUEST_ID: &str = "x-request-id";
+
+const MAX_REQUEST_ID_LENGTH: usize = 128;
+
+thread_local! {
+    static CURRENT_REQUEST_ID: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+pub fn x_request_id_header_name() -> HeaderName {
+    HeaderName::from_static(X_REQUEST_ID)
+}
+
+// Ids supplied by clients are only trusted when short and printable, since
+// they end up in log files.
+pub fn from_headers(headers: &HeaderMap) -> Option<String> {
+    let request_id = headers.get(X_REQUEST_ID)?.to_str().ok()?.trim();
+
+    let valid = !request_id.is_empty()
+        && request_id.len() <= MAX_REQUEST_ID_LENGTH
+        && request_id.chars().all(|c| c.is_ascii_graphic());
+
+    if valid {
+        Some(request_id.to_string())
+    } else {
+        None
+    }
+}
+
+// The id of the request whose future is being polled on this thread.
+pub fn current() -> Option<String> {
+    CURRENT_REQUEST_ID.with(|current| current.borrow().clone())
+}
+
+// Makes the request id current on the polling thread for the duration of
+// each poll, so every log record emitted while handling a request carries it.
+pub struct WithRequestId<F> {
+    request_id: String,
+    inner: F,
+}
+
+impl<F> WithRequestId<F> {
+    pub fn new(request_id: String, inner: F) -> Self {
+        WithRequestId { request_id, inner }
+    }
+}
+
+impl<F: Future> Future for WithRequestId<F> {
+    type Item = F::Item;
+    type Error = F::Error;
+
+    fn poll(&mut self) -> Poll<F::Item, F::Error> {
+        let previous =
+            CURRENT_REQUEST_ID.with(|current| current.replace(Some(self.request_id.clone())));
+
+        let result = self.inner.poll();
+
+        CURRENT_REQUEST_ID.with(|current| *current.borrow_mut() = previous);
+
+        result
+    }
+}
@@ -0,0 +1,475 @@
+use std::cmp;
+use std::ffi::CString;
+use std::io;
+use std::mem;
+use std::os::unix::process::CommandExt;
+use std::process::Command;
+use std::ptr;
+use std::sync::Arc;
+
+// Classic BPF as used by seccomp; see linux/filter.h and linux/seccomp.h.
+#[repr(C)]
+struct SockFilter {
+    code: u16,
+    jt: u8,
+    jf: u8,
+    k: u32,
+}
+
+#[repr(C)]
+struct SockFprog {
+    len: u16,
+    filter: *const SockFilter,
+}
+
+const BPF_LD_W_ABS: u16 = 0x20;
+const BPF_JMP_JEQ_K: u16 = 0x15;
+const BPF_JMP_JGE_K: u16 = 0x35;
+const BPF_RET_K: u16 = 0x06;
+
+const SECCOMP_MODE_FILTER: libc::c_ulong = 2;
+const SECCOMP_RET_KILL: u32 = 0;
+const SECCOMP_RET_ERRNO: u32 = 0x0005_0000;
+const SECCOMP_RET_ALLOW: u32 = 0x7fff_0000;
+
+// offsets into struct seccomp_data
+const SECCOMP_DATA_NR: u32 = 0;
+const SECCOMP_DATA_ARCH: u32 = 4;
+
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH: Option<u32> = Some(0xc000_003e);
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH: Option<u32> = Some(0xc000_00b7);
+#[cfg(target_arch = "arm")]
+const AUDIT_ARCH: Option<u32> = Some(0x4000_0028);
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64", target_arch = "arm")))]
+const AUDIT_ARCH: Option<u32> = None;
+
+// x32 syscalls share the x86_64 audit arch but have this bit set.
+const X32_SYSCALL_BIT: u32 = 0x4000_0000;
+
+// Only the errno of a pre_exec error reaches the parent, so sandbox setup
+// errors are shifted past the range of real errno values to tell them
+// apart from exec errors.
+const SETUP_ERRNO_OFFSET: i32 = 0x10000;
+
+const BLOCKED_SYSCALLS: [libc::c_long; 26] = [
+    libc::SYS_acct,
+    libc::SYS_add_key,
+    libc::SYS_bpf,
+    libc::SYS_clock_settime,
+    libc::SYS_delete_module,
+    libc::SYS_finit_module,
+    libc::SYS_init_module,
+    libc::SYS_kexec_load,
+    libc::SYS_keyctl,
+    libc::SYS_mount,
+    libc::SYS_open_by_handle_at,
+    libc::SYS_perf_event_open,
+    libc::SYS_pivot_root,
+    libc::SYS_process_vm_readv,
+    libc::SYS_process_vm_writev,
+    libc::SYS_ptrace,
+    libc::SYS_reboot,
+    libc::SYS_request_key,
+    libc::SYS_setdomainname,
+    libc::SYS_sethostname,
+    libc::SYS_setns,
+    libc::SYS_settimeofday,
+    libc::SYS_swapoff,
+    libc::SYS_swapon,
+    libc::SYS_umount2,
+    libc::SYS_unshare,
+];
+
+fn bpf_statement(code: u16, k: u32) -> SockFilter {
+    SockFilter {
+        code,
+        jt: 0,
+        jf: 0,
+        k,
+    }
+}
+
+fn bpf_jump(code: u16, k: u32, jt: u8, jf: u8) -> SockFilter {
+    SockFilter { code, jt, jf, k }
+}
+
+// Blocked syscalls fail with EPERM; syscalls for another architecture kill
+// the process, since their numbers mean something else.
+fn default_seccomp_filter() -> Result<Vec<SockFilter>, Box<::std::error::Error>> {
+    let audit_arch = AUDIT_ARCH.ok_or("seccomp filters are not supported on this architecture")?;
+    let deny = SECCOMP_RET_ERRNO | libc::EPERM as u32;
+
+    let mut filter = vec![
+        bpf_statement(BPF_LD_W_ABS, SECCOMP_DATA_ARCH),
+        bpf_jump(BPF_JMP_JEQ_K, audit_arch, 1, 0),
+        bpf_statement(BPF_RET_K, SECCOMP_RET_KILL),
+        bpf_statement(BPF_LD_W_ABS, SECCOMP_DATA_NR),
+    ];
+
+    if cfg!(target_arch = "x86_64") {
+        filter.push(bpf_jump(BPF_JMP_JGE_K, X32_SYSCALL_BIT, 0, 1));
+        filter.push(bpf_statement(BPF_RET_K, deny));
+    }
+
+    for syscall in BLOCKED_SYSCALLS.iter() {
+        filter.push(bpf_jump(BPF_JMP_JEQ_K, *syscall as u32, 0, 1));
+        filter.push(bpf_statement(BPF_RET_K, deny));
+    }
+
+    filter.push(bpf_statement(BPF_RET_K, SECCOMP_RET_ALLOW));
+
+    Ok(filter)
+}
+
+fn check(result: libc::c_int) -> io::Result<libc::c_int> {
+    if result == -1 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(result)
+    }
+}
+
+// path must be nul terminated.
+fn write_file(path: &[u8], contents: &[u8]) -> io::Result<()> {
+    let fd = check(unsafe {
+        libc::open(
+            path.as_ptr() as *const libc::c_char,
+            libc::O_WRONLY | libc::O_CLOEXEC,
+        )
+    })?;
+
+    let written =
+        unsafe { libc::write(fd, contents.as_ptr() as *const libc::c_void, contents.len()) };
+    let result = if written < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    };
+
+    unsafe { libc::close(fd) };
+    result
+}
+
+fn mount(
+    source: *const libc::c_char,
+    target: *const libc::c_char,
+    fstype: *const libc::c_char,
+    flags: libc::c_ulong,
+) -> io::Result<()> {
+    check(unsafe { libc::mount(source, target, fstype, flags, ptr::null()) }).map(|_| ())
+}
+
+// Mounts in a user namespace keep the nosuid, nodev and noexec flags they
+// were created with, so a read-only remount has to repeat them.
+fn bind_read_only(path: &CString) -> io::Result<()> {
+    mount(
+        path.as_ptr(),
+        path.as_ptr(),
+        ptr::null(),
+        libc::MS_BIND | libc::MS_REC,
+    )?;
+
+    let mut stat: libc::statvfs = unsafe { mem::zeroed() };
+    check(unsafe { libc::statvfs(path.as_ptr(), &mut stat) })?;
+
+    let mut flags = libc::MS_BIND | libc::MS_REMOUNT | libc::MS_RDONLY;
+    for (statvfs_flag, mount_flag) in &[
+        (libc::ST_NOSUID, libc::MS_NOSUID),
+        (libc::ST_NODEV, libc::MS_NODEV),
+        (libc::ST_NOEXEC, libc::MS_NOEXEC),
+    ] {
+        if stat.f_flag & statvfs_flag != 0 {
+            flags |= mount_flag;
+        }
+    }
+
+    mount(ptr::null(), path.as_ptr(), ptr::null(), flags)
+}
+
+// A new pid namespace only applies to children, so fork once more.  The
+// child becomes pid 1 and goes on to exec the command; this process waits
+// for it and exits with its status.  The child is killed when this process
+// dies, taking the rest of the namespace with it.
+fn fork_into_pid_namespace() -> io::Result<()> {
+    // the child sees the write end close if this process is already gone
+    // by the time it has set its death signal
+    let mut parent_pipe = [0; 2];
+    check(unsafe { libc::pipe2(parent_pipe.as_mut_ptr(), libc::O_CLOEXEC) })?;
+
+    let child = check(unsafe { libc::fork() })?;
+    if child == 0 {
+        unsafe { libc::close(parent_pipe[1]) };
+
+        check(unsafe {
+            libc::prctl(
+                libc::PR_SET_PDEATHSIG,
+                libc::SIGKILL as libc::c_ulong,
+                0 as libc::c_ulong,
+                0 as libc::c_ulong,
+                0 as libc::c_ulong,
+            )
+        })?;
+
+        let mut poll_fd = libc::pollfd {
+            fd: parent_pipe[0],
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        check(unsafe { libc::poll(&mut poll_fd, 1, 0) })?;
+        unsafe { libc::close(parent_pipe[0]) };
+
+        if poll_fd.revents & libc::POLLHUP != 0 {
+            unsafe { libc::_exit(1) };
+        }
+
+        return Ok(());
+    }
+
+    // Command::spawn waits for the exec error pipe to close, which would
+    // otherwise stay open in this process until the command finishes.
+    let max_fd = unsafe { libc::sysconf(libc::_SC_OPEN_MAX) };
+    let max_fd = if max_fd < 0 {
+        1024
+    } else {
+        cmp::min(max_fd, 65536)
+    };
+    for fd in 3..max_fd as libc::c_int {
+        if fd != parent_pipe[1] {
+            unsafe { libc::close(fd) };
+        }
+    }
+
+    let mut status = 0;
+    unsafe {
+        while libc::waitpid(child, &mut status, 0) == -1 {
+            if io::Error::last_os_error().raw_os_error() != Some(libc::EINTR) {
+                libc::_exit(1);
+            }
+        }
+
+        if libc::WIFEXITED(status) {
+            libc::_exit(libc::WEXITSTATUS(status));
+        } else if libc::WIFSIGNALED(status) {
+            libc::_exit(128 + libc::WTERMSIG(status));
+        } else {
+            libc::_exit(1);
+        }
+    }
+}
+
+pub struct Sandbox {
+    clone_flags: libc::c_int,
+    user_namespace: bool,
+    uid_map: Vec<u8>,
+    gid_map: Vec<u8>,
+    mount_namespace: bool,
+    pid_namespace: bool,
+    read_only_paths: Vec<CString>,
+    seccomp_filter: Option<Vec<SockFilter>>,
+    required: bool,
+}
+
+impl Sandbox {
+    pub fn new(
+        sandbox_info: &crate::config::SandboxInfo,
+    ) -> Result<Self, Box<::std::error::Error>> {
+        let namespaces = sandbox_info.namespaces();
+        let mount_namespace = namespaces.contains(&crate::config::SandboxNamespace::Mount);
+        let pid_namespace = namespaces.contains(&crate::config::SandboxNamespace::Pid);
+        let network_namespace = namespaces.contains(&crate::config::SandboxNamespace::Network);
+
+        if !sandbox_info.read_only_paths().is_empty() && !mount_namespace {
+            return Err("sandbox read_only_paths require the mount namespace".into());
+        }
+
+        let mut read_only_paths = Vec::with_capacity(sandbox_info.read_only_paths().len());
+        for path in sandbox_info.read_only_paths() {
+            if !path.starts_with('/') {
+                return Err(format!("sandbox read_only_path '{}' is not absolute", path).into());
+            }
+            read_only_paths.push(CString::new(path.as_str())?);
+        }
+
+        let mut clone_flags = 0;
+        if mount_namespace {
+            clone_flags |= libc::CLONE_NEWNS;
+        }
+        if pid_namespace {
+            clone_flags |= libc::CLONE_NEWPID;
+        }
+        if network_namespace {
+            clone_flags |= libc::CLONE_NEWNET;
+        }
+
+        let uid = unsafe { libc::geteuid() };
+        let gid = unsafe { libc::getegid() };
+
+        // Without root, new namespaces need a user namespace that maps the
+        // server's own uid and gid.
+        let user_namespace = clone_flags != 0 && uid != 0;
+        if user_namespace {
+            clone_flags |= libc::CLONE_NEWUSER;
+        }
+
+        let seccomp_filter = match sandbox_info.seccomp_profile() {
+            crate::config::SeccompProfile::Default => Some(default_seccomp_filter()?),
+            crate::config::SeccompProfile::Unconfined => None,
+        };
+
+        Ok(Sandbox {
+            clone_flags,
+            user_namespace,
+            uid_map: format!("{} {} 1\n", uid, uid).into_bytes(),
+            gid_map: format!("{} {} 1\n", gid, gid).into_bytes(),
+            mount_namespace,
+            pid_namespace,
+            read_only_paths,
+            seccomp_filter,
+            required: sandbox_info.required(),
+        })
+    }
+
+    pub fn required(&self) -> bool {
+        self.required
+    }
+
+    // Runs in the forked child before exec, so it only makes syscalls and
+    // doesn't allocate.
+    fn enter(&self) -> io::Result<()> {
+        self.set_up().map_err(|e| {
+            io::Error::from_raw_os_error(
+                SETUP_ERRNO_OFFSET + e.raw_os_error().unwrap_or(libc::EINVAL),
+            )
+        })
+    }
+
+    fn set_up(&self) -> io::Result<()> {
+        if self.clone_flags != 0 {
+            check(unsafe { libc::unshare(self.clone_flags) })?;
+        }
+
+        if self.user_namespace {
+            match write_file(b"/proc/self/setgroups\0", b"deny") {
+                Err(ref e) if e.raw_os_error() == Some(libc::ENOENT) => {}
+                result => result?,
+            }
+            write_file(b"/proc/self/uid_map\0", &self.uid_map)?;
+            write_file(b"/proc/self/gid_map\0", &self.gid_map)?;
+        }
+
+        if self.mount_namespace {
+            // keep the mounts below from propagating back to the host
+            mount(
+                ptr::null(),
+                b"/\0".as_ptr() as *const libc::c_char,
+                ptr::null(),
+                libc::MS_REC | libc::MS_PRIVATE,
+            )?;
+
+            for path in &self.read_only_paths {
+                bind_read_only(path)?;
+            }
+        }
+
+        if self.pid_namespace {
+            fork_into_pid_namespace()?;
+
+            if self.mount_namespace {
+                // a fresh /proc shows only the sandbox's processes
+                let proc = b"proc\0".as_ptr() as *const libc::c_char;
+                mount(
+                    proc,
+                    b"/proc\0".as_ptr() as *const libc::c_char,
+                    proc,
+                    libc::MS_NOSUID | libc::MS_NODEV | libc::MS_NOEXEC,
+                )?;
+            }
+        }
+
+        check(unsafe {
+            libc::prctl(
+                libc::PR_SET_NO_NEW_PRIVS,
+                1 as libc::c_ulong,
+                0 as libc::c_ulong,
+                0 as libc::c_ulong,
+                0 as libc::c_ulong,
+            )
+        })?;
+
+        if let Some(seccomp_filter) = &self.seccomp_filter {
+            let program = SockFprog {
+                len: seccomp_filter.len() as u16,
+                filter: seccomp_filter.as_ptr(),
+            };
+            check(unsafe {
+                libc::prctl(
+                    libc::PR_SET_SECCOMP,
+                    SECCOMP_MODE_FILTER,
+                    &program as *const SockFprog,
+                )
+            })?;
+        }
+
+        Ok(())
+    }
+
+    // Spawning a command the sandbox was applied to fails with an error
+    // for which setup_error is Some when the sandbox couldn't be set up.
+    pub fn apply(self: &Arc<Self>, command: &mut Command) {
+        let self_clone = Arc::clone(self);
+
+        unsafe {
+            command.pre_exec(move || self_clone.enter());
+        }
+    }
+}
+
+// The reason the sandbox couldn't be set up, if that is why spawning the
+// command failed.
+pub fn setup_error(error: &io::Error) -> Option<io::Error> {
+    match error.raw_os_error() {
+        Some(errno) if errno >= SETUP_ERRNO_OFFSET => {
+            Some(io::Error::from_raw_os_error(errno - SETUP_ERRNO_OFFSET))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sandbox(sandbox_json: &str) -> Arc<Sandbox> {
+        let sandbox_info: crate::config::SandboxInfo =
+            ::serde_json::from_str(sandbox_json).unwrap();
+        Arc::new(Sandbox::new(&sandbox_info).unwrap())
+    }
+
+    #[test]
+    fn exec_errors_are_not_setup_errors() {
+        let sandbox = sandbox(r#"{"namespaces": [], "seccomp_profile": "unconfined"}"#);
+
+        let mut command = Command::new("/nonexistent/rust_hyper_sandbox_test");
+        sandbox.apply(&mut command);
+
+        let e = command.output().unwrap_err();
+        assert_eq!(e.raw_os_error(), Some(libc::ENOENT));
+        assert!(setup_error(&e).is_none());
+    }
+
+    #[test]
+    fn setup_errors_are_reported_with_their_errno() {
+        let sandbox = sandbox(
+            r#"{"namespaces": ["mount"], "read_only_paths": ["/nonexistent/rust_hyper_sandbox_test"], "seccomp_profile": "unconfined"}"#,
+        );
+
+        let mut command = Command::new("true");
+        sandbox.apply(&mut command);
+
+        let e = command.output().unwrap_err();
+        let setup_error = setup_error(&e).expect("sandbox setup should fail");
+        assert!(setup_error.raw_os_error().unwrap() < SETUP_ERRNO_OFFSET);
+    }
+}
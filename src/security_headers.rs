@@ -0,0 +1,91 @@
+use hyper::header::{
+    HeaderMap, HeaderName, HeaderValue, CONTENT_SECURITY_POLICY, REFERRER_POLICY,
+    STRICT_TRANSPORT_SECURITY, X_CONTENT_TYPE_OPTIONS, X_FRAME_OPTIONS,
+};
+
+type HeaderList = Vec<(HeaderName, HeaderValue)>;
+
+// Replaces or, for an empty value, removes name in headers.
+fn set_header(
+    headers: &mut HeaderList,
+    name: HeaderName,
+    value: &str,
+) -> Result<(), Box<::std::error::Error>> {
+    headers.retain(|(existing_name, _)| *existing_name != name);
+
+    if !value.is_empty() {
+        let header_value = HeaderValue::from_str(value)
+            .map_err(|e| format!("invalid value for security header {}: {}", name, e))?;
+        headers.push((name, header_value));
+    }
+
+    Ok(())
+}
+
+pub struct SecurityHeaders {
+    global_headers: HeaderList,
+    route_headers: crate::server::PrefixRules<HeaderList>,
+}
+
+impl SecurityHeaders {
+    pub fn new(
+        security_headers_info: &crate::config::SecurityHeadersInfo,
+    ) -> Result<Self, Box<::std::error::Error>> {
+        let mut global_headers = HeaderList::new();
+        set_header(
+            &mut global_headers,
+            CONTENT_SECURITY_POLICY,
+            security_headers_info.content_security_policy(),
+        )?;
+        set_header(
+            &mut global_headers,
+            X_CONTENT_TYPE_OPTIONS,
+            security_headers_info.x_content_type_options(),
+        )?;
+        set_header(
+            &mut global_headers,
+            REFERRER_POLICY,
+            security_headers_info.referrer_policy(),
+        )?;
+        set_header(
+            &mut global_headers,
+            X_FRAME_OPTIONS,
+            security_headers_info.x_frame_options(),
+        )?;
+        set_header(
+            &mut global_headers,
+            STRICT_TRANSPORT_SECURITY,
+            security_headers_info.strict_transport_security(),
+        )?;
+
+        let mut route_headers = Vec::with_capacity(security_headers_info.routes().len());
+        for route_info in security_headers_info.routes() {
+            let mut headers = global_headers.clone();
+            for (name, value) in route_info.headers() {
+                let header_name = HeaderName::from_bytes(name.as_bytes())
+                    .map_err(|e| format!("invalid security header name '{}': {}", name, e))?;
+                set_header(&mut headers, header_name, value)?;
+            }
+            route_headers.push((route_info.path_prefix().clone(), headers));
+        }
+
+        Ok(SecurityHeaders {
+            global_headers,
+            route_headers: crate::server::PrefixRules::new(route_headers),
+        })
+    }
+
+    // Headers the handler set itself are left alone.
+    pub fn apply(&self, path: &str, headers: &mut HeaderMap) {
+        let security_headers = self
+            .route_headers
+            .lookup(path)
+            .unwrap_or(&self.global_headers);
+
+        for (name, value) in security_headers {
+            if !headers.contains_key(name) {
+                headers.insert(name.clone(), value.clone());
+            }
+        }
+    }
+}
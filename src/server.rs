@@ -1,18 +1,22 @@
 use futures::{future, Future};
 
-use log::{info, warn};
+use log::{debug, info, warn};
 
-use hyper::header::{HeaderValue, CONTENT_TYPE};
-use hyper::service::service_fn;
-use hyper::{Body, Request, Response, Server, StatusCode};
+use hyper::body::Payload;
+use hyper::header::{HeaderValue, CONTENT_LENGTH, CONTENT_TYPE, LOCATION, REFERER, USER_AGENT};
+use hyper::server::conn::AddrStream;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
 
 use std::borrow::Cow;
+use std::cmp::Reverse;
 use std::collections::HashMap;
 use std::error;
 use std::fmt;
-use std::net::SocketAddr;
-use std::sync::Arc;
-use std::time::Instant;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
 
 pub type HyperHttpClient = ::hyper::Client<
     ::hyper::client::HttpConnector<::hyper::client::connect::dns::TokioThreadpoolGaiResolver>,
@@ -21,30 +25,73 @@ pub type HyperHttpClient = ::hyper::Client<
 
 pub struct ApplicationContext {
     http_client: HyperHttpClient,
+    metrics: Arc<crate::metrics::Metrics>,
+    tracer: Option<crate::trace::Tracer>,
 }
 
 impl ApplicationContext {
-    fn new(http_client: HyperHttpClient) -> Self {
-        ApplicationContext { http_client }
+    fn new(
+        http_client: HyperHttpClient,
+        metrics: Arc<crate::metrics::Metrics>,
+        tracer: Option<crate::trace::Tracer>,
+    ) -> Self {
+        ApplicationContext {
+            http_client,
+            metrics,
+            tracer,
+        }
     }
 
     pub fn http_client(&self) -> &HyperHttpClient {
         &self.http_client
     }
+
+    pub fn metrics(&self) -> &Arc<crate::metrics::Metrics> {
+        &self.metrics
+    }
+
+    pub fn tracer(&self) -> &Option<crate::trace::Tracer> {
+        &self.tracer
+    }
 }
 
 pub struct RequestContext {
     req: Request<Body>,
+    body: Mutex<Option<Body>>,
     app_context: Arc<ApplicationContext>,
     start_time: Instant,
+    path: String,
+    // the peer address, or the forwarded client address for requests from
+    // trusted proxies
+    client_ip: IpAddr,
+    request_id: String,
+    span_context: Option<crate::trace::SpanContext>,
+    principal: Option<crate::auth::Principal>,
 }
 
 impl RequestContext {
-    fn new(req: Request<Body>, app_context: Arc<ApplicationContext>) -> Self {
+    fn new(
+        req: Request<Body>,
+        path: String,
+        app_context: Arc<ApplicationContext>,
+        client_ip: IpAddr,
+        request_id: String,
+        span_context: Option<crate::trace::SpanContext>,
+    ) -> Self {
+        // handlers only get a shared reference, so the body is kept apart
+        // for the one that consumes it
+        let (parts, body) = req.into_parts();
+
         RequestContext {
-            req,
+            req: Request::from_parts(parts, Body::empty()),
+            body: Mutex::new(Some(body)),
             app_context,
             start_time: Instant::now(),
+            path,
+            client_ip,
+            request_id,
+            span_context,
+            principal: None,
         }
     }
 
@@ -52,9 +99,68 @@ impl RequestContext {
         &self.app_context
     }
 
+    // The body of req() is always empty; see take_body.
     pub fn req(&self) -> &Request<Body> {
         &self.req
     }
+
+    pub fn take_body(&self) -> Option<Body> {
+        self.body.lock().unwrap().take()
+    }
+
+    // The decoded request path from normalize_path, which routing,
+    // authorization and the other path prefix checks all match against.
+    pub fn path(&self) -> &String {
+        &self.path
+    }
+
+    pub fn request_id(&self) -> &String {
+        &self.request_id
+    }
+
+    // Starts a child of the request span, when tracing is enabled.
+    pub fn start_child_span(
+        &self,
+        name: String,
+        kind: crate::trace::SpanKind,
+    ) -> Option<crate::trace::Span> {
+        let tracer = self.app_context.tracer.as_ref()?;
+
+        Some(tracer.start_span(name, kind, self.span_context.as_ref()))
+    }
+}
+
+// The request span continues the caller's trace when a valid traceparent
+// header is present.
+fn start_request_span(
+    tracer: &crate::trace::Tracer,
+    req: &Request<Body>,
+    route: &str,
+    remote_addr: SocketAddr,
+    request_id: &str,
+) -> crate::trace::Span {
+    let parent = crate::trace::SpanContext::from_headers(req.headers());
+
+    let mut span = tracer.start_span(
+        format!("{} {}", req.method(), route),
+        crate::trace::SpanKind::Server,
+        parent.as_ref(),
+    );
+
+    span.set_attribute("http.method", req.method().as_str());
+    span.set_attribute("http.target", req.uri().to_string());
+    span.set_attribute("http.route", route);
+    span.set_attribute("net.peer.ip", remote_addr.ip().to_string());
+    span.set_attribute("request_id", request_id);
+
+    span
+}
+
+fn header_string(req: &Request<Body>, header_name: ::hyper::header::HeaderName) -> Option<String> {
+    req.headers()
+        .get(header_name)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.to_string())
 }
 
 struct RequestLogInfo {
@@ -62,10 +168,15 @@ struct RequestLogInfo {
     method: String,
     uri: String,
     version: String,
+    client_ip: IpAddr,
+    request_id: String,
+    route: String,
+    user_agent: Option<String>,
+    referer: Option<String>,
 }
 
 impl RequestLogInfo {
-    fn new(req_context: &RequestContext) -> Self {
+    fn new(req_context: &RequestContext, route: &str) -> Self {
         let req = &req_context.req;
 
         RequestLogInfo {
@@ -73,19 +184,56 @@ impl RequestLogInfo {
             method: req.method().to_string(),
             uri: req.uri().to_string(),
             version: format!("{:?}", req.version()),
+            client_ip: req_context.client_ip,
+            request_id: req_context.request_id.clone(),
+            route: route.to_string(),
+            user_agent: header_string(req, USER_AGENT),
+            referer: header_string(req, REFERER),
         }
     }
 }
 
-fn log_request_and_response(req_log_info: RequestLogInfo, resp: &Response<Body>) {
-    let response_status = resp.status().as_u16().to_string();
+fn response_bytes(resp: &Response<Body>) -> Option<u64> {
+    resp.headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse().ok())
+        .or_else(|| resp.body().content_length())
+}
 
+// Requests go to the access log when one is configured, otherwise to the
+// application log.
+fn log_request_and_response(
+    req_log_info: RequestLogInfo,
+    resp: &Response<Body>,
+    access_logger: &Option<crate::access_log::AccessLogger>,
+) {
     let duration = crate::utils::duration_in_seconds_f64(req_log_info.start_time.elapsed());
 
-    info!(
-        "\"{} {} {}\" {} {:.9}s",
-        req_log_info.method, req_log_info.uri, req_log_info.version, response_status, duration
-    );
+    match access_logger {
+        Some(access_logger) => access_logger.log(&crate::access_log::AccessLogEntry {
+            time: ::chrono::Local::now(),
+            request_id: req_log_info.request_id,
+            client_ip: req_log_info.client_ip,
+            method: req_log_info.method,
+            uri: req_log_info.uri,
+            version: req_log_info.version,
+            status: resp.status().as_u16(),
+            response_bytes: response_bytes(resp),
+            duration_seconds: duration,
+            route: req_log_info.route,
+            user_agent: req_log_info.user_agent,
+            referer: req_log_info.referer,
+        }),
+        None => info!(
+            "\"{} {} {}\" {} {:.9}s",
+            req_log_info.method,
+            req_log_info.uri,
+            req_log_info.version,
+            resp.status().as_u16(),
+            duration
+        ),
+    }
 }
 
 #[derive(Debug)]
@@ -134,25 +282,40 @@ impl error::Error for HandlerError {
 pub type ResponseFuture =
     Box<Future<Item = ::hyper::Response<::hyper::Body>, Error = HandlerError> + Send>;
 
+// Futures spawned on the runtime when the server starts.
+pub type BackgroundTask = Box<Future<Item = (), Error = ()> + Send>;
+
 pub trait RequestHandler: Send + Sync {
     fn handle(&self, req_context: &RequestContext) -> ResponseFuture;
+
+    // Shown in the route table printed by the routes command.
+    fn handler_type(&self) -> &'static str {
+        ::std::any::type_name::<Self>()
+    }
 }
 
 pub type RouteConfigurationHandler = Box<dyn RequestHandler>;
 pub type RouteConfigurationHandlerMap = HashMap<String, RouteConfigurationHandler>;
+pub type RouteConfigurationPrefixHandlerList = Vec<(String, RouteConfigurationHandler)>;
 
 pub struct RouteConfiguration {
     path_to_handler: RouteConfigurationHandlerMap,
+    prefix_to_handler: RouteConfigurationPrefixHandlerList,
     not_found_handler: RouteConfigurationHandler,
 }
 
 impl RouteConfiguration {
     pub fn new(
         path_to_handler: RouteConfigurationHandlerMap,
+        mut prefix_to_handler: RouteConfigurationPrefixHandlerList,
         not_found_handler: RouteConfigurationHandler,
     ) -> Self {
+        // longest prefix first so nested mounts win over their parents
+        prefix_to_handler.sort_by_key(|(prefix, _)| Reverse(prefix.len()));
+
         RouteConfiguration {
             path_to_handler,
+            prefix_to_handler,
             not_found_handler,
         }
     }
@@ -161,9 +324,99 @@ impl RouteConfiguration {
         &self.path_to_handler
     }
 
+    pub fn prefix_to_handler(&self) -> &RouteConfigurationPrefixHandlerList {
+        &self.prefix_to_handler
+    }
+
     pub fn not_found_handler(&self) -> &RouteConfigurationHandler {
         &self.not_found_handler
     }
+
+    // Returns the matched route (the configured path or prefix, not the
+    // request path) along with its handler.
+    pub fn find_route(&self, path: &str) -> (&str, &RouteConfigurationHandler) {
+        if let Some((route, handler)) = self.path_to_handler.get_key_value(path) {
+            return (route, handler);
+        }
+
+        for (prefix, handler) in &self.prefix_to_handler {
+            if path_matches_prefix(path, prefix) {
+                return (prefix, handler);
+            }
+        }
+
+        ("not_found", &self.not_found_handler)
+    }
+}
+
+// Percent-decodes path and drops empty and "." segments, so that every
+// spelling of a path matches the same route and policies.  Returns None for
+// paths with ".." segments, control characters or invalid utf-8.
+pub fn normalize_path(path: &str) -> Option<String> {
+    let decoded_path = ::percent_encoding::percent_decode(path.as_bytes())
+        .decode_utf8()
+        .ok()?;
+
+    if decoded_path.chars().any(|c| c.is_control() || c == '\\') {
+        return None;
+    }
+
+    let mut normalized_path = String::with_capacity(decoded_path.len());
+
+    for segment in decoded_path.split('/') {
+        match segment {
+            "" | "." => continue,
+            ".." => return None,
+            segment => {
+                normalized_path.push('/');
+                normalized_path.push_str(segment);
+            }
+        }
+    }
+
+    if normalized_path.is_empty() || decoded_path.ends_with('/') {
+        normalized_path.push('/');
+    }
+
+    Some(normalized_path)
+}
+
+pub fn path_matches_prefix(path: &str, prefix: &str) -> bool {
+    let prefix = prefix.trim_end_matches('/');
+
+    path.starts_with(prefix) && {
+        let rest = &path[prefix.len()..];
+        rest.is_empty() || rest.starts_with('/')
+    }
+}
+
+// Per-route settings keyed by path prefix.  The longest matching prefix
+// wins, as with route prefixes.
+pub struct PrefixRules<T> {
+    rules: Vec<(String, T)>,
+}
+
+impl<T> PrefixRules<T> {
+    pub fn new(mut rules: Vec<(String, T)>) -> Self {
+        rules.sort_by_key(|(path_prefix, _)| Reverse(path_prefix.len()));
+
+        PrefixRules { rules }
+    }
+
+    pub fn lookup(&self, path: &str) -> Option<&T> {
+        self.position(path).map(|index| &self.rules[index].1)
+    }
+
+    // Index of the matching rule, stable for the lifetime of the rules.
+    pub fn position(&self, path: &str) -> Option<usize> {
+        self.rules
+            .iter()
+            .position(|(path_prefix, _)| path_matches_prefix(path, path_prefix))
+    }
+
+    pub fn get(&self, index: usize) -> Option<&T> {
+        self.rules.get(index).map(|(_, value)| value)
+    }
 }
 
 pub fn text_plain_content_type_header_value() -> HeaderValue {
@@ -185,6 +438,20 @@ pub fn build_response_status(status_code: StatusCode) -> Response<Body> {
         .unwrap()
 }
 
+pub fn build_response_redirect(status_code: StatusCode, location: &str) -> Response<Body> {
+    match HeaderValue::from_str(location) {
+        Ok(location) => Response::builder()
+            .status(status_code)
+            .header(LOCATION, location)
+            .body(Body::empty())
+            .unwrap(),
+        Err(e) => {
+            warn!("invalid redirect location {:?}: {}", location, e);
+            build_response_status(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
 pub fn build_response_string(
     status_code: StatusCode,
     body: Cow<'static, str>,
@@ -200,6 +467,104 @@ pub fn build_response_string(
 struct InnerThreadedServer {
     application_context: Arc<ApplicationContext>,
     route_configuration: RouteConfiguration,
+    compression_info: Option<crate::config::CompressionInfo>,
+    access_logger: Option<crate::access_log::AccessLogger>,
+    authenticator: Option<crate::auth::Authenticator>,
+    ip_access_control: Option<crate::ip_access::IpAccessControl>,
+    rate_limiter: Option<crate::rate_limit::RateLimiter>,
+    security_headers: crate::security_headers::SecurityHeaders,
+    cors: Option<crate::cors::Cors>,
+    request_id_prefix: String,
+    next_request_id: AtomicUsize,
+}
+
+impl InnerThreadedServer {
+    // Requests that fail authorization are charged to the client ip, and
+    // an ip without tokens left is turned away before its credentials are
+    // verified, so passwords can't be guessed faster than the rate limit.
+    fn authorize_and_dispatch(
+        self: &Arc<Self>,
+        authenticator: &crate::auth::Authenticator,
+        mut req_context: RequestContext,
+    ) -> ResponseFuture {
+        if let Some(rate_limiter) = &self.rate_limiter {
+            if let Some(rate_limit_status) =
+                rate_limiter.peek_ip(req_context.client_ip, &req_context.path)
+            {
+                if !rate_limit_status.allowed() {
+                    debug!(
+                        "rate limited {} access to {} before authorization",
+                        req_context.client_ip, req_context.path
+                    );
+                    return Box::new(future::ok(rate_limit_status.too_many_requests_response()));
+                }
+            }
+        }
+
+        let self_clone = Arc::clone(self);
+
+        Box::new(
+            authenticator
+                .authorize(&req_context.req, &req_context.path)
+                .and_then(move |authorization| -> ResponseFuture {
+                    match authorization {
+                        crate::auth::Authorization::Allowed(principal) => {
+                            req_context.principal = principal;
+                            self_clone.dispatch(&req_context)
+                        }
+                        crate::auth::Authorization::Denied(mut resp) => {
+                            if let Some(rate_limit_status) =
+                                self_clone.rate_limiter.as_ref().and_then(|rate_limiter| {
+                                    rate_limiter.check(
+                                        None,
+                                        req_context.client_ip,
+                                        &req_context.path,
+                                    )
+                                })
+                            {
+                                rate_limit_status.add_headers(resp.headers_mut());
+                            }
+                            Box::new(future::ok(resp))
+                        }
+                    }
+                }),
+        )
+    }
+
+    // Runs after authorization, so authenticated requests are rate limited
+    // per user rather than per client ip.
+    fn dispatch(&self, req_context: &RequestContext) -> ResponseFuture {
+        let path = req_context.path.as_str();
+
+        let rate_limit_status = self.rate_limiter.as_ref().and_then(|rate_limiter| {
+            rate_limiter.check(req_context.principal.as_ref(), req_context.client_ip, path)
+        });
+
+        let (_, handler) = self.route_configuration.find_route(path);
+
+        match rate_limit_status {
+            Some(ref rate_limit_status) if !rate_limit_status.allowed() => {
+                debug!("rate limited {} access to {}", req_context.client_ip, path);
+                Box::new(future::ok(rate_limit_status.too_many_requests_response()))
+            }
+            Some(rate_limit_status) => {
+                Box::new(handler.handle(req_context).map(move |mut resp| {
+                    rate_limit_status.add_headers(resp.headers_mut());
+                    resp
+                }))
+            }
+            None => handler.handle(req_context),
+        }
+    }
+
+    // Start time plus a counter keeps ids unique across restarts.
+    fn generate_request_id(&self) -> String {
+        format!(
+            "{}-{:x}",
+            self.request_id_prefix,
+            self.next_request_id.fetch_add(1, Ordering::Relaxed)
+        )
+    }
 }
 
 #[derive(Clone)]
@@ -211,63 +576,263 @@ impl ThreadedServer {
     fn new(
         application_context: Arc<ApplicationContext>,
         route_configuration: RouteConfiguration,
+        request_pipeline: RequestPipeline,
     ) -> Self {
+        let RequestPipeline {
+            compression_info,
+            access_logger,
+            authenticator,
+            ip_access_control,
+            rate_limiter,
+            security_headers,
+            cors,
+        } = request_pipeline;
+
+        let request_id_prefix = match SystemTime::now().duration_since(UNIX_EPOCH) {
+            Ok(duration) => format!("{:x}", duration.as_secs()),
+            Err(_) => "0".to_string(),
+        };
+
         ThreadedServer {
             inner: Arc::new(InnerThreadedServer {
                 application_context,
                 route_configuration,
+                compression_info,
+                access_logger,
+                authenticator,
+                ip_access_control,
+                rate_limiter,
+                security_headers,
+                cors,
+                request_id_prefix,
+                next_request_id: AtomicUsize::new(0),
             }),
         }
     }
 }
 
 impl ThreadedServer {
-    fn call(&self, req: Request<Body>) -> ResponseFuture {
-        let req_context = RequestContext::new(req, Arc::clone(&self.inner.application_context));
-
-        let req_log_info = RequestLogInfo::new(&req_context);
+    fn call(&self, req: Request<Body>, remote_addr: SocketAddr) -> ResponseFuture {
+        let request_id = match crate::request_id::from_headers(req.headers()) {
+            Some(request_id) => request_id,
+            None => self.inner.generate_request_id(),
+        };
+
+        let self_clone = self.clone();
+        let request_id_clone = request_id.clone();
+        let inner_clone = Arc::clone(&self.inner);
+        let path = normalize_path(req.uri().path());
+        let security_headers_path = path.clone().unwrap_or_else(|| req.uri().path().to_string());
+
+        // lazy so that handler setup also runs with the request id current
+        Box::new(crate::request_id::WithRequestId::new(
+            request_id,
+            future::lazy(move || {
+                self_clone.handle_request(req, path, remote_addr, request_id_clone)
+            })
+            .map(move |mut resp| {
+                inner_clone
+                    .security_headers
+                    .apply(&security_headers_path, resp.headers_mut());
+                resp
+            }),
+        ))
+    }
 
+    // path is None when normalize_path rejected the request path.
+    fn handle_request(
+        &self,
+        req: Request<Body>,
+        path: Option<String>,
+        remote_addr: SocketAddr,
+        request_id: String,
+    ) -> ResponseFuture {
         let route_configuration = &self.inner.route_configuration;
 
-        let handler = route_configuration
-            .path_to_handler()
-            .get(req_context.req.uri().path())
-            .unwrap_or(route_configuration.not_found_handler());
+        let route = match &path {
+            Some(path) => route_configuration.find_route(path).0,
+            None => "bad_request",
+        };
+
+        let request_span = self
+            .inner
+            .application_context
+            .tracer()
+            .as_ref()
+            .map(|tracer| start_request_span(tracer, &req, route, remote_addr, &request_id));
+
+        let client_ip = match &self.inner.ip_access_control {
+            Some(ip_access_control) => ip_access_control.client_ip(remote_addr.ip(), req.headers()),
+            None => remote_addr.ip(),
+        };
+
+        let path_valid = path.is_some();
+        let path = path.unwrap_or_else(|| req.uri().path().to_string());
+
+        let req_context = RequestContext::new(
+            req,
+            path,
+            Arc::clone(&self.inner.application_context),
+            client_ip,
+            request_id,
+            request_span.as_ref().map(|span| *span.context()),
+        );
+
+        let acceptable_encodings = match self.inner.compression_info {
+            Some(_) if req_context.req.method() != Method::HEAD => {
+                crate::compression::acceptable_encodings(req_context.req.headers())
+            }
+            _ => Vec::new(),
+        };
+
+        let req_log_info = RequestLogInfo::new(&req_context, route);
+
+        let request_id_header_value = HeaderValue::from_str(&req_context.request_id).ok();
+
+        let metrics = Arc::clone(self.inner.application_context.metrics());
+        let in_flight_guard = metrics.request_started();
+
+        let ip_allowed = match &self.inner.ip_access_control {
+            Some(ip_access_control) => {
+                ip_access_control.is_allowed(req_context.client_ip, &req_context.path)
+            }
+            None => true,
+        };
+
+        let preflight_response = match &self.inner.cors {
+            Some(cors) if path_valid && ip_allowed => {
+                cors.preflight_response(&req_context.req, &req_context.path)
+            }
+            _ => None,
+        };
+
+        // Some(allowed origin) for covered paths; preflight responses
+        // already have their cors headers
+        let cors_origin = match (&self.inner.cors, &preflight_response) {
+            (Some(cors), None) if cors.covers(&req_context.path) => {
+                Some(cors.allowed_origin(&req_context.req, &req_context.path))
+            }
+            _ => None,
+        };
+
+        let response_future: ResponseFuture = match (&self.inner.authenticator, preflight_response)
+        {
+            _ if !path_valid => {
+                debug!("rejecting request path {}", req_context.req.uri().path());
+                Box::new(future::ok(build_response_status(StatusCode::BAD_REQUEST)))
+            }
+            _ if !ip_allowed => Box::new(future::ok(build_response_status(StatusCode::FORBIDDEN))),
+            // preflight requests never carry credentials, so they are
+            // answered before authorization
+            (_, Some(preflight_response)) => Box::new(future::ok(preflight_response)),
+            (Some(authenticator), None) => self
+                .inner
+                .authorize_and_dispatch(authenticator, req_context),
+            (None, None) => self.inner.dispatch(&req_context),
+        };
+
+        let inner_clone = Arc::clone(&self.inner);
+        let log_inner_clone = Arc::clone(&self.inner);
 
         Box::new(
-            handler
-                .handle(&req_context)
-                .then(move |result| match result {
-                    Ok(resp) => {
-                        log_request_and_response(req_log_info, &resp);
-                        Ok(resp)
-                    }
-                    Err(e) => {
-                        match e {
-                            HandlerError::Hyper(e) => warn!("hyper handler error: {}", e),
-                            HandlerError::IoError(e) => warn!("io handler error: {}", e),
+            response_future
+                .and_then(move |resp| match &inner_clone.compression_info {
+                    Some(compression_info) => crate::compression::compress_response(
+                        resp,
+                        &acceptable_encodings,
+                        compression_info,
+                    ),
+                    None => Box::new(future::ok(resp)),
+                })
+                .then(move |result| {
+                    let mut resp = match result {
+                        Ok(resp) => resp,
+                        Err(e) => {
+                            match e {
+                                HandlerError::Hyper(e) => warn!("hyper handler error: {}", e),
+                                HandlerError::IoError(e) => warn!("io handler error: {}", e),
+                            }
+                            build_response_status(StatusCode::INTERNAL_SERVER_ERROR)
                         }
-                        let resp = build_response_status(StatusCode::INTERNAL_SERVER_ERROR);
-                        log_request_and_response(req_log_info, &resp);
-                        Ok(resp)
+                    };
+
+                    metrics.observe_request(
+                        &req_log_info.route,
+                        resp.status().as_u16(),
+                        req_log_info.start_time.elapsed(),
+                    );
+                    drop(in_flight_guard);
+
+                    if let Some(mut request_span) = request_span {
+                        let status = resp.status();
+                        request_span.set_attribute("http.status_code", i64::from(status.as_u16()));
+                        if status.is_server_error() {
+                            request_span.set_error();
+                        }
+                        request_span.end();
+                    }
+
+                    if let (Some(cors), Some(cors_origin)) = (&log_inner_clone.cors, cors_origin) {
+                        cors.add_response_headers(cors_origin, resp.headers_mut());
+                    }
+
+                    if let Some(request_id_header_value) = request_id_header_value {
+                        resp.headers_mut().insert(
+                            crate::request_id::x_request_id_header_name(),
+                            request_id_header_value,
+                        );
                     }
+
+                    log_request_and_response(req_log_info, &resp, &log_inner_clone.access_logger);
+                    Ok(resp)
                 }),
         )
     }
 }
 
+// The processing applied to every request around its route handler.
+pub struct RequestPipeline {
+    pub compression_info: Option<crate::config::CompressionInfo>,
+    pub access_logger: Option<crate::access_log::AccessLogger>,
+    pub authenticator: Option<crate::auth::Authenticator>,
+    pub ip_access_control: Option<crate::ip_access::IpAccessControl>,
+    pub rate_limiter: Option<crate::rate_limit::RateLimiter>,
+    pub security_headers: crate::security_headers::SecurityHeaders,
+    pub cors: Option<crate::cors::Cors>,
+}
+
 pub struct ServerConfiguration {
     listen_addr: SocketAddr,
     tcp_nodelay: bool,
+    metrics: Arc<crate::metrics::Metrics>,
+    background_tasks: Vec<BackgroundTask>,
+    tracer: Option<crate::trace::Tracer>,
+    request_pipeline: RequestPipeline,
 }
 
 impl ServerConfiguration {
-    pub fn new(listen_addr: SocketAddr, tcp_nodelay: bool) -> Self {
+    pub fn new(
+        listen_addr: SocketAddr,
+        tcp_nodelay: bool,
+        metrics: Arc<crate::metrics::Metrics>,
+        background_tasks: Vec<BackgroundTask>,
+        tracer: Option<crate::trace::Tracer>,
+        request_pipeline: RequestPipeline,
+    ) -> Self {
         ServerConfiguration {
             listen_addr,
             tcp_nodelay,
+            metrics,
+            background_tasks,
+            tracer,
+            request_pipeline,
         }
     }
+
+    // Set by serve, since opening the access log starts its writer thread.
+    pub fn set_access_logger(&mut self, access_logger: crate::access_log::AccessLogger) {
+        self.request_pipeline.access_logger = Some(access_logger);
+    }
 }
 
 pub fn run_forever(
@@ -281,17 +846,39 @@ pub fn run_forever(
 
         let http_client = ::hyper::client::Client::builder().build(http_connector);
 
-        let application_context = Arc::new(ApplicationContext::new(http_client));
+        for background_task in server_configuration.background_tasks {
+            ::hyper::rt::spawn(background_task);
+        }
+
+        let metrics = server_configuration.metrics;
+
+        let application_context = Arc::new(ApplicationContext::new(
+            http_client,
+            Arc::clone(&metrics),
+            server_configuration.tracer,
+        ));
 
-        let threaded_server = ThreadedServer::new(application_context, route_configuration);
+        let threaded_server = ThreadedServer::new(
+            application_context,
+            route_configuration,
+            server_configuration.request_pipeline,
+        );
 
         let server = Server::bind(&server_configuration.listen_addr)
             .tcp_nodelay(server_configuration.tcp_nodelay)
-            .serve(move || {
+            .serve(make_service_fn(move |socket: &AddrStream| {
                 let threaded_server_clone = threaded_server.clone();
 
-                service_fn(move |req: Request<Body>| threaded_server_clone.call(req))
-            })
+                let remote_addr = socket.remote_addr();
+
+                // the service lives as long as its connection
+                let connection_guard = metrics.connection_opened();
+
+                service_fn(move |req: Request<Body>| {
+                    let _ = &connection_guard;
+                    threaded_server_clone.call(req, remote_addr)
+                })
+            }))
             .map_err(|e| warn!("serve error: {}", e));
 
         info!("Listening on http://{}", server_configuration.listen_addr);
@@ -301,3 +888,76 @@ pub fn run_forever(
 
     Err(From::from("run_forever exiting"))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalize_path_decodes_and_collapses_segments() {
+        assert_eq!(normalize_path("/"), Some("/".to_string()));
+        assert_eq!(normalize_path(""), Some("/".to_string()));
+        assert_eq!(
+            normalize_path("/files/%70rivate/x"),
+            Some("/files/private/x".to_string())
+        );
+        assert_eq!(
+            normalize_path("/files//private/x"),
+            Some("/files/private/x".to_string())
+        );
+        assert_eq!(
+            normalize_path("/files/./private/x"),
+            Some("/files/private/x".to_string())
+        );
+        assert_eq!(normalize_path("/files/"), Some("/files/".to_string()));
+        assert_eq!(normalize_path("//"), Some("/".to_string()));
+    }
+
+    #[test]
+    fn normalize_path_rejects_unsafe_paths() {
+        assert_eq!(normalize_path("/files/../etc/passwd"), None);
+        assert_eq!(normalize_path("/files/%2e%2e/etc/passwd"), None);
+        assert_eq!(normalize_path("/files/%2F..%2Fx"), None);
+        assert_eq!(normalize_path("/files/%00"), None);
+        assert_eq!(normalize_path("/files/%0a"), None);
+        assert_eq!(normalize_path("/files/a%5Cb"), None);
+        assert_eq!(normalize_path("/files/%ff"), None);
+    }
+
+    #[test]
+    fn build_response_redirect_rejects_invalid_locations() {
+        let resp = build_response_redirect(StatusCode::SEE_OTHER, "/next");
+        assert_eq!(resp.status(), StatusCode::SEE_OTHER);
+        assert_eq!(resp.headers()[LOCATION], "/next");
+
+        let resp = build_response_redirect(StatusCode::SEE_OTHER, "/\n");
+        assert_eq!(resp.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert!(!resp.headers().contains_key(LOCATION));
+    }
+
+    #[test]
+    fn path_matches_prefix_matches_whole_segments() {
+        assert!(path_matches_prefix("/files", "/files"));
+        assert!(path_matches_prefix("/files/x", "/files/"));
+        assert!(!path_matches_prefix("/filesystem", "/files"));
+    }
+
+    #[test]
+    fn prefix_rules_prefer_longest_prefix() {
+        let rules = PrefixRules::new(vec![
+            ("/".to_string(), "root"),
+            ("/api/admin".to_string(), "admin"),
+            ("/api".to_string(), "api"),
+        ]);
+
+        assert_eq!(rules.lookup("/api/admin/users"), Some(&"admin"));
+        assert_eq!(rules.lookup("/api/other"), Some(&"api"));
+        assert_eq!(rules.lookup("/apis"), Some(&"root"));
+
+        let index = rules.position("/api").unwrap();
+        assert_eq!(rules.get(index), Some(&"api"));
+
+        let rules = PrefixRules::new(vec![("/api".to_string(), "api")]);
+        assert_eq!(rules.lookup("/other"), None);
+    }
+}
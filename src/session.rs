@@ -0,0 +1,293 @@
+use hmac::{Hmac, Mac};
+
+use hyper::header::{HeaderMap, HeaderValue, COOKIE};
+
+use log::warn;
+
+use sha2::Sha256;
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+type HmacSha256 = Hmac<Sha256>;
+
+const GENERATED_SECRET_BYTES: usize = 32;
+const MIN_SECRET_LENGTH: usize = 32;
+const CSRF_TOKEN_BYTES: usize = 16;
+const LOGIN_CSRF_MAX_AGE_SECONDS: u64 = 10 * 60;
+
+fn now_seconds() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+fn encode(bytes: &[u8]) -> String {
+    ::base64::encode_config(bytes, ::base64::URL_SAFE_NO_PAD)
+}
+
+pub fn new_csrf_token() -> Result<String, Box<::std::error::Error>> {
+    Ok(encode(&crate::utils::random_bytes(CSRF_TOKEN_BYTES)?))
+}
+
+pub fn cookie_value(headers: &HeaderMap, name: &str) -> Option<String> {
+    headers
+        .get_all(COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .filter_map(|cookie| {
+            let mut split = cookie.trim().splitn(2, '=');
+            match (split.next(), split.next()) {
+                (Some(cookie_name), Some(value)) if cookie_name == name => Some(value.to_string()),
+                _ => None,
+            }
+        })
+        .next()
+}
+
+pub struct Session {
+    username: String,
+    generation: u64,
+    csrf_token: String,
+}
+
+impl Session {
+    pub fn username(&self) -> &String {
+        &self.username
+    }
+
+    pub fn csrf_token(&self) -> &String {
+        &self.csrf_token
+    }
+}
+
+// The cookie carries the username, expiry, csrf token and the user's
+// session generation, signed with HMAC-SHA256.  Logging out bumps the
+// generation, revoking every session of that user.  Generations are only
+// kept in memory, so revocations don't survive a restart.
+pub struct SessionManager {
+    key: Vec<u8>,
+    cookie_name: String,
+    login_csrf_cookie_name: String,
+    max_age_seconds: u64,
+    secure_cookie: bool,
+    generations: Mutex<HashMap<String, u64>>,
+}
+
+impl SessionManager {
+    pub fn new(
+        session_info: &crate::config::SessionInfo,
+    ) -> Result<Self, Box<::std::error::Error>> {
+        let cookie_name = session_info.cookie_name();
+        if cookie_name.is_empty()
+            || !cookie_name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+        {
+            return Err(format!("invalid session cookie_name '{}'", cookie_name).into());
+        }
+
+        let key = match session_info.secret() {
+            Some(secret) if secret.len() < MIN_SECRET_LENGTH => {
+                return Err(format!(
+                    "session secret must be at least {} characters",
+                    MIN_SECRET_LENGTH
+                )
+                .into());
+            }
+            Some(secret) => secret.as_bytes().to_vec(),
+            None => {
+                warn!("no session secret configured, sessions will not survive a restart");
+                crate::utils::random_bytes(GENERATED_SECRET_BYTES)?
+            }
+        };
+
+        Ok(SessionManager {
+            key,
+            cookie_name: session_info.cookie_name().clone(),
+            login_csrf_cookie_name: format!("{}_login_csrf", session_info.cookie_name()),
+            max_age_seconds: session_info.max_age_seconds(),
+            secure_cookie: session_info.secure_cookie(),
+            generations: Mutex::new(HashMap::new()),
+        })
+    }
+
+    fn generation(&self, username: &str) -> u64 {
+        self.generations
+            .lock()
+            .unwrap()
+            .get(username)
+            .cloned()
+            .unwrap_or(0)
+    }
+
+    fn mac(&self, payload: &str) -> HmacSha256 {
+        let mut mac = HmacSha256::new_varkey(&self.key).expect("hmac accepts any key length");
+        mac.input(payload.as_bytes());
+        mac
+    }
+
+    fn cookie_header_value(
+        &self,
+        name: &str,
+        value: &str,
+        path: &str,
+        same_site: &str,
+        max_age_seconds: u64,
+    ) -> HeaderValue {
+        let mut cookie = format!(
+            "{}={}; Path={}; Max-Age={}; HttpOnly; SameSite={}",
+            name, value, path, max_age_seconds, same_site
+        );
+        if self.secure_cookie {
+            cookie.push_str("; Secure");
+        }
+
+        // names are validated and values are base64url, so this can't fail
+        HeaderValue::from_str(&cookie).unwrap()
+    }
+
+    // A new session gets a fresh csrf token; session_cookie signs it into
+    // the Set-Cookie value.
+    pub fn create_session(&self, username: &str) -> Result<Session, Box<::std::error::Error>> {
+        Ok(Session {
+            username: username.to_string(),
+            generation: self.generation(username),
+            csrf_token: new_csrf_token()?,
+        })
+    }
+
+    // Invalidates all existing sessions of the session's user.
+    pub fn revoke_sessions(&self, session: &Session) {
+        let mut generations = self.generations.lock().unwrap();
+        let generation = generations.entry(session.username.clone()).or_insert(0);
+        if *generation == session.generation {
+            *generation += 1;
+        }
+    }
+
+    pub fn session_cookie(&self, session: &Session) -> HeaderValue {
+        let payload = encode(
+            format!(
+                "{}:{}:{}:{}",
+                now_seconds() + self.max_age_seconds,
+                session.generation,
+                session.csrf_token,
+                session.username
+            )
+            .as_bytes(),
+        );
+        let signature = encode(&self.mac(&payload).result().code());
+
+        self.cookie_header_value(
+            &self.cookie_name,
+            &format!("{}.{}", payload, signature),
+            "/",
+            "Lax",
+            self.max_age_seconds,
+        )
+    }
+
+    pub fn clear_session_cookie(&self) -> HeaderValue {
+        self.cookie_header_value(&self.cookie_name, "", "/", "Lax", 0)
+    }
+
+    pub fn session_from_headers(&self, headers: &HeaderMap) -> Option<Session> {
+        let cookie = cookie_value(headers, &self.cookie_name)?;
+
+        let mut split = cookie.splitn(2, '.');
+        let payload = split.next()?;
+        let signature = ::base64::decode_config(split.next()?, ::base64::URL_SAFE_NO_PAD).ok()?;
+
+        self.mac(payload).verify(&signature).ok()?;
+
+        let payload = ::base64::decode_config(payload, ::base64::URL_SAFE_NO_PAD).ok()?;
+        let payload = String::from_utf8(payload).ok()?;
+
+        let mut fields = payload.splitn(4, ':');
+        let expires: u64 = fields.next()?.parse().ok()?;
+        let generation: u64 = fields.next()?.parse().ok()?;
+        let csrf_token = fields.next()?.to_string();
+        let username = fields.next()?.to_string();
+
+        if expires <= now_seconds() || generation != self.generation(&username) {
+            return None;
+        }
+
+        Some(Session {
+            username,
+            generation,
+            csrf_token,
+        })
+    }
+
+    // The login form's csrf token is checked against this cookie, since
+    // there is no session yet to tie it to.
+    pub fn login_csrf_cookie(&self, csrf_token: &str) -> HeaderValue {
+        self.cookie_header_value(
+            &self.login_csrf_cookie_name,
+            csrf_token,
+            crate::auth::LOGIN_PATH,
+            "Strict",
+            LOGIN_CSRF_MAX_AGE_SECONDS,
+        )
+    }
+
+    pub fn clear_login_csrf_cookie(&self) -> HeaderValue {
+        self.cookie_header_value(
+            &self.login_csrf_cookie_name,
+            "",
+            crate::auth::LOGIN_PATH,
+            "Strict",
+            0,
+        )
+    }
+
+    pub fn login_csrf_from_headers(&self, headers: &HeaderMap) -> Option<String> {
+        cookie_value(headers, &self.login_csrf_cookie_name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request_headers(set_cookie: &HeaderValue) -> HeaderMap {
+        let cookie = set_cookie.to_str().unwrap().split(';').next().unwrap();
+
+        let mut headers = HeaderMap::new();
+        headers.insert(COOKIE, HeaderValue::from_str(cookie).unwrap());
+        headers
+    }
+
+    #[test]
+    fn logout_revokes_all_sessions_of_the_user() {
+        let session_info: crate::config::SessionInfo = ::serde_json::from_str("{}").unwrap();
+        let session_manager = SessionManager::new(&session_info).unwrap();
+
+        let first = request_headers(
+            &session_manager.session_cookie(&session_manager.create_session("alice").unwrap()),
+        );
+        let second = request_headers(
+            &session_manager.session_cookie(&session_manager.create_session("alice").unwrap()),
+        );
+        let other = request_headers(
+            &session_manager.session_cookie(&session_manager.create_session("bob").unwrap()),
+        );
+
+        let session = session_manager.session_from_headers(&first).unwrap();
+        session_manager.revoke_sessions(&session);
+
+        assert!(session_manager.session_from_headers(&first).is_none());
+        assert!(session_manager.session_from_headers(&second).is_none());
+        assert!(session_manager.session_from_headers(&other).is_some());
+
+        let new_cookie = request_headers(
+            &session_manager.session_cookie(&session_manager.create_session("alice").unwrap()),
+        );
+        assert!(session_manager.session_from_headers(&new_cookie).is_some());
+    }
+}
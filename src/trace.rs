@@ -0,0 +1,584 @@
+use futures::{future, Future, Stream};
+
+use hyper::header::{HeaderMap, CONTENT_TYPE};
+use hyper::{Body, Request, Uri};
+
+use log::{debug, warn};
+
+use serde_derive::Serialize;
+
+use std::fmt::Write;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+pub const TRACEPARENT: &str = "traceparent";
+
+const TRACEPARENT_VERSION: &str = "00";
+const FLAG_SAMPLED: u8 = 0x01;
+
+fn to_hex(bytes: &[u8]) -> String {
+    let mut hex = String::with_capacity(bytes.len() * 2);
+    for byte in bytes {
+        write!(hex, "{:02x}", byte).unwrap();
+    }
+    hex
+}
+
+// Trace context fields are lowercase hex only.
+fn is_lower_hex(hex: &str) -> bool {
+    hex.chars()
+        .all(|c| c.is_ascii_digit() || ('a'..='f').contains(&c))
+}
+
+fn parse_hex(hex: &str, bytes: &mut [u8]) -> Option<()> {
+    if hex.len() != bytes.len() * 2 || !is_lower_hex(hex) {
+        return None;
+    }
+
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).ok()?;
+    }
+
+    if bytes.iter().all(|b| *b == 0) {
+        None
+    } else {
+        Some(())
+    }
+}
+
+// Ids may not be all zeros.  Tracer::new checks that random bytes are
+// available, so failing to get them afterwards is a bug.
+fn random_id(bytes: &mut [u8]) {
+    loop {
+        crate::utils::fill_random(bytes).expect("no random bytes for span id");
+        if bytes.iter().any(|b| *b != 0) {
+            return;
+        }
+    }
+}
+
+fn unix_nanos(time: SystemTime) -> String {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(duration) => {
+            (duration.as_secs() * 1_000_000_000 + u64::from(duration.subsec_nanos())).to_string()
+        }
+        Err(_) => "0".to_string(),
+    }
+}
+
+// W3C trace context: identifies a span within a trace.
+#[derive(Debug, Clone, Copy)]
+pub struct SpanContext {
+    trace_id: [u8; 16],
+    span_id: [u8; 8],
+    sampled: bool,
+}
+
+impl SpanContext {
+    pub fn from_headers(headers: &HeaderMap) -> Option<Self> {
+        let traceparent = headers.get(TRACEPARENT)?.to_str().ok()?.trim();
+
+        let parts: Vec<&str> = traceparent.split('-').collect();
+        if parts.len() < 4 || parts[0].len() != 2 || !is_lower_hex(parts[0]) || parts[0] == "ff" {
+            return None;
+        }
+        // later versions may append fields, version 00 may not
+        if parts[0] == TRACEPARENT_VERSION && parts.len() != 4 {
+            return None;
+        }
+
+        let mut trace_id = [0; 16];
+        parse_hex(parts[1], &mut trace_id)?;
+        let mut span_id = [0; 8];
+        parse_hex(parts[2], &mut span_id)?;
+        if parts[3].len() != 2 || !is_lower_hex(parts[3]) {
+            return None;
+        }
+        let flags = u8::from_str_radix(parts[3], 16).ok()?;
+
+        Some(SpanContext {
+            trace_id,
+            span_id,
+            sampled: flags & FLAG_SAMPLED != 0,
+        })
+    }
+
+    pub fn traceparent(&self) -> String {
+        format!(
+            "{}-{}-{}-{:02x}",
+            TRACEPARENT_VERSION,
+            to_hex(&self.trace_id),
+            to_hex(&self.span_id),
+            if self.sampled { FLAG_SAMPLED } else { 0 }
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum SpanKind {
+    Internal = 1,
+    Server = 2,
+    Client = 3,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub enum AttributeValue {
+    #[serde(rename = "stringValue")]
+    String(String),
+    // OTLP JSON encodes 64 bit integers as strings
+    #[serde(rename = "intValue")]
+    Int(String),
+    #[serde(rename = "boolValue")]
+    Bool(bool),
+}
+
+impl From<&str> for AttributeValue {
+    fn from(value: &str) -> Self {
+        AttributeValue::String(value.to_string())
+    }
+}
+
+impl From<String> for AttributeValue {
+    fn from(value: String) -> Self {
+        AttributeValue::String(value)
+    }
+}
+
+impl From<i64> for AttributeValue {
+    fn from(value: i64) -> Self {
+        AttributeValue::Int(value.to_string())
+    }
+}
+
+impl From<bool> for AttributeValue {
+    fn from(value: bool) -> Self {
+        AttributeValue::Bool(value)
+    }
+}
+
+#[derive(Serialize)]
+struct OtlpKeyValue {
+    key: String,
+    value: AttributeValue,
+}
+
+#[derive(Serialize)]
+struct OtlpStatus {
+    code: u8,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct OtlpSpan {
+    trace_id: String,
+    span_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    parent_span_id: Option<String>,
+    name: String,
+    kind: u8,
+    start_time_unix_nano: String,
+    end_time_unix_nano: String,
+    attributes: Vec<OtlpKeyValue>,
+    status: OtlpStatus,
+}
+
+#[derive(Serialize)]
+struct OtlpScope {
+    name: &'static str,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct OtlpScopeSpans {
+    scope: OtlpScope,
+    spans: Vec<OtlpSpan>,
+}
+
+#[derive(Serialize)]
+struct OtlpResource {
+    attributes: Vec<OtlpKeyValue>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct OtlpResourceSpans {
+    resource: OtlpResource,
+    scope_spans: Vec<OtlpScopeSpans>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct OtlpExportRequest {
+    resource_spans: Vec<OtlpResourceSpans>,
+}
+
+// OTLP status codes: 1 is ok, 2 is error.
+const STATUS_CODE_OK: u8 = 1;
+const STATUS_CODE_ERROR: u8 = 2;
+
+// An in-progress span.  Spans that are dropped without end() are not
+// exported.
+pub struct Span {
+    tracer: Tracer,
+    context: SpanContext,
+    parent_span_id: Option<[u8; 8]>,
+    name: String,
+    kind: SpanKind,
+    start_time: SystemTime,
+    attributes: Vec<OtlpKeyValue>,
+    error: bool,
+}
+
+impl Span {
+    pub fn context(&self) -> &SpanContext {
+        &self.context
+    }
+
+    pub fn set_attribute<V: Into<AttributeValue>>(&mut self, key: &str, value: V) {
+        self.attributes.push(OtlpKeyValue {
+            key: key.to_string(),
+            value: value.into(),
+        });
+    }
+
+    pub fn set_error(&mut self) {
+        self.error = true;
+    }
+
+    pub fn end(self) {
+        if !self.context.sampled {
+            return;
+        }
+
+        let tracer = self.tracer.clone();
+        tracer.inner.finish_span(OtlpSpan {
+            trace_id: to_hex(&self.context.trace_id),
+            span_id: to_hex(&self.context.span_id),
+            parent_span_id: self.parent_span_id.map(|id| to_hex(&id)),
+            name: self.name,
+            kind: self.kind as u8,
+            start_time_unix_nano: unix_nanos(self.start_time),
+            end_time_unix_nano: unix_nanos(SystemTime::now()),
+            attributes: self.attributes,
+            status: OtlpStatus {
+                code: if self.error {
+                    STATUS_CODE_ERROR
+                } else {
+                    STATUS_CODE_OK
+                },
+            },
+        });
+    }
+}
+
+struct InnerTracer {
+    service_name: String,
+    collector_uri: Uri,
+    export_interval: Duration,
+    max_queued_spans: usize,
+    finished_spans: Mutex<Vec<OtlpSpan>>,
+    dropped_spans: AtomicUsize,
+}
+
+impl InnerTracer {
+    fn finish_span(&self, span: OtlpSpan) {
+        let mut finished_spans = self.finished_spans.lock().unwrap();
+
+        if finished_spans.len() >= self.max_queued_spans {
+            self.dropped_spans.fetch_add(1, Ordering::Relaxed);
+        } else {
+            finished_spans.push(span);
+        }
+    }
+
+    fn export_request_body(&self, spans: Vec<OtlpSpan>) -> Result<String, ::serde_json::Error> {
+        ::serde_json::to_string(&OtlpExportRequest {
+            resource_spans: vec![OtlpResourceSpans {
+                resource: OtlpResource {
+                    attributes: vec![OtlpKeyValue {
+                        key: "service.name".to_string(),
+                        value: AttributeValue::from(self.service_name.as_str()),
+                    }],
+                },
+                scope_spans: vec![OtlpScopeSpans {
+                    scope: OtlpScope {
+                        name: module_path!(),
+                    },
+                    spans,
+                }],
+            }],
+        })
+    }
+
+    fn export(
+        &self,
+        http_client: &::hyper::Client<::hyper::client::HttpConnector>,
+    ) -> Box<Future<Item = (), Error = ()> + Send> {
+        let spans: Vec<OtlpSpan> = self.finished_spans.lock().unwrap().drain(..).collect();
+
+        let dropped_spans = self.dropped_spans.swap(0, Ordering::Relaxed);
+        if dropped_spans > 0 {
+            warn!(
+                "dropped {} spans because the export queue was full",
+                dropped_spans
+            );
+        }
+
+        if spans.is_empty() {
+            return Box::new(future::ok(()));
+        }
+
+        let span_count = spans.len();
+
+        let request = self
+            .export_request_body(spans)
+            .map_err(|e| e.to_string())
+            .and_then(|body| {
+                Request::post(self.collector_uri.clone())
+                    .header(CONTENT_TYPE, "application/json")
+                    .body(Body::from(body))
+                    .map_err(|e| e.to_string())
+            });
+
+        let request = match request {
+            Ok(request) => request,
+            Err(e) => {
+                warn!("error building span export request: {}", e);
+                return Box::new(future::ok(()));
+            }
+        };
+
+        Box::new(http_client.request(request).then(move |result| {
+            match result {
+                Ok(ref response) if response.status().is_success() => {
+                    debug!("exported {} spans", span_count)
+                }
+                Ok(response) => warn!(
+                    "span export of {} spans failed with status {}",
+                    span_count,
+                    response.status()
+                ),
+                Err(e) => warn!("span export of {} spans failed: {}", span_count, e),
+            }
+            Ok(())
+        }))
+    }
+}
+
+// Records spans and exports them in batches to an OTLP collector.
+#[derive(Clone)]
+pub struct Tracer {
+    inner: Arc<InnerTracer>,
+}
+
+impl Tracer {
+    pub fn new(
+        tracing_info: &crate::config::TracingInfo,
+    ) -> Result<Self, Box<::std::error::Error>> {
+        let collector_uri: Uri = tracing_info.collector_url().parse()?;
+
+        if collector_uri.scheme_part().map(|s| s.as_str()) != Some("http") {
+            return Err(format!(
+                "tracing collector_url '{}' must be an http url",
+                tracing_info.collector_url()
+            )
+            .into());
+        }
+
+        // fail at startup rather than on the first span
+        crate::utils::fill_random(&mut [0; 8])?;
+
+        Ok(Tracer {
+            inner: Arc::new(InnerTracer {
+                service_name: tracing_info.service_name().clone(),
+                collector_uri,
+                export_interval: Duration::from_secs(tracing_info.export_interval_seconds().max(1)),
+                max_queued_spans: tracing_info.max_queued_spans(),
+                finished_spans: Mutex::new(Vec::new()),
+                dropped_spans: AtomicUsize::new(0),
+            }),
+        })
+    }
+
+    // Starts a span in the parent's trace, or a new sampled trace when there
+    // is no parent.
+    pub fn start_span(&self, name: String, kind: SpanKind, parent: Option<&SpanContext>) -> Span {
+        let mut span_id = [0; 8];
+        random_id(&mut span_id);
+
+        let context = match parent {
+            Some(parent) => SpanContext {
+                trace_id: parent.trace_id,
+                span_id,
+                sampled: parent.sampled,
+            },
+            None => {
+                let mut trace_id = [0; 16];
+                random_id(&mut trace_id);
+                SpanContext {
+                    trace_id,
+                    span_id,
+                    sampled: true,
+                }
+            }
+        };
+
+        Span {
+            tracer: self.clone(),
+            context,
+            parent_span_id: parent.map(|parent| parent.span_id),
+            name,
+            kind,
+            start_time: SystemTime::now(),
+            attributes: Vec::new(),
+            error: false,
+        }
+    }
+
+    pub fn export_task(&self) -> crate::server::BackgroundTask {
+        let inner_clone = Arc::clone(&self.inner);
+
+        Box::new(future::lazy(move || {
+            let http_client = ::hyper::Client::new();
+
+            ::tokio_timer::Interval::new_interval(inner_clone.export_interval)
+                .map_err(|e| warn!("span export interval error: {}", e))
+                .for_each(move |_| inner_clone.export(&http_client))
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use hyper::header::HeaderValue;
+    use hyper::Response;
+
+    fn traceparent_headers(traceparent: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(TRACEPARENT, HeaderValue::from_str(traceparent).unwrap());
+        headers
+    }
+
+    #[test]
+    fn from_headers_parses_traceparent() {
+        let traceparent = "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01";
+
+        let context = SpanContext::from_headers(&traceparent_headers(traceparent)).unwrap();
+
+        assert!(context.sampled);
+        assert_eq!(context.traceparent(), traceparent);
+    }
+
+    #[test]
+    fn from_headers_rejects_invalid_traceparent() {
+        for traceparent in &[
+            "00-0AF7651916CD43DD8448EB211C80319C-b7ad6b7169203331-01",
+            "00-0af7651916cd43dd8448eb211c80319c-B7AD6B7169203331-01",
+            "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-0A",
+            "00-00000000000000000000000000000000-b7ad6b7169203331-01",
+            "00-0af7651916cd43dd8448eb211c80319c-0000000000000000-01",
+            "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01-extra",
+            "ff-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01",
+        ] {
+            assert!(
+                SpanContext::from_headers(&traceparent_headers(traceparent)).is_none(),
+                "{}",
+                traceparent
+            );
+        }
+    }
+
+    // (path, body) of every request the mock collector received
+    type ReceivedRequests = Arc<Mutex<Vec<(String, String)>>>;
+
+    // Runs a local collector that records every request it receives.
+    fn start_mock_collector(
+        runtime: &mut ::tokio::runtime::Runtime,
+    ) -> (::std::net::SocketAddr, ReceivedRequests) {
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let received_clone = Arc::clone(&received);
+
+        let server = ::hyper::Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(move || {
+            let received = Arc::clone(&received_clone);
+            ::hyper::service::service_fn(move |req: Request<Body>| {
+                let received = Arc::clone(&received);
+                let path = req.uri().path().to_string();
+                req.into_body().concat2().map(move |body| {
+                    received
+                        .lock()
+                        .unwrap()
+                        .push((path, String::from_utf8_lossy(&body).into_owned()));
+                    Response::new(Body::empty())
+                })
+            })
+        });
+        let addr = server.local_addr();
+
+        runtime.spawn(server.map_err(|e| panic!("mock collector error: {}", e)));
+
+        (addr, received)
+    }
+
+    #[test]
+    fn export_sends_spans_to_collector() {
+        let mut runtime = ::tokio::runtime::Runtime::new().unwrap();
+        let (addr, received) = start_mock_collector(&mut runtime);
+
+        let tracing_info: crate::config::TracingInfo = ::serde_json::from_str(&format!(
+            r#"{{"collector_url": "http://{}/v1/traces", "service_name": "test"}}"#,
+            addr
+        ))
+        .unwrap();
+        let tracer = Tracer::new(&tracing_info).unwrap();
+
+        let parent = tracer.start_span("parent".to_string(), SpanKind::Server, None);
+        let mut child = tracer.start_span(
+            "child".to_string(),
+            SpanKind::Client,
+            Some(parent.context()),
+        );
+        child.set_attribute("command.exit_code", 0);
+        child.set_error();
+        let parent_context = *parent.context();
+        child.end();
+        parent.end();
+
+        let inner = Arc::clone(&tracer.inner);
+        runtime
+            .block_on(future::lazy(move || inner.export(&::hyper::Client::new())))
+            .unwrap();
+
+        let received = received.lock().unwrap();
+        assert_eq!(received.len(), 1);
+        assert_eq!(received[0].0, "/v1/traces");
+
+        let body: ::serde_json::Value = ::serde_json::from_str(&received[0].1).unwrap();
+        let resource_spans = &body["resourceSpans"][0];
+        assert_eq!(
+            resource_spans["resource"]["attributes"][0]["value"]["stringValue"],
+            "test"
+        );
+
+        let spans = resource_spans["scopeSpans"][0]["spans"].as_array().unwrap();
+        assert_eq!(spans.len(), 2);
+
+        let (child, parent) = (&spans[0], &spans[1]);
+        assert_eq!(child["name"], "child");
+        assert_eq!(child["traceId"], to_hex(&parent_context.trace_id));
+        assert_eq!(child["parentSpanId"], to_hex(&parent_context.span_id));
+        assert_eq!(child["status"]["code"], STATUS_CODE_ERROR);
+        assert_eq!(child["attributes"][0]["value"]["intValue"], "0");
+        assert_eq!(parent["spanId"], to_hex(&parent_context.span_id));
+        assert!(parent.get("parentSpanId").is_none());
+
+        // exported spans are not sent again
+        let inner = Arc::clone(&tracer.inner);
+        runtime
+            .block_on(future::lazy(move || inner.export(&::hyper::Client::new())))
+            .unwrap();
+        assert_eq!(received.len(), 1);
+    }
+}
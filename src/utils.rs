@@ -1,5 +1,10 @@
 use chrono::prelude::{DateTime, Local, TimeZone, Utc};
 
+use futures::{future, Async, Future};
+
+use std::collections::HashMap;
+use std::io;
+use std::path::Path;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 pub fn local_time_now_to_string() -> String {
@@ -20,3 +25,87 @@ pub fn system_time_to_utc(st: SystemTime) -> DateTime<Utc> {
 pub fn duration_in_seconds_f64(duration: Duration) -> f64 {
     (duration.as_secs() as f64) + ((duration.subsec_nanos() as f64) / 1e9)
 }
+
+pub fn content_type_for_path<P: AsRef<Path>>(path: P) -> String {
+    ::mime_guess::from_path(path)
+        .first_or_octet_stream()
+        .to_string()
+}
+
+pub fn blocking_io<F, T>(f: F) -> impl Future<Item = T, Error = io::Error>
+where
+    F: FnOnce() -> io::Result<T>,
+{
+    let mut f_option = Some(f);
+
+    future::poll_fn(
+        move || match ::tokio_threadpool::blocking(|| (f_option.take().unwrap())()) {
+            Ok(Async::Ready(Ok(v))) => Ok(Async::Ready(v)),
+            Ok(Async::Ready(Err(e))) => Err(e),
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            Err(e) => Err(io::Error::other(e)),
+        },
+    )
+}
+
+// Does not return early, so the time taken doesn't reveal how much of a
+// secret matched.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+
+    a.iter().zip(b.iter()).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+// Cryptographically secure random bytes from the kernel.  getrandom needs
+// no file descriptor, so this keeps working when the process is out of them.
+pub fn fill_random(bytes: &mut [u8]) -> io::Result<()> {
+    let mut filled = 0;
+
+    while filled < bytes.len() {
+        let remaining = &mut bytes[filled..];
+        let result = unsafe {
+            ::libc::getrandom(
+                remaining.as_mut_ptr() as *mut ::libc::c_void,
+                remaining.len(),
+                0,
+            )
+        };
+
+        if result < 0 {
+            let e = io::Error::last_os_error();
+            if e.kind() != io::ErrorKind::Interrupted {
+                return Err(e);
+            }
+        } else {
+            filled += result as usize;
+        }
+    }
+
+    Ok(())
+}
+
+pub fn random_bytes(length: usize) -> io::Result<Vec<u8>> {
+    let mut bytes = vec![0; length];
+    fill_random(&mut bytes)?;
+    Ok(bytes)
+}
+
+// Parses an application/x-www-form-urlencoded body or query string.
+pub fn parse_form(form: &str) -> HashMap<String, String> {
+    form.split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let mut split = pair.splitn(2, '=');
+            let decode = |value: Option<&str>| {
+                let value = value.unwrap_or("").replace('+', " ");
+                ::percent_encoding::percent_decode(value.as_bytes())
+                    .decode_utf8_lossy()
+                    .into_owned()
+            };
+            let name = decode(split.next());
+            (name, decode(split.next()))
+        })
+        .collect()
+}